@@ -0,0 +1,49 @@
+// compares applying 1M generated rows serially with apply_all and across 2, 4 and 8 workers with apply_parallel
+// run with: cargo bench --bench apply_parallel
+// this uses a plain timing loop rather than a benchmark harness, so it runs on stable without extra dependencies
+
+use std::time::{Duration, Instant};
+
+use csv_transaction_engine::TransactionEngine;
+
+mod common;
+
+use common::{generated_csv, rows, ROWS};
+
+const ROUNDS: usize = 5;
+
+fn main() {
+    let csv = generated_csv();
+    // 1 thread is apply_all
+    let mut timings: Vec<(usize, Vec<Duration>)> = [1, 2, 4, 8]
+        .iter()
+        .map(|threads| (*threads, Vec::new()))
+        .collect();
+    let mut serial_applied = None;
+    // alternated, so none always runs on a warmer allocator
+    for _ in 0..ROUNDS {
+        for (threads, times) in &mut timings {
+            let mut engine = TransactionEngine::default();
+            // parsing isn't what's measured
+            let rows = rows(&csv);
+            let start = Instant::now();
+            let applied = match *threads {
+                1 => engine.apply_all(rows),
+                threads => engine.apply_parallel(rows.into_iter(), threads),
+            };
+            times.push(start.elapsed());
+            assert_eq!(*serial_applied.get_or_insert(applied), applied);
+        }
+    }
+    for (threads, times) in &mut timings {
+        times.sort_unstable();
+        println!(
+            "{} threads: {} rows, best {:?}, median {:?} of {}",
+            threads,
+            ROWS,
+            times[0],
+            times[ROUNDS / 2],
+            ROUNDS
+        );
+    }
+}
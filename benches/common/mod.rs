@@ -0,0 +1,50 @@
+// rows shared by the benchmarks, each only uses some of this
+#![allow(dead_code)]
+
+use std::fmt::Write;
+
+use csv_transaction_engine::{TransactionReader, TransactionRow};
+
+pub const ROWS: u32 = 1_000_000;
+pub const CLIENTS: u32 = 5_000;
+
+// a deterministic mix of deposits, withdrawals, disputes, resolves and chargebacks, the same mix the engine's tests
+// generate, some referencing rows that were rejected or belong to other clients
+pub fn generated_csv() -> String {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for i in 0..ROWS {
+        let client = i.wrapping_mul(2_654_435_761) % CLIENTS;
+        match i % 10 {
+            0..=4 => writeln!(
+                csv,
+                "deposit,{},{},{}.{:04}",
+                client,
+                i,
+                i % 100 + 1,
+                i % 10000
+            ),
+            5 | 6 => writeln!(csv, "withdrawal,{},{},{}.5", client, i, i % 50),
+            7 => writeln!(csv, "dispute,{},{},", client, i - 7),
+            8 => writeln!(
+                csv,
+                "resolve,{},{},",
+                client,
+                i.saturating_sub(8 + 10 * (i % 3))
+            ),
+            _ => writeln!(
+                csv,
+                "chargeback,{},{},",
+                client,
+                i.saturating_sub(9 + 10 * (i % 2))
+            ),
+        }
+        .unwrap();
+    }
+    csv
+}
+
+pub fn rows(csv: &str) -> Vec<TransactionRow> {
+    TransactionReader::from_reader(csv.as_bytes())
+        .valid_records()
+        .collect()
+}
@@ -0,0 +1,67 @@
+// compares reading 1M generated rows from a file with no BufReader and with BufReaders of 8KiB, 64KiB and 1MiB
+// run with: cargo bench --bench read_buffering
+// this uses a plain timing loop rather than a benchmark harness, so it runs on stable without extra dependencies
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use csv_transaction_engine::{TransactionReader, TransactionReaderBuilder};
+
+mod common;
+
+use common::{generated_csv, ROWS};
+
+const ROUNDS: usize = 5;
+
+// removes the generated file however the benchmark ends
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn main() {
+    let file = TempFile(std::env::temp_dir().join(format!(
+        "csv_transaction_engine_read_buffering_{}.csv",
+        std::process::id()
+    )));
+    std::fs::write(&file.0, generated_csv()).unwrap();
+    // 0 is no BufReader at all
+    let mut timings: Vec<(usize, Vec<Duration>)> = [0, 8 * 1024, 64 * 1024, 1024 * 1024]
+        .iter()
+        .map(|capacity| (*capacity, Vec::new()))
+        .collect();
+    // alternated, so none always runs with a warmer page cache
+    for _ in 0..ROUNDS {
+        for (capacity, times) in &mut timings {
+            let start = Instant::now();
+            let rows = match *capacity {
+                0 => TransactionReader::from_reader(File::open(&file.0).unwrap())
+                    .valid_records()
+                    .count(),
+                capacity => TransactionReaderBuilder::new()
+                    .buffer_capacity(capacity)
+                    .from_path(&file.0)
+                    .unwrap()
+                    .valid_records()
+                    .count(),
+            };
+            times.push(start.elapsed());
+            assert_eq!(rows, ROWS as usize);
+        }
+    }
+    for (capacity, times) in &mut timings {
+        times.sort_unstable();
+        println!(
+            "{:>7} buffer: {} rows, best {:?}, median {:?} of {}",
+            capacity,
+            ROWS,
+            times[0],
+            times[ROUNDS / 2],
+            ROUNDS
+        );
+    }
+}
@@ -0,0 +1,45 @@
+// compares applying 1M generated rows to a default engine and to one pre-sized with with_capacity
+// run with: cargo bench --bench with_capacity
+// this uses a plain timing loop rather than a benchmark harness, so it runs on stable without extra dependencies
+
+use std::time::{Duration, Instant};
+
+use csv_transaction_engine::TransactionEngine;
+
+mod common;
+
+use common::{generated_csv, rows, CLIENTS, ROWS};
+
+const ROUNDS: usize = 5;
+
+fn main() {
+    let csv = generated_csv();
+    let mut timings: [(&str, Vec<Duration>); 2] =
+        [("default", Vec::new()), ("with_capacity", Vec::new())];
+    // alternated, so neither always runs on a warmer allocator
+    for _ in 0..ROUNDS {
+        for (name, times) in &mut timings {
+            let mut engine = match *name {
+                "default" => TransactionEngine::default(),
+                _ => TransactionEngine::with_capacity(ROWS as usize, CLIENTS as usize),
+            };
+            // parsing isn't what's measured
+            let rows = rows(&csv);
+            let start = Instant::now();
+            let applied = engine.apply_all(rows);
+            times.push(start.elapsed());
+            assert!(applied > 0);
+        }
+    }
+    for (name, times) in &mut timings {
+        times.sort_unstable();
+        println!(
+            "{:>13}: {} rows, best {:?}, median {:?} of {}",
+            name,
+            ROWS,
+            times[0],
+            times[ROUNDS / 2],
+            ROUNDS
+        );
+    }
+}
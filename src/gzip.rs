@@ -0,0 +1,365 @@
+//! minimal gzip (RFC 1952) support so output can be compressed without pulling in another crate
+//! compression uses deflate (RFC 1951) fixed huffman blocks with a simple LZ77 matcher, it won't match
+//! zlib's ratio but CSV is repetitive enough that it still shrinks a lot, and any gzip tool can read it
+
+use std::io::Write;
+
+// input is compressed in blocks of this size, matches never cross a block
+const BLOCK_SIZE: usize = 64 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_DISTANCE: usize = 32 * 1024;
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+// deflate length codes 257..=285, base length and extra bits
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// deflate distance codes 0..=29, base distance and extra bits
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+/// updates a running CRC-32 (as used by gzip) with bytes, start with 0
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut c = !crc;
+    for b in bytes {
+        c = CRC32_TABLE[((c ^ *b as u32) & 0xFF) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+// writes bits least significant first, as deflate requires
+struct BitWriter<W> {
+    inner: Option<W>, // only taken by GzEncoder::finish
+    bits: u64,
+    count: u32,
+    out: Vec<u8>,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // huffman codes are defined most significant bit first, so they go out reversed
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn inner(&mut self) -> &mut W {
+        self.inner.as_mut().expect("inner is only taken by finish")
+    }
+
+    fn flush_bytes(&mut self) -> std::io::Result<()> {
+        let inner = self.inner.as_mut().expect("inner is only taken by finish");
+        inner.write_all(&self.out)?;
+        self.out.clear();
+        Ok(())
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write_bits(0, 8 - self.count);
+        }
+    }
+}
+
+/// a Write that gzip compresses everything written to it into inner
+/// call finish or try_finish once everything is written to see any error writing the end of the stream, dropping
+/// the encoder finishes it too, but ignores errors
+pub struct GzEncoder<W: Write> {
+    writer: BitWriter<W>,
+    block: Vec<u8>,
+    crc: u32,
+    size: u32,
+    header_written: bool,
+    // the last block and trailer have been compressed, though maybe not written yet
+    finished: bool,
+}
+
+impl<W: Write> GzEncoder<W> {
+    pub fn new(inner: W) -> GzEncoder<W> {
+        GzEncoder {
+            writer: BitWriter {
+                inner: Some(inner),
+                bits: 0,
+                count: 0,
+                out: Vec::new(),
+            },
+            block: Vec::with_capacity(BLOCK_SIZE),
+            crc: 0,
+            size: 0,
+            header_written: false,
+            finished: false,
+        }
+    }
+
+    /// compresses whatever is left, writes the gzip trailer, and returns inner
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.try_finish()?;
+        Ok(self.writer.inner.take().unwrap())
+    }
+
+    /// compresses whatever is left and writes the gzip trailer, nothing more can be written afterwards
+    /// it can be called again if writing to inner failed
+    pub fn try_finish(&mut self) -> std::io::Result<()> {
+        if !self.finished {
+            self.write_header()?;
+            self.compress_block(true);
+            self.writer.align();
+            let (crc, size) = (self.crc, self.size);
+            self.writer.out.extend_from_slice(&crc.to_le_bytes());
+            self.writer.out.extend_from_slice(&size.to_le_bytes());
+            self.finished = true;
+        }
+        self.writer.flush_bytes()?;
+        self.writer.inner().flush()
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        if !self.header_written {
+            // magic, deflate, no flags, no mtime, no extra flags, unknown OS
+            self.writer
+                .inner()
+                .write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff])?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    // compresses self.block as a single fixed huffman block
+    fn compress_block(&mut self, last: bool) {
+        let data = std::mem::take(&mut self.block);
+        let w = &mut self.writer;
+        w.write_bits(last as u32, 1);
+        w.write_bits(1, 2); // fixed huffman
+
+        let mut head = vec![usize::MAX; 1 << HASH_BITS];
+        let mut prev = vec![usize::MAX; data.len()];
+        // every position with at least MIN_MATCH bytes left is chained onto the head of its hash bucket
+        let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+            if i + MIN_MATCH <= data.len() {
+                let h = hash(&data, i);
+                prev[i] = head[h];
+                head[h] = i;
+            }
+        };
+
+        let mut i = 0;
+        while i < data.len() {
+            let (mut best_len, mut best_dist) = (0, 0);
+            if i + MIN_MATCH <= data.len() {
+                let mut candidate = head[hash(&data, i)];
+                let mut chain = 0;
+                let max_len = MAX_MATCH.min(data.len() - i);
+                while candidate != usize::MAX && i - candidate <= MAX_DISTANCE && chain < MAX_CHAIN
+                {
+                    let len = data[candidate..]
+                        .iter()
+                        .zip(&data[i..i + max_len])
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    if len > best_len {
+                        best_len = len;
+                        best_dist = i - candidate;
+                        if len == max_len {
+                            break;
+                        }
+                    }
+                    candidate = prev[candidate];
+                    chain += 1;
+                }
+            }
+            if best_len >= MIN_MATCH {
+                write_length(w, best_len);
+                write_distance(w, best_dist);
+                for j in i..i + best_len {
+                    insert(j, &mut head, &mut prev);
+                }
+                i += best_len;
+            } else {
+                write_literal(w, data[i] as u32);
+                insert(i, &mut head, &mut prev);
+                i += 1;
+            }
+        }
+        write_literal(w, 256); // end of block
+        self.block = data;
+        self.block.clear();
+    }
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let v = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_literal<W: Write>(w: &mut BitWriter<W>, value: u32) {
+    match value {
+        0..=143 => w.write_code(0x30 + value, 8),
+        144..=255 => w.write_code(0x190 + value - 144, 9),
+        256..=279 => w.write_code(value - 256, 7),
+        _ => w.write_code(0xC0 + value - 280, 8),
+    }
+}
+
+fn write_length<W: Write>(w: &mut BitWriter<W>, len: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= len)
+        .unwrap();
+    write_literal(w, 257 + code as u32);
+    w.write_bits(
+        (len - LENGTH_BASE[code] as usize) as u32,
+        LENGTH_EXTRA[code] as u32,
+    );
+}
+
+fn write_distance<W: Write>(w: &mut BitWriter<W>, dist: usize) {
+    let code = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| base as usize <= dist)
+        .unwrap();
+    w.write_code(code as u32, 5);
+    w.write_bits(
+        (dist - DISTANCE_BASE[code] as usize) as u32,
+        DISTANCE_EXTRA[code] as u32,
+    );
+}
+
+impl<W: Write> Write for GzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.finished {
+            return Err(std::io::Error::other(
+                "write after the gzip stream was finished",
+            ));
+        }
+        self.write_header()?;
+        let len = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..len]);
+        self.crc = crc32(self.crc, &buf[..len]);
+        self.size = self.size.wrapping_add(len as u32);
+        if self.block.len() == BLOCK_SIZE {
+            self.compress_block(false);
+            self.writer.flush_bytes()?;
+        }
+        Ok(len)
+    }
+
+    // only flushes whole compressed blocks, partial input stays buffered until finish
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush_bytes()?;
+        self.writer.inner().flush()
+    }
+}
+
+impl<W: Write> Drop for GzEncoder<W> {
+    // best effort, there's nowhere to report an error from here
+    fn drop(&mut self) {
+        if self.writer.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        // incremental updates match a single pass
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn gzip_empty() {
+        // this is byte for byte what `gzip -n` produces for empty input, apart from the OS byte
+        let out = GzEncoder::new(Vec::new()).finish().unwrap();
+        assert_eq!(
+            out,
+            vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn gzip_compresses_repetitive_input() {
+        let mut encoder = GzEncoder::new(Vec::new());
+        let line = b"1,1.0000,0.0000,1.0000,false\n";
+        for _ in 0..10_000 {
+            encoder.write_all(line).unwrap();
+        }
+        let out = encoder.finish().unwrap();
+        assert!(out.len() < line.len() * 10_000 / 20);
+        let len = out.len();
+        assert_eq!(
+            &out[len - 4..],
+            &((line.len() * 10_000) as u32).to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn gzip_finish_on_drop() {
+        let line = b"1,1.0000,0.0000,1.0000,false\n";
+        let mut encoder = GzEncoder::new(Vec::new());
+        encoder.write_all(line).unwrap();
+        let finished = encoder.finish().unwrap();
+
+        let mut dropped = Vec::new();
+        {
+            let mut encoder = GzEncoder::new(&mut dropped);
+            encoder.write_all(line).unwrap();
+        }
+        assert_eq!(dropped, finished);
+
+        // finishing twice writes the trailer once, and nothing can be written after it
+        let mut twice = Vec::new();
+        let mut encoder = GzEncoder::new(&mut twice);
+        encoder.write_all(line).unwrap();
+        encoder.try_finish().unwrap();
+        encoder.try_finish().unwrap();
+        assert!(encoder.write_all(line).is_err());
+        drop(encoder);
+        assert_eq!(twice, finished);
+    }
+}
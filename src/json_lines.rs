@@ -0,0 +1,329 @@
+use std::io::{BufRead, BufReader, Read};
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use serde::de::value::{Error, MapDeserializer};
+use serde::de::{self, Deserializer, IntoDeserializer, Visitor};
+use serde::{forward_to_deserialize_any, Deserialize};
+
+use crate::transaction_reader::{RawTransactionRow, ReaderOptions};
+use crate::TransactionRow;
+
+/// reads newline-delimited JSON, one transaction object per line like
+/// `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}`, into the same rows TransactionReader reads from CSV
+/// keys are the CSV headers, client, tx and amount can be numbers or strings, a missing or null amount is the same
+/// as an empty CSV field, only flat objects are supported, and blank lines are skipped
+pub struct JsonLinesReader<R> {
+    reader: BufReader<R>,
+    options: ReaderOptions,
+}
+
+impl<R: Read> JsonLinesReader<R> {
+    pub fn from_reader(rdr: R) -> JsonLinesReader<R> {
+        JsonLinesReader {
+            reader: BufReader::new(rdr),
+            options: ReaderOptions::default(),
+        }
+    }
+
+    /// every line that parses into a valid transaction, skipping the rest, like TransactionReader::valid_records
+    pub fn valid_records(&mut self) -> JsonValidRecordsIter<'_, R> {
+        JsonValidRecordsIter {
+            reader: &mut self.reader,
+            options: &self.options,
+            line: Vec::new(),
+        }
+    }
+}
+
+pub struct JsonValidRecordsIter<'r, R: 'r> {
+    reader: &'r mut BufReader<R>,
+    options: &'r ReaderOptions,
+    line: Vec<u8>,
+}
+
+impl<'r, R: Read> Iterator for JsonValidRecordsIter<'r, R> {
+    type Item = TransactionRow;
+
+    fn next(&mut self) -> Option<TransactionRow> {
+        loop {
+            self.line.clear();
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) | Err(_) => return None, // an io error would most likely just repeat, so stop
+                Ok(_) => {}
+            }
+            let line = match std::str::from_utf8(&self.line) {
+                Ok(line) if !line.trim().is_empty() => line,
+                _ => continue, // move to next on invalid UTF-8 or a blank line
+            };
+            match parse_raw_row(line)
+                .ok()
+                .and_then(|raw| raw.normalize(self.options).ok())
+                .and_then(|raw| raw.into_row(self.options).ok())
+            {
+                Some(transaction_row) => return Some(transaction_row),
+                None => continue,
+            }
+        }
+    }
+}
+
+fn parse_raw_row(line: &str) -> Result<RawTransactionRow, Error> {
+    let fields = parse_object(line)?;
+    RawTransactionRow::deserialize(MapDeserializer::new(fields.into_iter()))
+}
+
+// a scalar JSON value, numbers are kept as their text so amounts never go through f64, the same as CSV fields
+enum JsonValue {
+    Null,
+    Bool(bool),
+    Text(String),
+}
+
+impl<'de> IntoDeserializer<'de, Error> for JsonValue {
+    type Deserializer = JsonValue;
+
+    fn into_deserializer(self) -> JsonValue {
+        self
+    }
+}
+
+impl<'de> Deserializer<'de> for JsonValue {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            JsonValue::Null => visitor.visit_unit(),
+            JsonValue::Bool(b) => visitor.visit_bool(b),
+            JsonValue::Text(text) => visitor.visit_string(text),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            JsonValue::Null => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self {
+            JsonValue::Text(text) => text
+                .into_deserializer()
+                .deserialize_enum(name, variants, visitor),
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+// the key/value pairs of a flat JSON object, in order
+fn parse_object(line: &str) -> Result<Vec<(String, JsonValue)>, Error> {
+    let mut chars = line.char_indices().peekable();
+    let mut fields = Vec::new();
+    expect(&mut chars, '{')?;
+    skip_whitespace(&mut chars);
+    if chars.peek().map(|(_, c)| *c) == Some('}') {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            let key = parse_string(&mut chars)?;
+            expect(&mut chars, ':')?;
+            let value = parse_value(line, &mut chars)?;
+            fields.push((key, value));
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                Some((_, ',')) => {}
+                Some((_, '}')) => break,
+                _ => return Err(de::Error::custom("expected , or }")),
+            }
+        }
+    }
+    skip_whitespace(&mut chars);
+    match chars.next() {
+        None => Ok(fields),
+        Some(_) => Err(de::Error::custom("trailing characters after object")),
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<CharIndices<'_>>) {
+    while chars.next_if(|(_, c)| c.is_ascii_whitespace()).is_some() {}
+}
+
+fn expect(chars: &mut Peekable<CharIndices<'_>>, expected: char) -> Result<(), Error> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some((_, c)) if c == expected => Ok(()),
+        _ => Err(de::Error::custom(format!("expected {}", expected))),
+    }
+}
+
+fn parse_value(line: &str, chars: &mut Peekable<CharIndices<'_>>) -> Result<JsonValue, Error> {
+    skip_whitespace(chars);
+    let start = match chars.peek() {
+        None => return Err(de::Error::custom("expected a value")),
+        Some((_, '"')) => return parse_string(chars).map(JsonValue::Text),
+        Some((_, '{')) | Some((_, '[')) => {
+            return Err(de::Error::custom("nested values are not supported"))
+        }
+        Some((start, _)) => *start,
+    };
+    // a number, true, false or null, which run until the next delimiter
+    let mut end = line.len();
+    while let Some((i, c)) = chars.peek() {
+        if *c == ',' || *c == '}' || c.is_ascii_whitespace() {
+            end = *i;
+            break;
+        }
+        chars.next();
+    }
+    match &line[start..end] {
+        "null" => Ok(JsonValue::Null),
+        "true" => Ok(JsonValue::Bool(true)),
+        "false" => Ok(JsonValue::Bool(false)),
+        number
+            if number
+                .chars()
+                .all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) =>
+        {
+            Ok(JsonValue::Text(number.to_string()))
+        }
+        _ => Err(de::Error::custom("invalid value")),
+    }
+}
+
+fn parse_string(chars: &mut Peekable<CharIndices<'_>>) -> Result<String, Error> {
+    expect(chars, '"')?;
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            None => return Err(de::Error::custom("unterminated string")),
+            Some((_, '"')) => return Ok(string),
+            Some((_, '\\')) => {
+                let c = match chars.next() {
+                    Some((_, '"')) => '"',
+                    Some((_, '\\')) => '\\',
+                    Some((_, '/')) => '/',
+                    Some((_, 'b')) => '\u{8}',
+                    Some((_, 'f')) => '\u{c}',
+                    Some((_, 'n')) => '\n',
+                    Some((_, 'r')) => '\r',
+                    Some((_, 't')) => '\t',
+                    Some((_, 'u')) => parse_unicode_escape(chars)?,
+                    _ => return Err(de::Error::custom("invalid escape")),
+                };
+                string.push(c);
+            }
+            Some((_, c)) => string.push(c),
+        }
+    }
+}
+
+// after \u, a surrogate pair is two escapes in a row
+fn parse_unicode_escape(chars: &mut Peekable<CharIndices<'_>>) -> Result<char, Error> {
+    let first = parse_hex4(chars)?;
+    let code = if (0xD800..0xDC00).contains(&first) {
+        let low = match (chars.next(), chars.next()) {
+            (Some((_, '\\')), Some((_, 'u'))) => parse_hex4(chars)?,
+            _ => return Err(de::Error::custom("unpaired surrogate")),
+        };
+        if !(0xDC00..0xE000).contains(&low) {
+            return Err(de::Error::custom("unpaired surrogate"));
+        }
+        0x10000 + ((first - 0xD800) << 10) + (low - 0xDC00)
+    } else {
+        first
+    };
+    char::from_u32(code).ok_or_else(|| de::Error::custom("unpaired surrogate"))
+}
+
+fn parse_hex4(chars: &mut Peekable<CharIndices<'_>>) -> Result<u32, Error> {
+    let mut code = 0;
+    for _ in 0..4 {
+        let digit = chars
+            .next()
+            .and_then(|(_, c)| c.to_digit(16))
+            .ok_or_else(|| de::Error::custom("invalid \\u escape"))?;
+        code = code * 16 + digit;
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json_lines::JsonLinesReader;
+    use crate::{TransactionReader, TransactionRow};
+
+    // the JSON-lines equivalent of a CSV line, numbers as JSON numbers and amounts as strings
+    fn to_json_line(csv_line: &str) -> String {
+        let fields: Vec<&str> = csv_line.split(',').map(str::trim).collect();
+        let amount = match fields.get(3) {
+            Some(amount) if !amount.is_empty() => format!(r#", "amount": "{}""#, amount),
+            _ => String::new(),
+        };
+        format!(
+            r#"{{"type": "{}", "client": {}, "tx": {}{}}}"#,
+            fields[0], fields[1], fields[2], amount
+        )
+    }
+
+    #[test]
+    fn same_rows_as_csv() {
+        let csv = include_str!("../test.csv");
+        let json_lines: String = csv
+            .lines()
+            .skip(1)
+            .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+            .map(|line| to_json_line(line) + "\n")
+            .collect();
+        let from_csv: Vec<TransactionRow> = TransactionReader::from_reader(csv.as_bytes())
+            .valid_records()
+            .collect();
+        let from_json: Vec<TransactionRow> = JsonLinesReader::from_reader(json_lines.as_bytes())
+            .valid_records()
+            .collect();
+        assert!(!from_csv.is_empty());
+        assert_eq!(from_json, from_csv);
+    }
+
+    #[test]
+    fn json_values() {
+        let input = br#"{"t\u0079pe": "deposit", "client": 1, "tx": 1, "amount": 1.5}
+{ "tx" : "2" , "client" : "1" , "type" : "withdrawal" , "amount" : "0.5" }
+
+{"type": "dispute", "client": 1, "tx": 1, "amount": null, "note": "unknown keys are ignored"}
+{"type": "resolve", "client": 1, "tx": 1, "amount": "1.0"}
+{"type": "deposit", "client": 70000, "tx": 3, "amount": 1}
+{"type": "deposit", "client": 1, "tx": 4, "amount": [1]}
+{"type": "deposit", "client": 1, "tx": 5, "amount": 1} trailing
+{"type": "deposit", "client": 1, "tx": 6, "amount": 1.00001}
+not json
+{"type": "unlock", "client": 1, "tx": 0}"#;
+        let rows: Vec<TransactionRow> = JsonLinesReader::from_reader(&input[..])
+            .valid_records()
+            .collect();
+        let csv = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.5
+withdrawal, 1, 2, 0.5
+dispute, 1, 1,
+unlock, 1, 0,
+";
+        let expected: Vec<TransactionRow> = TransactionReader::from_reader(&csv[..])
+            .valid_records()
+            .collect();
+        assert_eq!(expected.len(), 4);
+        assert_eq!(rows, expected);
+    }
+}
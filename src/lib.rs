@@ -0,0 +1,1544 @@
+use crate::transaction_engine::state_name;
+use crate::TransactionState::{Chargeback, Disputed, Resolved};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+
+#[cfg(feature = "gzip")]
+pub mod gzip;
+pub mod json_lines;
+pub mod log;
+pub mod multi_asset;
+pub mod store;
+pub mod transaction_engine;
+pub mod transaction_reader;
+
+pub use crate::json_lines::JsonLinesReader;
+pub use crate::multi_asset::MultiAssetEngine;
+pub use crate::store::{ClientMap, ClientStore, TransactionMap, TransactionStore};
+pub use crate::transaction_engine::TransactionEngine;
+pub use crate::transaction_reader::{
+    FileMetadata, RawTransactionRow, RecordError, SourceRecord, TransactionReader,
+    TransactionReaderBuilder,
+};
+
+// number of places past the decimal to support
+pub const DECIMAL_PLACES: u32 = 4;
+
+/// the asset or currency a row is in, rows without one are in the implicit asset, the empty string
+pub type AssetId = String;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    tx: u32,
+    client: u16,
+    kind: TransactionKind,
+    amount: Decimal, // Deposit is positive, Withdrawal is negative
+    state: TransactionState,
+    raw_amount: Option<Decimal>, // as read, only with TransactionReaderBuilder::raw_amounts
+    // the part of amount a dispute holds, all of it unless disputed with an amount, kept after a chargeback as the
+    // part charged back, zero while Resolved
+    held_amount: Decimal,
+}
+
+impl Transaction {
+    /// the amount exactly as it was read, before it was rounded to the amount balances are computed with, only
+    /// kept with TransactionReaderBuilder::raw_amounts, and not kept by TransactionEngine::save
+    /// negative for a withdrawal, like the amount applied
+    pub fn raw_amount(&self) -> Option<Decimal> {
+        self.raw_amount
+    }
+
+    /// the part of amount disputing this holds, which a resolve returns to available and a chargeback removes, just
+    /// the disputed part if it was disputed with an amount, see TransactionReaderBuilder::allow_partial_disputes
+    /// zero unless it's disputed or charged back
+    pub fn held_amount(&self) -> Decimal {
+        self.held_amount
+    }
+}
+
+// the type a Transaction was read as, disputes check this rather than inferring it from amount's sign
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// serialized as `resolved`, `disputed` or `chargeback`, the same as dump_transactions_csv and checkpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionState {
+    // we assume the state can flip back and forth between Disputed and Resolved unlimited times
+    // but Chargeback is final
+    Resolved, // the default case, or Resolved after a Dispute
+    Disputed,
+    Chargeback, // final state, all future transactions modifying this will be ignored
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TransactionMod {
+    tx: u32,
+    client: Option<u16>, // None applies it to the original transaction's client, see optional_mod_client
+    state: TransactionState,
+    amount: Option<Decimal>, // only on a partial dispute, see TransactionReaderBuilder::allow_partial_disputes
+    op_id: Option<u64>,      // from an op_id column, see TransactionEngine::with_mod_dedup
+}
+
+// an admin row clearing every lock on client, tx is only kept so the row can be written back out
+#[derive(Debug, PartialEq)]
+pub struct TransactionUnlock {
+    tx: u32,
+    client: u16,
+}
+
+// moves amount from client's available to to_client's total, see TransactionReaderBuilder::transfers
+#[derive(Debug, PartialEq)]
+pub struct TransactionTransfer {
+    tx: u32,
+    client: u16,
+    to_client: u16,
+    amount: Decimal,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TransactionRow {
+    New(Transaction),
+    Mod(TransactionMod),
+    Unlock(TransactionUnlock),
+    Transfer(TransactionTransfer),
+}
+
+impl TransactionRow {
+    fn tx(&self) -> u32 {
+        match self {
+            TransactionRow::New(tx) => tx.tx,
+            TransactionRow::Mod(tx) => tx.tx,
+            TransactionRow::Unlock(tx) => tx.tx,
+            TransactionRow::Transfer(tx) => tx.tx,
+        }
+    }
+
+    // the canonical type,client,tx,amount CSV fields for this row, withdrawals are written with a positive amount
+    // a transfer's to_client has no column here, so it's written as the source client's side only
+    fn canonical_record(&self) -> [String; 4] {
+        let (r#type, client, tx, amount) = match self {
+            TransactionRow::New(tx) if tx.kind == TransactionKind::Withdrawal => {
+                ("withdrawal", Some(tx.client), tx.tx, Some(-tx.amount))
+            }
+            TransactionRow::New(tx) => ("deposit", Some(tx.client), tx.tx, Some(tx.amount)),
+            TransactionRow::Mod(tx) => match tx.state {
+                Disputed => ("dispute", tx.client, tx.tx, tx.amount),
+                Resolved => ("resolve", tx.client, tx.tx, None),
+                Chargeback => ("chargeback", tx.client, tx.tx, None),
+            },
+            TransactionRow::Unlock(tx) => ("unlock", Some(tx.client), tx.tx, None),
+            TransactionRow::Transfer(tx) => ("transfer", Some(tx.client), tx.tx, Some(tx.amount)),
+        };
+        [
+            r#type.to_string(),
+            client.map(|c| c.to_string()).unwrap_or_default(),
+            tx.to_string(),
+            amount.map(|a| a.to_string()).unwrap_or_default(),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Client {
+    client: u16,
+    total: Decimal,
+    held: Decimal,
+    locked: bool,
+    #[serde(skip)]
+    tx_count: u64, // successfully applied deposits/withdrawals
+    #[serde(skip)]
+    last_tx: Option<u32>, // the most recent of those, None if there were none, ie a seeded client
+    #[serde(skip)]
+    lock_reasons: Vec<LockReason>, // locked is true if and only if this isn't empty
+    #[serde(skip)]
+    ever_disputed: bool, // set by the first dispute of any of its transactions, never cleared
+    #[serde(skip)]
+    open_disputes: u32, // transactions currently disputed, see TransactionEngine::with_max_open_disputes
+}
+
+/// why a client is locked, a client stays locked until every reason is cleared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockReason {
+    Chargeback,
+    Manual,
+    Regulatory,
+}
+
+impl Client {
+    fn new(client: u16, total: Decimal) -> Client {
+        Client {
+            client,
+            total,
+            held: Decimal::new(0, total.scale()),
+            locked: false,
+            tx_count: 0,
+            last_tx: None,
+            lock_reasons: Vec::new(),
+            ever_disputed: false,
+            open_disputes: 0,
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// how many deposits and withdrawals have been applied to this client
+    pub fn tx_count(&self) -> u64 {
+        self.tx_count
+    }
+
+    /// the tx id of the most recently applied deposit or withdrawal
+    pub fn last_tx(&self) -> Option<u32> {
+        self.last_tx
+    }
+
+    /// whether any of this client's transactions has ever been disputed, even if the dispute was since resolved
+    pub fn ever_disputed(&self) -> bool {
+        self.ever_disputed
+    }
+
+    /// how many of this client's transactions are disputed right now
+    pub fn open_disputes(&self) -> u32 {
+        self.open_disputes
+    }
+
+    pub fn lock_reasons(&self) -> &[LockReason] {
+        &self.lock_reasons
+    }
+
+    fn lock(&mut self, reason: LockReason) {
+        if !self.lock_reasons.contains(&reason) {
+            self.lock_reasons.push(reason);
+        }
+        self.locked = true;
+    }
+
+    fn unlock(&mut self, reason: LockReason) {
+        self.lock_reasons.retain(|r| *r != reason);
+        self.locked = !self.lock_reasons.is_empty();
+    }
+
+    /// total - held
+    pub fn available(&self) -> Decimal {
+        self.total - self.held
+    }
+
+    /// total - held, rounded to places with strategy and rescaled to exactly that many
+    pub fn available_rounded(&self, places: u32, strategy: RoundingStrategy) -> Decimal {
+        let mut available = self.available().round_dp_with_strategy(places, strategy);
+        available.rescale(places);
+        available
+    }
+
+    /// the client, available, held, total and locked CSV fields, as dump_client_csv writes them
+    pub fn to_csv_record(&self) -> [String; 5] {
+        [
+            self.client.to_string(),
+            self.available().to_string(),
+            self.held.to_string(),
+            self.total.to_string(),
+            self.locked.to_string(),
+        ]
+    }
+}
+
+/// ie `client 1: available 1.0000, held 0.5000, total 1.5000, locked`
+impl std::fmt::Display for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "client {}: available {}, held {}, total {}, {}",
+            self.client,
+            self.available(),
+            self.held,
+            self.total,
+            if self.locked { "locked" } else { "unlocked" }
+        )
+    }
+}
+
+/// options controlling how clients are formatted on output, these never change the engine's internal state
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OutputOptions {
+    /// if set, the held column is rounded to this many places on output only, internally held stays at the engine's decimal places
+    pub held_display_places: Option<u32>,
+    /// prepend a UTF-8 byte order mark so Excel detects the encoding
+    pub write_bom: bool,
+    /// quote the available/held/total columns so downstream parsers read them as strings, not floats
+    /// this writes with csv's QuoteStyle::Always, which applies to the whole writer, so the header, client and
+    /// locked columns are quoted too
+    pub quote_numbers: bool,
+}
+
+pub fn dump_client_csv<'a, W: std::io::Write>(
+    wtr: W,
+    clients: impl Iterator<Item = &'a Client>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    dump_client_csv_with_options(wtr, clients, &OutputOptions::default())
+}
+
+/// the order dump_client_csv_sorted_by writes clients in, ties are always broken by client id ascending
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ClientSort {
+    #[default]
+    ByClientId,
+    /// largest total first
+    ByTotalDesc,
+    /// largest held first
+    ByHeldDesc,
+}
+
+/// like dump_client_csv, but sorted by client id ascending, so output is deterministic regardless of the engine's
+/// iteration order, this has to collect every client first
+pub fn dump_client_csv_sorted<'a, W: std::io::Write>(
+    wtr: W,
+    clients: impl Iterator<Item = &'a Client>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    dump_client_csv_sorted_by(wtr, clients, ClientSort::ByClientId)
+}
+
+/// like dump_client_csv_sorted, but in sort's order, ie largest balances first for reporting
+pub fn dump_client_csv_sorted_by<'a, W: std::io::Write>(
+    wtr: W,
+    clients: impl Iterator<Item = &'a Client>,
+    sort: ClientSort,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut clients: Vec<&Client> = clients.collect();
+    match sort {
+        ClientSort::ByClientId => clients.sort_by_key(|c| c.client),
+        ClientSort::ByTotalDesc => {
+            clients.sort_by(|a, b| b.total.cmp(&a.total).then(a.client.cmp(&b.client)))
+        }
+        ClientSort::ByHeldDesc => {
+            clients.sort_by(|a, b| b.held.cmp(&a.held).then(a.client.cmp(&b.client)))
+        }
+    }
+    dump_client_csv(wtr, clients.into_iter())
+}
+
+pub fn dump_client_csv_with_options<'a, W: std::io::Write>(
+    wtr: W,
+    clients: impl Iterator<Item = &'a Client>,
+    options: &OutputOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = StreamingClientWriter::with_options(wtr, options)?;
+    for client in clients {
+        wtr.write_client(client)?;
+    }
+    wtr.finish()?;
+    Ok(())
+}
+
+/// writes clients as CSV one at a time as they're handed to it, nothing is collected, so it can be fed straight
+/// from TransactionEngine::clients() or anything else, the header is written by new
+/// dump_client_csv does the same for an iterator
+pub struct StreamingClientWriter<W: std::io::Write> {
+    wtr: csv::Writer<W>,
+    held_display_places: Option<u32>,
+}
+
+impl<W: std::io::Write> StreamingClientWriter<W> {
+    pub fn new(wtr: W) -> Result<Self, Box<dyn std::error::Error>> {
+        StreamingClientWriter::with_options(wtr, &OutputOptions::default())
+    }
+
+    pub fn with_options(
+        mut wtr: W,
+        options: &OutputOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if options.write_bom {
+            wtr.write_all(b"\xEF\xBB\xBF")?;
+        }
+        let mut wtr = csv::WriterBuilder::new()
+            .quote_style(if options.quote_numbers {
+                csv::QuoteStyle::Always
+            } else {
+                csv::QuoteStyle::Necessary
+            })
+            .from_writer(wtr);
+        wtr.write_record(["client", "available", "held", "total", "locked"])?;
+        Ok(StreamingClientWriter {
+            wtr,
+            held_display_places: options.held_display_places,
+        })
+    }
+
+    pub fn write_client(&mut self, client: &Client) -> Result<(), Box<dyn std::error::Error>> {
+        let mut record = client.to_csv_record();
+        if let Some(places) = self.held_display_places {
+            let mut held = client.held.round_dp(places);
+            held.rescale(places);
+            record[2] = held.to_string();
+        }
+        self.wtr.write_record(&record)?;
+        Ok(())
+    }
+
+    /// flushes everything written and returns the inner writer
+    pub fn finish(self) -> Result<W, Box<dyn std::error::Error>> {
+        self.wtr
+            .into_inner()
+            .map_err(|e| e.error().to_string().into())
+    }
+}
+
+/// writes clients as a JSON array of objects with the same fields as the CSV output, ie
+/// `[{"client":1,"available":"1.0000","held":"0.0000","total":"1.0000","locked":false}]`
+/// amounts are strings so they aren't read back as floats and lose precision
+pub fn dump_client_json<'a, W: std::io::Write>(
+    mut wtr: W,
+    clients: impl Iterator<Item = &'a Client>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    wtr.write_all(b"[")?;
+    for (i, client) in clients.enumerate() {
+        if i > 0 {
+            wtr.write_all(b",")?;
+        }
+        write!(
+            wtr,
+            "{{\"client\":{},\"available\":{},\"held\":{},\"total\":{},\"locked\":{}}}",
+            client.client,
+            json_string(&client.available().to_string()),
+            json_string(&client.held.to_string()),
+            json_string(&client.total.to_string()),
+            client.locked
+        )?;
+    }
+    wtr.write_all(b"]\n")?;
+    wtr.flush()?;
+    Ok(())
+}
+
+/// writes transactions as CSV with tx, client, amount and state columns, for reconciling the ledger rather than
+/// just balances, withdrawals have negative amounts and state is resolved, disputed or chargeback
+/// transactions are written in the order given, TransactionEngine::transactions is in no particular order
+pub fn dump_transactions_csv<'a, W: std::io::Write>(
+    wtr: W,
+    txs: impl Iterator<Item = &'a Transaction>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_writer(wtr);
+    wtr.write_record(["tx", "client", "amount", "state"])?;
+    for tx in txs {
+        wtr.write_record(&[
+            tx.tx.to_string(),
+            tx.client.to_string(),
+            tx.amount.to_string(),
+            state_name(tx.state).to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// applies rows to engine in order and writes every one that was applied along with the client's available balance
+/// right after it, in other words a running-balance ledger
+/// this has to replay every row in order through a full engine and writes one line per applied row, so it's as
+/// expensive as processing the file plus writing output roughly the size of the input, only use it when needed
+pub fn dump_running_balance_csv<W: std::io::Write>(
+    wtr: W,
+    engine: &mut TransactionEngine,
+    rows: impl Iterator<Item = TransactionRow>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_writer(wtr);
+    wtr.write_record(["type", "client", "tx", "amount", "available"])?;
+    for row in rows {
+        let (_, client) = engine.affected_ids(&row);
+        let [r#type, client_field, tx, amount] = row.canonical_record();
+        // skipped by with_client_filter, so not applied either
+        if !engine.accepts(&row) || engine.apply(row).is_err() {
+            continue;
+        }
+        // the client always exists after a successful apply
+        let available = engine.client(client).unwrap().available();
+        wtr.write_record(&[r#type, client_field, tx, amount, available.to_string()])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// writes clients as CSV like dump_client_csv, plus tx_count, last_tx and ever_disputed columns for reconciliation
+/// and risk scoring, last_tx is empty for a client with no deposits or withdrawals
+pub fn dump_client_csv_extended<'a, W: std::io::Write>(
+    wtr: W,
+    clients: impl Iterator<Item = &'a Client>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_writer(wtr);
+    wtr.write_record([
+        "client",
+        "available",
+        "held",
+        "total",
+        "locked",
+        "tx_count",
+        "last_tx",
+        "ever_disputed",
+    ])?;
+    for client in clients {
+        let mut record = client.to_csv_record().to_vec();
+        record.push(client.tx_count.to_string());
+        record.push(client.last_tx.map(|tx| tx.to_string()).unwrap_or_default());
+        record.push(client.ever_disputed.to_string());
+        wtr.write_record(&record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// writes clients as CSV like dump_client_csv, plus an asset column, one row per client per asset, see
+/// MultiAssetEngine::clients
+pub fn dump_client_csv_with_asset<'a, W: std::io::Write>(
+    wtr: W,
+    clients: impl Iterator<Item = (&'a str, &'a Client)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_writer(wtr);
+    wtr.write_record(["client", "asset", "available", "held", "total", "locked"])?;
+    for (asset, client) in clients {
+        let mut record = client.to_csv_record().to_vec();
+        record.insert(1, asset.to_string());
+        wtr.write_record(&record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// reads rdr like TransactionReader does, and writes just the valid records back out as canonical CSV without
+/// applying them: lowercase types, no whitespace, amounts at DECIMAL_PLACES, withdrawals with a positive amount
+pub fn normalize_csv<R: std::io::Read, W: std::io::Write>(
+    rdr: R,
+    wtr: W,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut tx_reader = TransactionReader::from_reader(rdr);
+    let mut wtr = csv::Writer::from_writer(wtr);
+    wtr.write_record(["type", "client", "tx", "amount"])?;
+    for row in tx_reader.valid_records() {
+        wtr.write_record(row.canonical_record())?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// quotes and escapes s as a JSON string
+pub(crate) fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// how many records were rejected by apply_reporting_rejections and the others built on it, by which stage
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RejectionCounts {
+    pub parse_errors: u64, // records the reader couldn't turn into a transaction
+    pub engine_rejections: u64, // valid transactions the engine refused
+}
+
+impl RejectionCounts {
+    pub fn total(&self) -> u64 {
+        self.parse_errors + self.engine_rejections
+    }
+}
+
+impl std::ops::AddAssign for RejectionCounts {
+    fn add_assign(&mut self, other: RejectionCounts) {
+        self.parse_errors += other.parse_errors;
+        self.engine_rejections += other.engine_rejections;
+    }
+}
+
+/// applies every record from reader to engine, calling on_rejection with every record rejected by either, its row
+/// replaced with Err and the reason, stopping at the first error on_rejection returns
+/// the other apply_*_rejections are this with a particular on_rejection
+pub fn apply_reporting_rejections<R: std::io::Read, E>(
+    reader: &mut TransactionReader<R>,
+    engine: &mut TransactionEngine,
+    mut on_rejection: impl FnMut(SourceRecord) -> Result<(), E>,
+) -> Result<RejectionCounts, E> {
+    let mut counts = RejectionCounts::default();
+    for mut record in reader.records_with_source() {
+        let reason = match record.row {
+            Ok(row) => match engine.apply(row) {
+                Ok(()) => continue,
+                Err(e) => {
+                    counts.engine_rejections += 1;
+                    e.to_string()
+                }
+            },
+            Err(reason) => {
+                counts.parse_errors += 1;
+                reason
+            }
+        };
+        record.row = Err(reason);
+        on_rejection(record)?;
+    }
+    Ok(counts)
+}
+
+/// applies every record from reader to engine, counting the ones rejected by either
+pub fn apply_counting_rejections<R: std::io::Read>(
+    reader: &mut TransactionReader<R>,
+    engine: &mut TransactionEngine,
+) -> RejectionCounts {
+    match apply_reporting_rejections(reader, engine, |_| Ok::<(), Infallible>(())) {
+        Ok(counts) => counts,
+        Err(never) => match never {},
+    }
+}
+
+/// applies every record from reader to engine, and for every record rejected by either, writes a JSON line to log
+/// with its line number, client, tx, the reason, and the raw fields, client and tx are null if they didn't parse
+pub fn apply_logging_rejections<R: std::io::Read, W: std::io::Write>(
+    reader: &mut TransactionReader<R>,
+    engine: &mut TransactionEngine,
+    mut log: W,
+) -> Result<RejectionCounts, Box<dyn std::error::Error>> {
+    let counts = apply_reporting_rejections(reader, engine, |record| {
+        let reason = record.row.err().unwrap_or_default();
+        let fields: Vec<String> = record.fields.iter().map(|f| json_string(f)).collect();
+        writeln!(
+            log,
+            "{{\"line\":{},\"client\":{},\"tx\":{},\"reason\":{},\"fields\":[{}]}}",
+            record.line,
+            record.client.map_or("null".to_string(), |c| c.to_string()),
+            record.tx.map_or("null".to_string(), |tx| tx.to_string()),
+            json_string(&reason),
+            fields.join(",")
+        )
+    })?;
+    log.flush()?;
+    Ok(counts)
+}
+
+/// applies every record from each reader in turn to engine, and writes every record rejected by either to w as
+/// CSV, with the fields as they were read plus a reason column, so rejected rows can be investigated or fixed
+/// and fed back in, the header is the first reader's with reason added, if it has one
+/// records that didn't even parse are kept as read too, so fields may not line up with the header
+pub fn apply_writing_rejections<R: std::io::Read, W: std::io::Write>(
+    readers: &mut [TransactionReader<R>],
+    engine: &mut TransactionEngine,
+    w: W,
+) -> Result<RejectionCounts, Box<dyn std::error::Error>> {
+    let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(w);
+    if let Some(mut headers) = readers.first_mut().and_then(|reader| reader.headers()) {
+        headers.push("reason".to_string());
+        wtr.write_record(&headers)?;
+    }
+    let mut counts = RejectionCounts::default();
+    for reader in readers {
+        counts += apply_reporting_rejections(reader, engine, |mut record| {
+            record.fields.push(record.row.err().unwrap_or_default());
+            wtr.write_record(&record.fields)
+        })?;
+    }
+    wtr.flush()?;
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction_engine::{ApplyError, IdBounds, StateCounts};
+    use crate::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_full_engine() {
+        // we aren't going to bother testing invalid records here, because we already test they aren't included in transaction_reader tests
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 3, 3, 3.0
+# next deposit will be ignored because it's a duplicate tx id
+deposit, 1, 3, 2.0
+# this withdrawal will be ignored too for duplicate tx id
+withdrawal, 1, 2, 1.0
+# withdrawal for non-existent client will fail
+withdrawal, 100, 4, 1.0
+# non-sequential tx ids are fine
+withdrawal, 3, 50, 1.0
+# non-sequential client ids are fine too
+deposit, 50, 51, 50.5555
+
+# now let's dispute
+deposit, 2, 5, 5.0
+# a chargeback when in the resolved state is ignored
+chargeback, 2, 5,
+dispute, 2, 5,
+# a second dispute is ignored
+dispute, 2, 5,
+resolve, 2, 5,
+# a chargeback when in the resolved state is ignored
+chargeback, 2, 5,
+# but a dispute and then chargeback is final
+dispute, 2, 5,
+chargeback, 2, 5,
+# resolve will not work
+resolve, 2, 5,
+
+# even though client 2 has 2.000 left, withdrawal will fail due to the account being locked
+withdrawal, 2, 6, 1.0
+# but a deposit will work
+deposit, 2, 7, 1.0
+# a dispute against a deposit where the client id does not match the original is rejected
+dispute, 3, 7,
+
+# withdrawal where not enough funds are available will fail
+withdrawal, 50, 8, 60
+# outrageously large deposit works
+deposit, 50, 19, 7922816251426433751
+# deposit with overflow will fail, 2^96 doesn't fit in a Decimal at all, amounts are read exactly rather than through
+# f64, so anything smaller with room for 4 places, ie 792281625142643375172, is a valid deposit
+deposit, 50, 20, 79228162514264337593543950336
+
+";
+
+        let expected_client_csv = b"\
+client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+2,3.0000,0.0000,3.0000,true
+3,2.0000,0.0000,2.0000,false
+50,7922816251426433801.5555,0.0000,7922816251426433801.5555,false
+";
+
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine.apply_all(tx_reader.valid_records());
+
+        // we are going to sort it by client id because it needs ordered to compare it
+        let mut clients: Vec<&Client> = tx_engine.clients().collect();
+        clients.sort_by_key(|c| c.client);
+
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv(&mut out, clients.into_iter()).unwrap();
+
+        // for debugging
+        //use std::io::{stdout, Write};
+        //stdout().write_all(&out).unwrap();
+
+        assert_eq!(&expected_client_csv[..], &out)
+    }
+
+    #[test]
+    fn test_from_ledger() {
+        let input_file = include_bytes!("../test.csv");
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine.apply_all(tx_reader.valid_records());
+        let mut original: Vec<u8> = Vec::new();
+        dump_client_csv_sorted(&mut original, tx_engine.clients()).unwrap();
+
+        // exported, then read back in the same order
+        let mut ledger: Vec<u8> = Vec::new();
+        normalize_csv(&input_file[..], &mut ledger).unwrap();
+        let rows: Vec<TransactionRow> = TransactionReader::from_reader(&ledger[..])
+            .valid_records()
+            .collect();
+        let replayed = TransactionEngine::from_ledger(rows);
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv_sorted(&mut out, replayed.clients()).unwrap();
+        assert_eq!(out, original);
+        assert_eq!(replayed.stats(), tx_engine.stats());
+    }
+
+    #[test]
+    fn test_state_counts() {
+        let mut tx_reader = TransactionReader::from_reader(&include_bytes!("../test.csv")[..]);
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine.apply_all(tx_reader.valid_records());
+        let counts = tx_engine.state_counts();
+        assert_eq!(
+            counts.resolved + counts.disputed + counts.chargeback,
+            tx_engine.id_bounds().distinct_txs
+        );
+
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 1.0
+deposit, 1, 3, 1.0
+deposit, 2, 4, 1.0
+withdrawal, 2, 5, 0.5
+dispute, 1, 1,
+dispute, 1, 2,
+resolve, 1, 2,
+dispute, 2, 4,
+chargeback, 2, 4,
+dispute, 1, 9,
+";
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine.apply_all(TransactionReader::from_reader(&input_file[..]).valid_records());
+        assert_eq!(
+            tx_engine.state_counts(),
+            StateCounts {
+                resolved: 3,
+                disputed: 1,
+                chargeback: 1
+            }
+        );
+        assert_eq!(
+            TransactionEngine::default().state_counts(),
+            StateCounts::default()
+        );
+    }
+
+    #[test]
+    fn test_id_bounds() {
+        let mut tx_reader = TransactionReader::from_reader(&include_bytes!("../test.csv")[..]);
+        let mut tx_engine = TransactionEngine::default();
+        assert_eq!(tx_engine.id_bounds(), IdBounds::default());
+        tx_engine.apply_all(tx_reader.valid_records());
+        // client 100 was rejected, so isn't counted
+        assert_eq!(
+            tx_engine.id_bounds(),
+            IdBounds {
+                max_tx: Some(51),
+                max_client: Some(50),
+                distinct_clients: 4,
+                distinct_txs: 9,
+            }
+        );
+        assert!(tx_engine.id_bounds().tx_ids_skipped());
+
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine.apply_all((1..=3).map(|tx| {
+            TransactionRow::New(Transaction {
+                tx,
+                client: 1,
+                kind: TransactionKind::Deposit,
+                amount: Decimal::from_str("1.0000").unwrap(),
+                state: Resolved,
+                raw_amount: None,
+                held_amount: Decimal::ZERO,
+            })
+        }));
+        assert!(!tx_engine.id_bounds().tx_ids_skipped());
+    }
+
+    #[test]
+    fn test_optional_mod_client() {
+        use crate::transaction_engine::ClientMismatchPolicy;
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, , 1,
+resolve, 1, 1,
+dispute, 2, 1,
+dispute, , 1,
+chargeback, , 1,
+deposit, , 2, 1.0
+";
+        // required by default
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(tx_reader.valid_records().count(), 3);
+
+        // a missing client applies to the original transaction's client, one that's there is still checked
+        let mut tx_engine = TransactionEngine::default()
+            .with_client_mismatch_policy(ClientMismatchPolicy::RecordAndReject);
+        let mut tx_reader = TransactionReaderBuilder::new()
+            .optional_mod_client(true)
+            .from_reader(&input_file[..]);
+        assert_eq!(tx_engine.apply_all(tx_reader.valid_records()), 5);
+        assert_eq!(tx_engine.client_mismatches(), &[(1, 2)]);
+        let client = tx_engine.client(1).unwrap();
+        assert!(client.locked);
+        assert_eq!(client.total, Decimal::ZERO);
+        assert!(tx_engine.client(0).is_none());
+
+        // trust_original_client ignores the client on every dispute/resolve/chargeback
+        let mut tx_engine = TransactionEngine::default()
+            .with_client_mismatch_policy(ClientMismatchPolicy::RecordAndReject);
+        let mut tx_reader = TransactionReaderBuilder::new()
+            .trust_original_client(true)
+            .from_reader(&input_file[..]);
+        assert_eq!(tx_engine.apply_all(tx_reader.valid_records()), 5);
+        assert!(tx_engine.client_mismatches().is_empty());
+        assert!(tx_engine.client(1).unwrap().locked);
+        assert!(tx_engine.client(2).is_none());
+    }
+
+    #[test]
+    fn test_held_display_places() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.2345
+deposit, 1, 2, 2.0
+dispute, 1, 1,
+";
+
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine.apply_all(tx_reader.valid_records());
+
+        let options = OutputOptions {
+            held_display_places: Some(2),
+            ..OutputOptions::default()
+        };
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv_with_options(&mut out, tx_engine.clients(), &options).unwrap();
+        assert_eq!(
+            &b"client,available,held,total,locked\n1,2.0000,1.23,3.2345,false\n"[..],
+            &out
+        );
+
+        // only the output is rounded, internal state still has full precision
+        let client = tx_engine.clients().next().unwrap();
+        assert_eq!(client.held, Decimal::new(12345, DECIMAL_PLACES));
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv(&mut out, tx_engine.clients()).unwrap();
+        assert_eq!(
+            &b"client,available,held,total,locked\n1,2.0000,1.2345,3.2345,false\n"[..],
+            &out
+        );
+    }
+
+    #[test]
+    fn test_write_bom() {
+        let mut client = Client::new(1, Decimal::new(10000, DECIMAL_PLACES));
+        client.locked = true;
+
+        let options = OutputOptions {
+            write_bom: true,
+            ..OutputOptions::default()
+        };
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv_with_options(&mut out, std::iter::once(&client), &options).unwrap();
+        assert_eq!(
+            &b"\xEF\xBB\xBFclient,available,held,total,locked\n1,1.0000,0.0000,1.0000,true\n"[..],
+            &out
+        );
+
+        // off by default
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv(&mut out, std::iter::once(&client)).unwrap();
+        assert_eq!(
+            &b"client,available,held,total,locked\n1,1.0000,0.0000,1.0000,true\n"[..],
+            &out
+        );
+    }
+
+    #[test]
+    fn test_quote_numbers() {
+        let client = Client::new(50, Decimal::from_str("7922816251426433801.5555").unwrap());
+
+        let options = OutputOptions {
+            quote_numbers: true,
+            ..OutputOptions::default()
+        };
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv_with_options(&mut out, std::iter::once(&client), &options).unwrap();
+        assert_eq!(
+            "\"client\",\"available\",\"held\",\"total\",\"locked\"\n\
+\"50\",\"7922816251426433801.5555\",\"0.0000\",\"7922816251426433801.5555\",\"false\"\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        // along with held_display_places
+        let options = OutputOptions {
+            held_display_places: Some(2),
+            ..options
+        };
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv_with_options(&mut out, std::iter::once(&client), &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out.lines().nth(1),
+            Some("\"50\",\"7922816251426433801.5555\",\"0.00\",\"7922816251426433801.5555\",\"false\"")
+        );
+    }
+
+    #[test]
+    fn test_running_balance() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 2.0
+withdrawal, 1, 3, 1.5
+# rejected, so not in the ledger
+withdrawal, 2, 4, 3.0
+dispute, 1, 1,
+resolve, 1, 1,
+";
+
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        let mut out: Vec<u8> = Vec::new();
+        dump_running_balance_csv(&mut out, &mut tx_engine, tx_reader.valid_records()).unwrap();
+
+        let expected = b"\
+type,client,tx,amount,available
+deposit,1,1,5.0000,5.0000
+deposit,2,2,2.0000,2.0000
+withdrawal,1,3,1.5000,3.5000
+dispute,1,1,,-1.5000
+resolve,1,1,,3.5000
+";
+        assert_eq!(&expected[..], &out[..]);
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(
+            json_string("a \"quote\" \\ and\nnewline\u{1}"),
+            "\"a \\\"quote\\\" \\\\ and\\nnewline\\u0001\""
+        );
+    }
+
+    #[test]
+    fn test_apply_logging_rejections() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2,\"bad \"\"amount\"\"\"
+withdrawal, 1, 3, 5.0
+dispute, 1, 1,
+";
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        let mut log: Vec<u8> = Vec::new();
+        let counts = apply_logging_rejections(&mut tx_reader, &mut tx_engine, &mut log).unwrap();
+        assert_eq!(
+            counts,
+            RejectionCounts {
+                parse_errors: 1,
+                engine_rejections: 1
+            }
+        );
+
+        let log = String::from_utf8(log).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(
+            "{\"line\":3,\"client\":null,\"tx\":null,\"reason\":\"CSV deserialize error"
+        ));
+        assert!(lines[0].ends_with(",\"fields\":[\"deposit\",\"2\",\"2\",\"bad \\\"amount\\\"\"]}"));
+        assert_eq!(
+            lines[1],
+            "{\"line\":4,\"client\":1,\"tx\":3,\"reason\":\"insufficient funds\",\"fields\":[\"withdrawal\",\"1\",\"3\",\"5.0\"]}"
+        );
+        // everything else was applied
+        assert_eq!(
+            tx_engine.client(1).unwrap().held,
+            Decimal::new(10000, DECIMAL_PLACES)
+        );
+    }
+
+    #[test]
+    fn test_apply_counting_rejections() {
+        let path = std::env::temp_dir().join("csv_transaction_engine_counting_rejections.csv");
+        std::fs::write(
+            &path,
+            "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, bad\nwithdrawal, 1, 3, 0.5\n",
+        )
+        .unwrap();
+        let mut tx_reader = TransactionReader::from_path(&path).unwrap();
+        let mut tx_engine = TransactionEngine::default();
+        let counts = apply_counting_rejections(&mut tx_reader, &mut tx_engine);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            counts,
+            RejectionCounts {
+                parse_errors: 1,
+                engine_rejections: 0
+            }
+        );
+        assert_eq!(
+            tx_engine.client(1).unwrap().total,
+            Decimal::from_str("0.5").unwrap()
+        );
+
+        // an engine rejection is counted separately
+        let input_file = b"type, client, tx, amount\nwithdrawal, 1, 1, 1.0\n";
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let counts = apply_counting_rejections(&mut tx_reader, &mut TransactionEngine::default());
+        assert_eq!((counts.parse_errors, counts.engine_rejections), (0, 1));
+        assert_eq!(counts.total(), 1);
+    }
+
+    #[test]
+    fn test_apply_reporting_rejections() {
+        let input_file = b"\
+type, client, tx, amount
+withdrawal, 1, 1, 1.0
+deposit, 1, 2, bad
+deposit, 1, 3, 1.0
+withdrawal, 2, 4, 1.0
+";
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut rejected = Vec::new();
+        let counts =
+            apply_reporting_rejections(&mut tx_reader, &mut TransactionEngine::default(), |r| {
+                rejected.push((r.line, r.row.unwrap_err()));
+                Ok::<(), ()>(())
+            })
+            .unwrap();
+        assert_eq!((counts.parse_errors, counts.engine_rejections), (1, 2));
+        assert_eq!(rejected[0], (2, "unknown client".to_string()));
+        assert_eq!(rejected[1].0, 3);
+        assert_eq!(rejected[2], (5, "unknown client".to_string()));
+
+        // the first error from on_rejection stops it, nothing after that record is applied
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        let stopped = apply_reporting_rejections(&mut tx_reader, &mut tx_engine, |r| Err(r.line));
+        assert_eq!(stopped, Err(2));
+        assert!(tx_engine.client(1).is_none());
+    }
+
+    #[test]
+    fn test_apply_writing_rejections() {
+        let day1 = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, bad
+withdrawal, 1, 3, 5.0
+";
+        let day2 = b"\
+type, client, tx, amount
+deposit, 1, 1, 2.0
+withdrawal, 1, 4, 0.5
+";
+        let mut tx_readers = vec![
+            TransactionReader::from_reader(&day1[..]),
+            TransactionReader::from_reader(&day2[..]),
+        ];
+        let mut tx_engine = TransactionEngine::default();
+        let mut sidecar = Vec::new();
+        let counts =
+            apply_writing_rejections(&mut tx_readers, &mut tx_engine, &mut sidecar).unwrap();
+        assert_eq!((counts.parse_errors, counts.engine_rejections), (1, 2));
+        assert_eq!(
+            tx_engine.client(1).unwrap().total,
+            Decimal::from_str("0.5").unwrap()
+        );
+        let sidecar = String::from_utf8(sidecar).unwrap();
+        let lines: Vec<&str> = sidecar.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "type,client,tx,amount,reason");
+        assert!(lines[1].starts_with("deposit,1,2,bad,"));
+        assert_eq!(lines[2], "withdrawal,1,3,5.0,insufficient funds");
+        assert_eq!(lines[3], "deposit,1,1,2.0,duplicate tx");
+    }
+
+    #[test]
+    fn test_dump_transactions_csv() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 0.25
+dispute, 1, 1,
+resolve, 1, 1,
+deposit, 2, 3, 2.0
+dispute, 2, 3,
+";
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine.apply_all(tx_reader.valid_records());
+        let mut txs: Vec<&Transaction> = tx_engine.transactions().collect();
+        txs.sort_by_key(|tx| tx.tx);
+        let mut out = Vec::new();
+        dump_transactions_csv(&mut out, txs.into_iter()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tx,client,amount,state\n\
+1,1,1.0000,resolved\n\
+2,1,-0.2500,resolved\n\
+3,2,2.0000,disputed\n"
+        );
+    }
+
+    #[test]
+    fn test_transaction_state_serde() {
+        use serde::de::value::{Error, StrDeserializer};
+        use serde::de::IntoDeserializer;
+        for (state, name) in [
+            (Resolved, "resolved"),
+            (Disputed, "disputed"),
+            (Chargeback, "chargeback"),
+        ] {
+            let mut wtr = csv::Writer::from_writer(Vec::new());
+            wtr.serialize(state).unwrap();
+            let out = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+            assert_eq!(out, format!("{}\n", name));
+            assert_eq!(name, state_name(state));
+            let deserializer: StrDeserializer<'_, Error> = name.into_deserializer();
+            assert_eq!(TransactionState::deserialize(deserializer), Ok(state));
+        }
+        let deserializer: StrDeserializer<'_, Error> = "Disputed".into_deserializer();
+        assert!(TransactionState::deserialize(deserializer).is_err());
+    }
+
+    #[test]
+    fn test_client_to_csv_record() {
+        let mut client = Client::new(7, Decimal::from_str("1.5000").unwrap());
+        client.held = Decimal::from_str("0.25").unwrap();
+        client.held.rescale(DECIMAL_PLACES);
+        assert_eq!(
+            client.to_csv_record(),
+            ["7", "1.2500", "0.2500", "1.5000", "false"].map(String::from)
+        );
+        client.lock(LockReason::Manual);
+        assert_eq!(client.to_csv_record()[4], "true");
+        assert_eq!(
+            client.to_string(),
+            "client 7: available 1.2500, held 0.2500, total 1.5000, locked"
+        );
+    }
+
+    #[test]
+    fn test_client_csv_extended() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+withdrawal, 1, 5, 0.5
+withdrawal, 1, 6, 5.0
+dispute, 1, 1,
+";
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine
+            .seed_clients(vec![(3, Decimal::new(1, 0))].into_iter())
+            .unwrap();
+        tx_engine.apply_all(TransactionReader::from_reader(&input_file[..]).valid_records());
+        let client = tx_engine.client(1).unwrap();
+        // the rejected withdrawal and the dispute don't count
+        assert_eq!((client.tx_count(), client.last_tx()), (2, Some(5)));
+
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv_extended(&mut out, tx_engine.clients_in_order()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+client,available,held,total,locked,tx_count,last_tx,ever_disputed
+3,1.0000,0.0000,1.0000,false,0,,false
+1,-0.5000,1.0000,0.5000,false,2,5,true
+2,2.0000,0.0000,2.0000,false,1,2,false
+"
+        );
+
+        // the default output is unchanged
+        let mut out: Vec<u8> = Vec::new();
+        dump_client_csv(&mut out, tx_engine.client(2).into_iter()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n2,2.0000,0.0000,2.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize_csv() {
+        let input_file = b"\
+type,   client, tx, amount
+deposit, 1, 1, 1.5
+withdrawal,  1,2,0.25
+deposit, 1, 3, -2.0
+bla, 1, 4, 1.0
+dispute, 1, 2,
+resolve, 1, 2,
+chargeback, 1, 2,
+";
+        let mut out: Vec<u8> = Vec::new();
+        normalize_csv(&input_file[..], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+type,client,tx,amount
+deposit,1,1,1.5000
+withdrawal,1,2,0.2500
+dispute,1,2,
+resolve,1,2,
+chargeback,1,2,
+"
+        );
+
+        // and it's idempotent
+        let once = b"type,client,tx,amount\ndeposit,1,1,1.5000\nwithdrawal,1,2,0.2500\n";
+        let mut twice: Vec<u8> = Vec::new();
+        normalize_csv(&once[..], &mut twice).unwrap();
+        assert_eq!(twice, &once[..]);
+    }
+
+    #[test]
+    fn transaction_kind_round_trip() {
+        let input_file = b"\
+type,client,tx,amount
+deposit,1,1,1.5
+withdrawal,1,2,0.0001
+dispute,1,2,
+";
+        let rows: Vec<TransactionRow> = TransactionReader::from_reader(&input_file[..])
+            .valid_records()
+            .collect();
+        let kinds: Vec<_> = rows
+            .iter()
+            .filter_map(|row| match row {
+                TransactionRow::New(tx) => Some(tx.kind),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![TransactionKind::Deposit, TransactionKind::Withdrawal]
+        );
+
+        // writing the rows back out and reading them again keeps the kind, not just the amount
+        let mut out: Vec<u8> = Vec::new();
+        normalize_csv(&input_file[..], &mut out).unwrap();
+        let reread: Vec<TransactionRow> = TransactionReader::from_reader(&out[..])
+            .valid_records()
+            .collect();
+        assert_eq!(reread, rows);
+    }
+
+    #[test]
+    fn test_decimal_places() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.5
+deposit, 1, 2, 0.125
+withdrawal, 1, 3, 0.25
+";
+        let run = |places: u32| {
+            let mut tx_reader = TransactionReaderBuilder::new()
+                .decimal_places(places)
+                .from_reader(&input_file[..]);
+            let mut tx_engine = TransactionEngine::default().with_decimal_places(places);
+            for tx_row in tx_reader.valid_records() {
+                tx_engine.apply(tx_row).unwrap();
+            }
+            let mut out: Vec<u8> = Vec::new();
+            dump_client_csv(&mut out, tx_engine.clients()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        // 0.125 has too many places at 2, so is rejected
+        assert_eq!(
+            run(2),
+            "client,available,held,total,locked\n1,1.25,0.00,1.25,false\n"
+        );
+        assert_eq!(
+            run(4),
+            "client,available,held,total,locked\n1,1.3750,0.0000,1.3750,false\n"
+        );
+
+        // the engine rejects amounts with more significant places than it keeps, even if the reader allowed them
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default().with_decimal_places(2);
+        let results: Vec<_> = tx_reader
+            .valid_records()
+            .map(|tx_row| tx_engine.apply(tx_row))
+            .collect();
+        assert_eq!(
+            results,
+            vec![Ok(()), Err(ApplyError::TooManyDecimalPlaces), Ok(())]
+        );
+    }
+
+    #[test]
+    fn test_chain_duplicates() {
+        // a tx id repeated across files is a duplicate just like within one file
+        let day1 = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let day2 = b"type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,1.0\n";
+        let mut readers = vec![
+            TransactionReader::from_reader(&day1[..]),
+            TransactionReader::from_reader(&day2[..]),
+        ];
+        let mut tx_engine = TransactionEngine::default();
+        let results: Vec<_> = TransactionReader::chain(&mut readers)
+            .map(|tx_row| tx_engine.apply(tx_row))
+            .collect();
+        assert_eq!(results, vec![Ok(()), Err(ApplyError::DuplicateTx), Ok(())]);
+        assert_eq!(
+            tx_engine.client(1).unwrap().total,
+            Decimal::new(20000, DECIMAL_PLACES)
+        );
+    }
+
+    #[test]
+    fn test_sorted_by() {
+        let input = b"\
+type,client,tx,amount
+deposit,1,1,2.0
+deposit,2,2,5.0
+deposit,3,3,2.0
+deposit,4,4,10.0
+dispute,4,4,
+";
+        let mut tx_reader = TransactionReader::from_reader(&input[..]);
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine.apply_all(tx_reader.valid_records());
+        let dump = |sort| {
+            let mut out: Vec<u8> = Vec::new();
+            dump_client_csv_sorted_by(&mut out, tx_engine.clients(), sort).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            dump(ClientSort::ByTotalDesc),
+            "\
+client,available,held,total,locked
+4,0.0000,10.0000,10.0000,false
+2,5.0000,0.0000,5.0000,false
+1,2.0000,0.0000,2.0000,false
+3,2.0000,0.0000,2.0000,false
+"
+        );
+        let by_held = dump(ClientSort::ByHeldDesc);
+        let ids: Vec<&str> = by_held.lines().skip(1).map(|line| &line[..1]).collect();
+        assert_eq!(ids, ["4", "1", "2", "3"]);
+        let by_id = dump(ClientSort::ByClientId);
+        let ids: Vec<&str> = by_id.lines().skip(1).map(|line| &line[..1]).collect();
+        assert_eq!(ids, ["1", "2", "3", "4"]);
+    }
+
+    #[test]
+    fn test_sorted() {
+        // the same transactions per client, interleaved differently
+        let input_a = b"\
+type,client,tx,amount
+deposit,3,1,1.0
+deposit,1,2,2.0
+deposit,2,3,3.0
+withdrawal,1,4,0.5
+deposit,4,5,4.0
+";
+        let input_b = b"\
+type,client,tx,amount
+deposit,4,5,4.0
+deposit,2,3,3.0
+deposit,1,2,2.0
+withdrawal,1,4,0.5
+deposit,3,1,1.0
+";
+        let dump = |input: &[u8]| {
+            let mut tx_reader = TransactionReader::from_reader(input);
+            let mut tx_engine = TransactionEngine::default();
+            for tx_row in tx_reader.valid_records() {
+                tx_engine.apply(tx_row).unwrap();
+            }
+            let mut out: Vec<u8> = Vec::new();
+            dump_client_csv_sorted(&mut out, tx_engine.clients()).unwrap();
+            out
+        };
+        let out = dump(&input_a[..]);
+        assert_eq!(out, dump(&input_b[..]));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,3.0000,0.0000,3.0000,false
+3,1.0000,0.0000,1.0000,false
+4,4.0000,0.0000,4.0000,false
+"
+        );
+    }
+
+    #[test]
+    fn test_client_json() {
+        let input_file = b"\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.5
+dispute,2,2,
+deposit,3,3,1.0
+dispute,3,3,
+chargeback,3,3,
+";
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        for tx_row in tx_reader.valid_records() {
+            tx_engine.apply(tx_row).unwrap();
+        }
+        let mut json: Vec<u8> = Vec::new();
+        dump_client_json(&mut json, tx_engine.clients_in_order()).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert_eq!(
+            json,
+            "[{\"client\":1,\"available\":\"1.0000\",\"held\":\"0.0000\",\"total\":\"1.0000\",\"locked\":false},\
+{\"client\":2,\"available\":\"0.0000\",\"held\":\"2.5000\",\"total\":\"2.5000\",\"locked\":false},\
+{\"client\":3,\"available\":\"0.0000\",\"held\":\"0.0000\",\"total\":\"0.0000\",\"locked\":true}]\n"
+        );
+
+        // parsing the values back out gives exactly the CSV rows
+        let mut csv: Vec<u8> = Vec::new();
+        dump_client_csv(&mut csv, tx_engine.clients_in_order()).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let from_json: Vec<String> = json
+            .trim_end()
+            .trim_start_matches("[{")
+            .trim_end_matches("}]")
+            .split("},{")
+            .map(|object| {
+                object
+                    .split(',')
+                    .map(|field| field.split_once(':').unwrap().1.trim_matches('"'))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect();
+        assert_eq!(from_json, csv.lines().skip(1).collect::<Vec<_>>());
+
+        let mut empty: Vec<u8> = Vec::new();
+        dump_client_json(&mut empty, std::iter::empty()).unwrap();
+        assert_eq!(empty, b"[]\n");
+    }
+
+    #[test]
+    fn test_streaming_client_writer() {
+        let input_file = b"\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.5
+dispute,2,2,
+";
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        for tx_row in tx_reader.valid_records() {
+            tx_engine.apply(tx_row).unwrap();
+        }
+
+        let mut batch: Vec<u8> = Vec::new();
+        dump_client_csv(&mut batch, tx_engine.clients_in_order()).unwrap();
+
+        let mut wtr = StreamingClientWriter::new(Vec::new()).unwrap();
+        for client in tx_engine.clients_in_order() {
+            wtr.write_client(client).unwrap();
+        }
+        assert_eq!(wtr.finish().unwrap(), batch);
+
+        // just the header with no clients
+        let wtr = StreamingClientWriter::new(Vec::new()).unwrap();
+        assert_eq!(
+            wtr.finish().unwrap(),
+            b"client,available,held,total,locked\n"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! a minimal logging facade, shaped after the log crate, for rejected transactions and records
+//! nothing is logged until set_logger installs a logger, before that every call is a check of an empty OnceLock
+//! each record carries the module path it was logged from as its target, like log's macros do
+
+use std::fmt;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// a transaction the engine rejected
+    Warn,
+    /// a record the reader skipped
+    Debug,
+}
+
+/// what a logger gets to look at before the message is formatted
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata<'a> {
+    level: Level,
+    target: &'a str,
+}
+
+impl<'a> Metadata<'a> {
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// the module the record was logged from, e.g. csv_transaction_engine::transaction_engine
+    pub fn target(&self) -> &'a str {
+        self.target
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Record<'a> {
+    metadata: Metadata<'a>,
+    args: fmt::Arguments<'a>,
+}
+
+impl<'a> Record<'a> {
+    pub fn metadata(&self) -> &Metadata<'a> {
+        &self.metadata
+    }
+
+    pub fn level(&self) -> Level {
+        self.metadata.level
+    }
+
+    pub fn target(&self) -> &'a str {
+        self.metadata.target
+    }
+
+    pub fn args(&self) -> &fmt::Arguments<'a> {
+        &self.args
+    }
+}
+
+pub trait Log: Send + Sync {
+    /// records this returns false for are never passed to log
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool;
+
+    fn log(&self, record: &Record<'_>);
+}
+
+static LOGGER: OnceLock<&'static dyn Log> = OnceLock::new();
+
+/// installs the logger for the rest of the process, returning false if one was already installed
+pub fn set_logger(logger: &'static dyn Log) -> bool {
+    LOGGER.set(logger).is_ok()
+}
+
+#[doc(hidden)]
+pub fn __private_log(level: Level, target: &str, args: fmt::Arguments<'_>) {
+    if let Some(logger) = LOGGER.get() {
+        let metadata = Metadata { level, target };
+        if logger.enabled(&metadata) {
+            logger.log(&Record { metadata, args });
+        }
+    }
+}
+
+// logs a rejected transaction, with the calling module as the target
+macro_rules! log_warn {
+    ($($arg:tt)+) => {
+        $crate::log::__private_log($crate::log::Level::Warn, module_path!(), format_args!($($arg)+))
+    };
+}
+
+// logs a skipped record, with the calling module as the target
+macro_rules! log_debug {
+    ($($arg:tt)+) => {
+        $crate::log::__private_log($crate::log::Level::Debug, module_path!(), format_args!($($arg)+))
+    };
+}
+
+// called as log::warn! and log::debug!, like the log crate's, but only inside this crate, so nothing is exported at
+// the crate root to clash with log's macros, and a bare `warn` would be ambiguous with the built-in attribute
+pub(crate) use {log_debug as debug, log_warn as warn};
+
+#[cfg(test)]
+mod tests {
+    use crate::log::{set_logger, Level, Log, Metadata, Record};
+    use crate::{TransactionEngine, TransactionReader};
+    use std::sync::Mutex;
+
+    // the logger is global, so other tests running at the same time log into it too,
+    // only the two targets this test cares about are let through
+    struct CapturingLogger(Mutex<Vec<(Level, &'static str, String)>>);
+
+    const TARGETS: [&str; 2] = [
+        "csv_transaction_engine::transaction_engine",
+        "csv_transaction_engine::transaction_reader",
+    ];
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            TARGETS.contains(&metadata.target())
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            let target = TARGETS.iter().find(|t| **t == record.target()).unwrap();
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.level(), target, record.args().to_string()));
+        }
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+    #[test]
+    fn logs_rejections() {
+        assert!(set_logger(&LOGGER));
+        assert!(!set_logger(&LOGGER));
+        // not one of TARGETS, so filtered out before it's formatted
+        crate::log::warn!("ignored");
+        let input_file = b"\
+type, client, tx, amount
+deposit, 48001, 48001, 1.0
+deposit, 48001, 48001, 2.0
+dispute, 48002, 48002, 1.0
+deposit, 48003, 48003, bla
+";
+        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
+        let mut tx_engine = TransactionEngine::default();
+        tx_engine.apply_all(tx_reader.valid_records());
+        let logged = LOGGER.0.lock().unwrap().clone();
+        assert!(logged.iter().all(|(_, _, message)| message != "ignored"));
+        assert!(logged.contains(&(
+            Level::Warn,
+            TARGETS[0],
+            "rejected tx 48001 for client 48001: duplicate tx".to_string()
+        )));
+        assert!(logged
+            .iter()
+            .any(|(level, target, message)| *level == Level::Debug
+                && *target == TARGETS[1]
+                && message.starts_with("line 4: skipped tx 48002 for client 48002: ")));
+        // amount isn't a number, so csv can't deserialize the record at all
+        assert!(logged
+            .iter()
+            .any(|(level, target, message)| *level == Level::Debug
+                && *target == TARGETS[1]
+                && message.starts_with("line 5: ")));
+    }
+}
@@ -1,186 +1,236 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
 
-use crate::transaction_engine::TransactionEngine;
-use crate::transaction_reader::TransactionReader;
-use crate::TransactionState::{Chargeback, Disputed, Resolved};
-use rust_decimal::Decimal;
-use serde::Serialize;
+#[cfg(feature = "gzip")]
+use csv_transaction_engine::gzip::GzEncoder;
+use csv_transaction_engine::{
+    apply_counting_rejections, apply_logging_rejections, apply_writing_rejections, dump_client_csv,
+    dump_client_csv_extended, dump_client_json, Client, RejectionCounts, TransactionEngine,
+    TransactionReader,
+};
 
-mod transaction_engine;
-mod transaction_reader;
-
-// number of places past the decimal to support
-pub const DECIMAL_PLACES: u32 = 4;
-
-#[derive(Debug, PartialEq)]
-pub struct Transaction {
-    tx: u32,
-    client: u16,
-    amount: Decimal, // Deposit is positive, Withdrawal is negative
-    state: TransactionState,
-}
-
-#[derive(Debug, PartialEq)]
-pub enum TransactionState {
-    // we assume the state can flip back and forth between Disputed and Resolved unlimited times
-    // but Chargeback is final
-    Resolved, // the default case, or Resolved after a Dispute
-    Disputed,
-    Chargeback, // final state, all future transactions modifying this will be ignored
-}
-
-#[derive(Debug, PartialEq)]
-pub struct TransactionMod {
-    tx: u32,
-    client: u16,
-    state: TransactionState,
-}
-
-#[derive(Debug, PartialEq)]
-pub enum TransactionRow {
-    New(Transaction),
-    Mod(TransactionMod),
-}
-
-#[derive(Debug, PartialEq, Serialize)]
-pub struct Client {
-    client: u16,
-    total: Decimal,
-    held: Decimal,
-    locked: bool,
+fn main() {
+    if let Err(e) = run(std::env::args_os().skip(1)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
 
-impl Client {
-    fn new(client: u16, total: Decimal) -> Client {
-        Client {
-            client,
-            total,
-            held: Decimal::new(0, DECIMAL_PLACES),
-            locked: false,
+// everything main does, with args not including the program name, an error is printed as is and exits with 1
+fn run(mut args: impl Iterator<Item = OsString>) -> Result<(), Box<dyn Error>> {
+    let mut input_files = Vec::new();
+    let mut output_file = None;
+    let mut rejected_file = None;
+    let mut in_order = false;
+    let mut sorted = false;
+    let mut json = false;
+    let mut extended = false;
+    let mut log_rejections = false;
+    let mut stats = false;
+    let mut strict = false;
+    while let Some(arg) = args.next() {
+        if arg == "--in-order" {
+            in_order = true;
+        } else if arg == "--sorted" {
+            sorted = true;
+        } else if arg == "--json" {
+            json = true;
+        } else if arg == "--extended" {
+            // adds tx_count, last_tx and ever_disputed columns to the CSV
+            extended = true;
+        } else if arg == "--log-rejections" {
+            // jsonl is the only format for now
+            let format = args.next().ok_or("--log-rejections requires a format")?;
+            if format != "jsonl" {
+                return Err("--log-rejections only supports jsonl".into());
+            }
+            log_rejections = true;
+        } else if arg == "--strict" {
+            strict = true;
+        } else if arg == "--stats" {
+            stats = true;
+        } else if arg == "--rejected-output" {
+            // every rejected row, as CSV with a reason column
+            rejected_file = Some(args.next().ok_or("--rejected-output requires a path")?);
+        } else if arg == "--output" || arg == "-o" {
+            output_file = Some(args.next().ok_or("--output requires a path")?);
+        } else {
+            input_files.push(arg);
         }
     }
-
-    fn available(&self) -> Decimal {
-        self.total - self.held
+    // - reads from stdin, ie `cat data.csv | engine -`
+    if input_files.is_empty() {
+        return Err("arguments must be one or more CSV files, or - for stdin".into());
+    }
+    // each of them reports every rejection on its own, only one can be used
+    if log_rejections && rejected_file.is_some() {
+        return Err("--log-rejections and --rejected-output can't be used together".into());
     }
-}
 
-pub fn dump_client_csv<'a, W: std::io::Write>(
-    wtr: W,
-    clients: impl Iterator<Item = &'a Client>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut wtr = csv::Writer::from_writer(wtr);
-    wtr.write_record(&["client", "available", "held", "total", "locked"])?;
-    for client in clients {
-        wtr.write_record(&[
-            client.client.to_string(),
-            client.available().to_string(),
-            client.held.to_string(),
-            client.total.to_string(),
-            client.locked.to_string(),
-        ])?;
+    // files are processed in argument order as one stream
+    let mut tx_readers = Vec::with_capacity(input_files.len());
+    for input_file in input_files {
+        match TransactionReader::from_path(&input_file) {
+            Ok(tx_reader) => tx_readers.push(tx_reader),
+            Err(e) => {
+                return Err(format!(
+                    "could not open CSV file {}: {}",
+                    Path::new(&input_file).display(),
+                    e
+                )
+                .into())
+            }
+        }
+    }
+    let mut tx_engine = TransactionEngine::default();
+    let mut rejections = RejectionCounts::default();
+    if log_rejections {
+        for tx_reader in &mut tx_readers {
+            // stderr so it doesn't mix with the client CSV on stdout
+            rejections +=
+                apply_logging_rejections(tx_reader, &mut tx_engine, std::io::stderr().lock())?;
+        }
+    } else if let Some(rejected_file) = rejected_file {
+        let written = File::create(&rejected_file)
+            .map_err(|e| e.into())
+            .and_then(|file| {
+                apply_writing_rejections(&mut tx_readers, &mut tx_engine, BufWriter::new(file))
+            });
+        match written {
+            Ok(counts) => rejections = counts,
+            Err(e) => {
+                return Err(format!(
+                    "could not write rejected output file {}: {}",
+                    Path::new(&rejected_file).display(),
+                    e
+                )
+                .into())
+            }
+        }
+    } else if strict {
+        for tx_reader in &mut tx_readers {
+            rejections += apply_counting_rejections(tx_reader, &mut tx_engine);
+        }
+    } else {
+        // rejected transactions are just skipped, --log-rejections reports why
+        tx_engine.apply_all(TransactionReader::chain(&mut tx_readers));
     }
-    wtr.flush()?;
-    Ok(())
-}
 
-fn main() {
-    let input_file = std::env::args_os()
-        .nth(1)
-        .expect("first argument must be CSV file");
-    let input_file = File::open(input_file).expect("could not open CSV file");
+    if stats {
+        // stderr so it doesn't mix with the client CSV on stdout
+        eprintln!("{}", tx_engine.stats());
+    }
 
-    let mut tx_reader = TransactionReader::from_reader(input_file);
-    let mut tx_engine = TransactionEngine::default();
-    for tx_row in tx_reader.valid_records() {
-        tx_engine.apply(tx_row);
+    let dump = |wtr: &mut dyn Write| {
+        // reqs say order does not matter, so by default clients are written in whatever order the engine has them
+        let clients: Box<dyn Iterator<Item = &Client>> = if sorted {
+            // by client id, so runs can be diffed
+            let mut clients: Vec<&Client> = tx_engine.clients().collect();
+            clients.sort_by_key(|c| c.client());
+            Box::new(clients.into_iter())
+        } else if in_order {
+            // same order the clients first appeared in the input
+            Box::new(tx_engine.clients_in_order())
+        } else {
+            Box::new(tx_engine.clients())
+        };
+        if json {
+            dump_client_json(wtr, clients)
+        } else if extended {
+            dump_client_csv_extended(wtr, clients)
+        } else {
+            dump_client_csv(wtr, clients)
+        }
+    };
+    match output_file {
+        None => dump(&mut std::io::stdout())?,
+        Some(output_file) => {
+            let gzip = Path::new(&output_file).extension() == Some(OsStr::new("gz"));
+            if gzip && cfg!(not(feature = "gzip")) {
+                return Err("writing .gz output needs the gzip feature".into());
+            }
+            let file = match File::create(&output_file) {
+                Ok(file) => BufWriter::new(file),
+                Err(e) => {
+                    return Err(format!(
+                        "could not create output file {}: {}",
+                        Path::new(&output_file).display(),
+                        e
+                    )
+                    .into())
+                }
+            };
+            let written = match gzip {
+                #[cfg(feature = "gzip")]
+                true => {
+                    let mut encoder = GzEncoder::new(file);
+                    // the encoder must be finished or the gzip trailer is never written
+                    dump(&mut encoder).and_then(|_| Ok(encoder.finish()?.flush()?))
+                }
+                _ => {
+                    let mut file = file;
+                    dump(&mut file).and_then(|_| Ok(file.flush()?))
+                }
+            };
+            if let Err(e) = written {
+                return Err(format!(
+                    "could not write output file {}: {}",
+                    Path::new(&output_file).display(),
+                    e
+                )
+                .into());
+            }
+        }
     }
 
-    // could sort clients here before output, but reqs say order does not matter
-    dump_client_csv(std::io::stdout(), tx_engine.clients())
-        .expect("cannot write to stdout? (should never happen)");
+    // the client CSV is still written, so it can be inspected
+    if strict && rejections.total() > 0 {
+        return Err(format!(
+            "{} records rejected: {} could not be parsed, {} were rejected by the engine",
+            rejections.total(),
+            rejections.parse_errors,
+            rejections.engine_rejections
+        )
+        .into());
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::*;
+    use crate::run;
+    use std::ffi::OsString;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = OsString> {
+        args.iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
 
     #[test]
-    fn test_full_engine() {
-        // we aren't going to bother testing invalid records here, because we already test they aren't included in transaction_reader tests
-        let input_file = b"\
-type, client, tx, amount
-deposit, 1, 1, 1.0
-deposit, 2, 2, 2.0
-deposit, 3, 3, 3.0
-# next deposit will be ignored because it's a duplicate tx id
-deposit, 1, 3, 2.0
-# this withdrawal will be ignored too for duplicate tx id
-withdrawal, 1, 2, 1.0
-# withdrawal for non-existent client will fail
-withdrawal, 100, 4, 1.0
-# non-sequential tx ids are fine
-withdrawal, 3, 50, 1.0
-# non-sequential client ids are fine too
-deposit, 50, 51, 50.5555
-
-# now let's dispute
-deposit, 2, 5, 5.0
-# a chargeback when in the resolved state is ignored
-chargeback, 2, 5,
-dispute, 2, 5,
-# a second dispute is ignored
-dispute, 2, 5,
-resolve, 2, 5,
-# a chargeback when in the resolved state is ignored
-chargeback, 2, 5,
-# but a dispute and then chargeback is final
-dispute, 2, 5,
-chargeback, 2, 5,
-# resolve will not work
-resolve, 2, 5,
-
-# even though client 2 has 2.000 left, withdrawal will fail due to the account being locked
-withdrawal, 2, 6, 1.0
-# but a deposit will work
-deposit, 2, 7, 1.0
-# a dispute against a deposit where the client id does not match the original is rejected
-dispute, 3, 7,
-
-# withdrawal where not enough funds are available will fail
-withdrawal, 50, 8, 60
-# outrageously large deposit works
-deposit, 50, 19, 7922816251426433751
-# deposit with overflow will fail
-deposit, 50, 20, 792281625142643375172
-
-";
-
-        let expected_client_csv = b"\
-client,available,held,total,locked
-1,1.0000,0.0000,1.0000,false
-2,3.0000,0.0000,3.0000,true
-3,2.0000,0.0000,2.0000,false
-50,7922816251426433801.5555,0.0000,7922816251426433801.5555,false
-";
-
-        let mut tx_reader = TransactionReader::from_reader(&input_file[..]);
-        let mut tx_engine = TransactionEngine::default();
-        for tx_row in tx_reader.valid_records() {
-            tx_engine.apply(tx_row);
-        }
-
-        // we are going to sort it by client id because it needs ordered to compare it
-        let mut clients: Vec<&Client> = tx_engine.clients().collect();
-        clients.sort_by(|a, b| a.client.cmp(&b.client));
-
-        let mut out: Vec<u8> = Vec::new();
-        dump_client_csv(&mut out, clients.into_iter()).unwrap();
-
-        // for debugging
-        //use std::io::{stdout, Write};
-        //stdout().write_all(&out).unwrap();
-
-        assert_eq!(&expected_client_csv[..], &out)
+    fn run_errors() {
+        let err = run(args(&["does/not/exist.csv"])).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("could not open CSV file does/not/exist.csv: "));
+        assert!(run(args(&[])).is_err());
+        assert!(run(args(&["test.csv", "--output"])).is_err());
+        assert!(run(args(&["test.csv", "--log-rejections", "xml"])).is_err());
+        let err = run(args(&[
+            "test.csv",
+            "--log-rejections",
+            "jsonl",
+            "--rejected-output",
+            "rejected.csv",
+        ]))
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--log-rejections and --rejected-output can't be used together"
+        );
     }
 }
@@ -0,0 +1,148 @@
+//! balances per (client, asset) instead of per client, for input with an asset column
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::transaction_engine::ApplyError;
+use crate::{AssetId, Client, TransactionEngine, TransactionRow};
+
+/// one TransactionEngine per asset, so deposits in different assets for the same client never commingle
+///
+/// tx ids are still unique across every asset, and dispute/resolve/chargeback rows find their transaction by tx id
+/// alone, whatever asset the row says, everything else goes to the asset of its row
+/// accounts are per asset too, a chargeback only locks the client's account in that asset, and an unlock row only
+/// unlocks the asset it's in
+#[derive(Debug, Default)]
+pub struct MultiAssetEngine {
+    // configuration every asset's engine is created with
+    template: TransactionEngine,
+    engines: BTreeMap<AssetId, TransactionEngine>,
+    // the asset of every applied deposit/withdrawal
+    tx_assets: HashMap<u32, AssetId>,
+}
+
+impl MultiAssetEngine {
+    /// each asset gets an engine configured like engine, only engine's with_ settings are used, not its state
+    pub fn new(engine: TransactionEngine) -> Self {
+        MultiAssetEngine {
+            template: engine.empty_like(),
+            ..MultiAssetEngine::default()
+        }
+    }
+
+    /// applies row to asset's engine, or for a dispute/resolve/chargeback, the engine of the asset its tx is in
+    pub fn apply(&mut self, asset: &str, row: TransactionRow) -> Result<(), ApplyError> {
+        let (asset, new_tx) = match &row {
+            TransactionRow::New(tx) => match self.tx_assets.get(&tx.tx) {
+                // asset's engine would never see the tx id is taken
+                Some(other) if other != asset => return Err(ApplyError::DuplicateTx),
+                _ => (asset, Some(tx.tx)),
+            },
+            // an unknown tx goes to the row's asset, which rejects it and records the orphan mod
+            TransactionRow::Mod(tx) => (
+                self.tx_assets.get(&tx.tx).map_or(asset, |a| a.as_str()),
+                None,
+            ),
+            TransactionRow::Unlock(_) | TransactionRow::Transfer(_) => (asset, None),
+        };
+        let asset = asset.to_string();
+        let template = &self.template;
+        let engine = self
+            .engines
+            .entry(asset.clone())
+            .or_insert_with(|| template.empty_like());
+        engine.apply(row)?;
+        if let Some(tx) = new_tx {
+            self.tx_assets.entry(tx).or_insert(asset);
+        }
+        Ok(())
+    }
+
+    /// applies every (asset, row) in order, returning how many applied
+    pub fn apply_all<I: IntoIterator<Item = (AssetId, TransactionRow)>>(
+        &mut self,
+        rows: I,
+    ) -> usize {
+        rows.into_iter()
+            .map(|(asset, row)| self.apply(&asset, row))
+            .filter(Result::is_ok)
+            .count()
+    }
+
+    /// the engine for asset, None if no row has been applied to it
+    pub fn engine(&self, asset: &str) -> Option<&TransactionEngine> {
+        self.engines.get(asset)
+    }
+
+    /// every client in every asset, along with the asset, assets in order
+    pub fn clients(&self) -> impl Iterator<Item = (&str, &Client)> {
+        self.engines
+            .iter()
+            .flat_map(|(asset, engine)| engine.clients().map(move |c| (asset.as_str(), c)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dump_client_csv_with_asset, TransactionReaderBuilder};
+
+    #[test]
+    fn two_assets() {
+        let input_file = b"\
+type, client, tx, amount, asset
+deposit, 1, 1, 10.0, USD
+deposit, 1, 2, 0.5, BTC
+# more than the BTC balance, USD doesn't count
+withdrawal, 1, 3, 0.75, BTC
+withdrawal, 1, 4, 4.0, USD
+# tx ids are unique across assets
+deposit, 1, 1, 3.0, BTC
+# found by tx, whatever the asset
+dispute, 1, 2,, USD
+chargeback, 1, 2,,
+";
+        let mut engine = MultiAssetEngine::default();
+        let applied = engine.apply_all(
+            TransactionReaderBuilder::new()
+                .asset_column(true)
+                .from_reader(&input_file[..])
+                .valid_records_with_asset(),
+        );
+        assert_eq!(applied, 5);
+
+        let mut out = Vec::new();
+        dump_client_csv_with_asset(&mut out, engine.clients()).unwrap();
+        // only BTC is locked
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+client,asset,available,held,total,locked
+1,BTC,0.0000,0.0000,0.0000,true
+1,USD,6.0000,0.0000,6.0000,false
+"
+        );
+        assert!(engine.engine("EUR").is_none());
+    }
+
+    #[test]
+    fn configuration_is_shared() {
+        let mut engine =
+            MultiAssetEngine::new(TransactionEngine::default().with_max_tx_per_client(Some(1)));
+        let row = |tx, amount: &str| {
+            TransactionReaderBuilder::new()
+                .from_reader(
+                    format!("type,client,tx,amount\ndeposit,1,{},{}\n", tx, amount).as_bytes(),
+                )
+                .valid_records()
+                .next()
+                .unwrap()
+        };
+        assert!(engine.apply("USD", row(1, "1.0")).is_ok());
+        assert!(engine.apply("BTC", row(2, "1.0")).is_ok());
+        // the cap is per client per asset
+        assert_eq!(
+            engine.apply("USD", row(3, "1.0")),
+            Err(ApplyError::ClientTxCapExceeded)
+        );
+    }
+}
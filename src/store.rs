@@ -0,0 +1,407 @@
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
+use std::ops::Index;
+
+use rust_decimal::Decimal;
+
+use crate::TransactionState::*;
+use crate::{Client, Transaction, TransactionState};
+
+/// where a TransactionEngine keeps deposits/withdrawals so they can be disputed later, TransactionMap is the
+/// in-memory default, implement this to keep them elsewhere, see TransactionEngine::with_stores
+pub trait TransactionStore {
+    fn get(&self, tx: &u32) -> Option<&Transaction>;
+
+    fn get_mut(&mut self, tx: &u32) -> Option<&mut Transaction>;
+
+    /// returns the transaction previously stored as id, if any
+    fn insert(&mut self, id: u32, tx: Transaction) -> Option<Transaction>;
+
+    /// every stored transaction, in any order
+    fn values(&self) -> Box<dyn Iterator<Item = &Transaction> + '_>;
+
+    /// removes every stored transaction, returning them in any order
+    fn take_all(&mut self) -> Vec<Transaction>;
+
+    /// a new empty store configured like this one, for apply_parallel's workers and MultiAssetEngine's assets
+    fn empty_like(&self) -> Self
+    where
+        Self: Sized;
+
+    fn contains_key(&self, tx: &u32) -> bool {
+        self.get(tx).is_some()
+    }
+
+    /// how the engine changes a stored transaction's state and the amount it holds, for a dispute/resolve/chargeback
+    fn set_state(&mut self, tx: u32, state: TransactionState, held_amount: Decimal) {
+        if let Some(tx) = self.get_mut(&tx) {
+            tx.state = state;
+            tx.held_amount = held_amount;
+        }
+    }
+
+    /// how the engine changes a stored deposit's amount, for DuplicateTxPolicy::Update
+    fn set_amount(&mut self, tx: u32, amount: Decimal) {
+        if let Some(tx) = self.get_mut(&tx) {
+            tx.amount = amount;
+        }
+    }
+
+    /// true if transactions can be dropped, the engine then can't recompute totals from them in
+    /// check_invariants, and apply_parallel applies serially since what's dropped depends on the order
+    fn is_bounded(&self) -> bool {
+        false
+    }
+}
+
+/// where a TransactionEngine keeps clients, ClientMap is the in-memory default, see TransactionEngine::with_stores
+pub trait ClientStore {
+    type Values<'a>: Iterator<Item = &'a Client>
+    where
+        Self: 'a;
+
+    fn get(&self, client: &u16) -> Option<&Client>;
+
+    fn get_mut(&mut self, client: &u16) -> Option<&mut Client>;
+
+    /// returns the client previously stored as id, if any
+    fn insert(&mut self, id: u16, client: Client) -> Option<Client>;
+
+    /// every stored client, in whatever order TransactionEngine::clients should yield them
+    fn values(&self) -> Self::Values<'_>;
+
+    /// removes every stored client, returning them in any order
+    fn take_all(&mut self) -> Vec<Client>;
+
+    /// a new empty store configured like this one, for apply_parallel's workers and MultiAssetEngine's assets
+    fn empty_like(&self) -> Self
+    where
+        Self: Sized;
+
+    fn contains_key(&self, client: &u16) -> bool {
+        self.get(client).is_some()
+    }
+}
+
+/// the default client store, a HashMap, or a BTreeMap with TransactionEngine::with_btreemap
+#[derive(Debug, Clone)]
+pub enum ClientMap {
+    Hash(HashMap<u16, Client>),
+    BTree(BTreeMap<u16, Client>),
+}
+
+impl Default for ClientMap {
+    fn default() -> Self {
+        ClientMap::Hash(HashMap::new())
+    }
+}
+
+impl ClientStore for ClientMap {
+    type Values<'a> = ClientValues<'a>;
+
+    fn get(&self, client: &u16) -> Option<&Client> {
+        match self {
+            ClientMap::Hash(map) => map.get(client),
+            ClientMap::BTree(map) => map.get(client),
+        }
+    }
+
+    fn get_mut(&mut self, client: &u16) -> Option<&mut Client> {
+        match self {
+            ClientMap::Hash(map) => map.get_mut(client),
+            ClientMap::BTree(map) => map.get_mut(client),
+        }
+    }
+
+    fn insert(&mut self, id: u16, client: Client) -> Option<Client> {
+        match self {
+            ClientMap::Hash(map) => map.insert(id, client),
+            ClientMap::BTree(map) => map.insert(id, client),
+        }
+    }
+
+    fn values(&self) -> ClientValues<'_> {
+        match self {
+            ClientMap::Hash(map) => ClientValues::Hash(map.values()),
+            ClientMap::BTree(map) => ClientValues::BTree(map.values()),
+        }
+    }
+
+    fn take_all(&mut self) -> Vec<Client> {
+        match self {
+            ClientMap::Hash(map) => map.drain().map(|(_, client)| client).collect(),
+            ClientMap::BTree(map) => std::mem::take(map).into_values().collect(),
+        }
+    }
+
+    fn empty_like(&self) -> Self {
+        match self {
+            ClientMap::Hash(_) => ClientMap::Hash(HashMap::new()),
+            ClientMap::BTree(_) => ClientMap::BTree(BTreeMap::new()),
+        }
+    }
+}
+
+impl Index<&u16> for ClientMap {
+    type Output = Client;
+
+    fn index(&self, client: &u16) -> &Client {
+        self.get(client).expect("no such client")
+    }
+}
+
+/// iterator over the engine's clients, in arbitrary order by default, or sorted by client id with with_btreemap
+pub enum ClientValues<'a> {
+    Hash(hash_map::Values<'a, u16, Client>),
+    BTree(btree_map::Values<'a, u16, Client>),
+}
+
+impl<'a> Iterator for ClientValues<'a> {
+    type Item = &'a Client;
+
+    fn next(&mut self) -> Option<&'a Client> {
+        match self {
+            ClientValues::Hash(values) => values.next(),
+            ClientValues::BTree(values) => values.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            ClientValues::Hash(values) => values.size_hint(),
+            ClientValues::BTree(values) => values.size_hint(),
+        }
+    }
+}
+
+/// the default transaction store, every transaction forever, or only the most recently used with
+/// TransactionEngine::with_recent_transactions
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionMap(Transactions);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Transactions {
+    Unbounded(HashMap<u32, Transaction>),
+    Recent(RecentTransactions),
+}
+
+// the `capacity` most recently inserted or changed transactions, disputed ones are pinned, neither counted nor
+// evicted, since evicting one would leave its held funds with no way to resolve or charge them back
+#[derive(Debug, Clone, PartialEq)]
+struct RecentTransactions {
+    capacity: usize,
+    // the use it was last stamped with, None while pinned
+    transactions: HashMap<u32, (Transaction, Option<u64>)>,
+    // use stamp -> tx id, oldest first
+    order: BTreeMap<u64, u32>,
+    next_use: u64,
+}
+
+impl Default for TransactionMap {
+    fn default() -> Self {
+        TransactionMap(Transactions::Unbounded(HashMap::new()))
+    }
+}
+
+impl RecentTransactions {
+    fn touch(&mut self, id: u32) {
+        let (tx, stamp) = match self.transactions.get_mut(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Some(stamp) = stamp.take() {
+            self.order.remove(&stamp);
+        }
+        if tx.state != Disputed {
+            *stamp = Some(self.next_use);
+            self.order.insert(self.next_use, id);
+            self.next_use += 1;
+        }
+        while self.order.len() > self.capacity {
+            if let Some((_, evicted)) = self.order.pop_first() {
+                self.transactions.remove(&evicted);
+            }
+        }
+    }
+}
+
+impl TransactionMap {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        TransactionMap(Transactions::Unbounded(HashMap::with_capacity(capacity)))
+    }
+
+    pub(crate) fn recent(capacity: usize) -> Self {
+        TransactionMap(Transactions::Recent(RecentTransactions {
+            capacity,
+            transactions: HashMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+        }))
+    }
+
+    // how many transactions fit before the HashMap has to grow, None if bounded
+    #[cfg(test)]
+    pub(crate) fn capacity(&self) -> Option<usize> {
+        match &self.0 {
+            Transactions::Unbounded(map) => Some(map.capacity()),
+            Transactions::Recent(_) => None,
+        }
+    }
+}
+
+impl TransactionStore for TransactionMap {
+    fn get(&self, id: &u32) -> Option<&Transaction> {
+        match &self.0 {
+            Transactions::Unbounded(map) => map.get(id),
+            Transactions::Recent(recent) => recent.transactions.get(id).map(|(tx, _)| tx),
+        }
+    }
+
+    // doesn't count as a use, set_state and set_amount do
+    fn get_mut(&mut self, id: &u32) -> Option<&mut Transaction> {
+        match &mut self.0 {
+            Transactions::Unbounded(map) => map.get_mut(id),
+            Transactions::Recent(recent) => recent.transactions.get_mut(id).map(|(tx, _)| tx),
+        }
+    }
+
+    fn insert(&mut self, id: u32, tx: Transaction) -> Option<Transaction> {
+        match &mut self.0 {
+            Transactions::Unbounded(map) => map.insert(id, tx),
+            Transactions::Recent(recent) => {
+                let old = recent
+                    .transactions
+                    .insert(id, (tx, None))
+                    .map(|(old, stamp)| {
+                        if let Some(stamp) = stamp {
+                            recent.order.remove(&stamp);
+                        }
+                        old
+                    });
+                recent.touch(id);
+                old
+            }
+        }
+    }
+
+    fn values(&self) -> Box<dyn Iterator<Item = &Transaction> + '_> {
+        match &self.0 {
+            Transactions::Unbounded(map) => Box::new(map.values()),
+            Transactions::Recent(recent) => {
+                Box::new(recent.transactions.values().map(|(tx, _)| tx))
+            }
+        }
+    }
+
+    fn take_all(&mut self) -> Vec<Transaction> {
+        match &mut self.0 {
+            Transactions::Unbounded(map) => map.drain().map(|(_, tx)| tx).collect(),
+            Transactions::Recent(recent) => {
+                recent.order.clear();
+                recent.transactions.drain().map(|(_, (tx, _))| tx).collect()
+            }
+        }
+    }
+
+    fn empty_like(&self) -> Self {
+        match &self.0 {
+            Transactions::Unbounded(_) => TransactionMap::default(),
+            Transactions::Recent(recent) => TransactionMap::recent(recent.capacity),
+        }
+    }
+
+    fn set_state(&mut self, id: u32, state: TransactionState, held_amount: Decimal) {
+        if let Some(tx) = self.get_mut(&id) {
+            tx.state = state;
+            tx.held_amount = held_amount;
+        }
+        if let Transactions::Recent(recent) = &mut self.0 {
+            recent.touch(id);
+        }
+    }
+
+    fn set_amount(&mut self, id: u32, amount: Decimal) {
+        if let Some(tx) = self.get_mut(&id) {
+            tx.amount = amount;
+        }
+        if let Transactions::Recent(recent) = &mut self.0 {
+            recent.touch(id);
+        }
+    }
+
+    fn is_bounded(&self) -> bool {
+        matches!(self.0, Transactions::Recent(_))
+    }
+}
+
+impl Index<&u32> for TransactionMap {
+    type Output = Transaction;
+
+    fn index(&self, id: &u32) -> &Transaction {
+        self.get(id).expect("no such transaction")
+    }
+}
+
+// reads through to base and keeps every change to itself, so TransactionEngine::validate can apply rows on top of the
+// engine's transactions without copying them, memory is proportional to what the validated rows change
+#[derive(Debug)]
+pub(crate) struct TransactionOverlay<'a, T> {
+    base: Option<&'a T>, // None once take_all has taken everything
+    changed: HashMap<u32, Transaction>,
+}
+
+impl<'a, T: TransactionStore> TransactionOverlay<'a, T> {
+    pub(crate) fn new(base: &'a T) -> Self {
+        TransactionOverlay {
+            base: Some(base),
+            changed: HashMap::new(),
+        }
+    }
+
+    fn base_get(&self, id: &u32) -> Option<&'a Transaction> {
+        self.base.and_then(|base| base.get(id))
+    }
+}
+
+impl<T: TransactionStore> TransactionStore for TransactionOverlay<'_, T> {
+    fn get(&self, id: &u32) -> Option<&Transaction> {
+        self.changed.get(id).or_else(|| self.base_get(id))
+    }
+
+    // copies the transaction out of base the first time it's changed
+    fn get_mut(&mut self, id: &u32) -> Option<&mut Transaction> {
+        if !self.changed.contains_key(id) {
+            let tx = self.base_get(id)?.clone();
+            self.changed.insert(*id, tx);
+        }
+        self.changed.get_mut(id)
+    }
+
+    fn insert(&mut self, id: u32, tx: Transaction) -> Option<Transaction> {
+        let base = self.base_get(&id);
+        self.changed.insert(id, tx).or_else(|| base.cloned())
+    }
+
+    fn values(&self) -> Box<dyn Iterator<Item = &Transaction> + '_> {
+        let changed = &self.changed;
+        let base = self
+            .base
+            .into_iter()
+            .flat_map(|base| base.values())
+            .filter(move |tx| !changed.contains_key(&tx.tx));
+        Box::new(changed.values().chain(base))
+    }
+
+    fn take_all(&mut self) -> Vec<Transaction> {
+        let all = self.values().cloned().collect();
+        self.changed.clear();
+        self.base = None;
+        all
+    }
+
+    fn empty_like(&self) -> Self {
+        TransactionOverlay {
+            base: None,
+            changed: HashMap::new(),
+        }
+    }
+}
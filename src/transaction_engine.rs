@@ -1,135 +1,5020 @@
-use std::collections::hash_map::{Entry, Values};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::mpsc::sync_channel;
 
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::log;
+pub use crate::store::ClientValues;
+use crate::store::{ClientMap, ClientStore, TransactionMap, TransactionOverlay, TransactionStore};
 use crate::TransactionState::*;
-use crate::{Client, Transaction, TransactionRow};
+use crate::{
+    Client, LockReason, Transaction, TransactionKind, TransactionReaderBuilder, TransactionRow,
+    TransactionState, DECIMAL_PLACES,
+};
+
+/// the in-memory TransactionMap and ClientMap by default, with_stores takes any other TransactionStore and
+/// ClientStore
+#[derive(Debug)]
+pub struct TransactionEngine<T = TransactionMap, C = ClientMap> {
+    // in production, we'd be using a real database instead of HashMaps, see with_stores
+    transactions: T,
+    clients: C,
+    // client ids in the order they were first created, costs O(clients) extra storage
+    client_order: Vec<u16>,
+    // from seed_clients, so check_invariants knows what each seeded client's total started at
+    opening_balances: HashMap<u16, Decimal>,
+    invariant_check: InvariantCheck,
+    last_invariant_violation: Option<InvariantViolation>,
+    duplicate_tx_policy: DuplicateTxPolicy,
+    // deposits per client that can still be updated, only tracked with DuplicateTxPolicy::Update
+    updatable_deposits: HashMap<u16, HashSet<u32>>,
+    rejected_duplicates: Vec<u32>,
+    halted: bool,
+    mod_dedup: Option<ModDedupCache>,
+    max_tx_per_client: Option<usize>,
+    max_open_disputes: Option<u32>,
+    client_filter: ClientFilter,
+    client_mismatch_policy: ClientMismatchPolicy,
+    client_mismatches: Vec<(u32, u16)>,
+    negative_held_policy: NegativeHeldPolicy,
+    dispute_policy: DisputePolicy,
+    overdraft_policy: OverdraftPolicy,
+    clamped_withdrawals: Vec<(u32, Decimal)>,
+    fee_config: FeeConfig,
+    // withdrawal fees charged per client, so check_invariants knows what's missing from each total
+    fees: HashMap<u16, Decimal>,
+    // net transferred in or out of each client, so check_invariants knows what no transaction accounts for
+    transfers: HashMap<u16, Decimal>,
+    // (client, to_client, amount) of every transfer applied, by tx id, transfers share the tx id space with
+    // deposits and withdrawals, so a redelivered transfer is a duplicate like any other
+    transfer_ids: HashMap<u32, (u16, u16, Decimal)>,
+    orphan_mods: Vec<(Option<u16>, u32)>,
+    held_ratio_alert: Option<Decimal>,
+    held_ratio_alerts: Vec<(u16, u32)>,
+    decimal_places: Option<u32>, // DECIMAL_PLACES if None
+    available_rounding: Option<(u32, RoundingStrategy)>, // exact if None
+    audit: Option<Vec<AuditEntry>>, // only allocated with with_audit
+    withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    lock_policy: LockPolicy,
+    stats: RunStats, // locked_accounts is only filled in by stats()
+}
+
+/// a successful change to a client, recorded with with_audit
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub tx: u32,
+    pub client: u16,
+    pub kind: AuditKind,
+    pub total: Decimal, // the client's total after the change
+    pub held: Decimal,  // the client's held after the change
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditKind {
+    Deposit,
+    Withdrawal,
+    DepositUpdated, // a duplicate deposit that replaced the original's amount under DuplicateTxPolicy::Update
+    Dispute,
+    Resolve,
+    Chargeback,
+    Unlock,
+    Transfer, // recorded for the source client, see TransactionReaderBuilder::transfers
+}
+
+/// a change to a client caused by applying a transaction, or a rejected transaction, see apply_emitting
+/// tx is the transaction that caused it, and client the client it applied to
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineEvent {
+    ClientCreated {
+        tx: u32,
+        client: u16,
+    },
+    BalanceChanged {
+        tx: u32,
+        client: u16,
+        delta: Decimal,
+    }, // delta of total
+    HeldChanged {
+        tx: u32,
+        client: u16,
+        delta: Decimal,
+    },
+    ClientLocked {
+        tx: u32,
+        client: u16,
+    },
+    ClientUnlocked {
+        tx: u32,
+        client: u16,
+    },
+    TransactionRejected {
+        tx: u32,
+        client: u16,
+        reason: ApplyError,
+    },
+}
+
+/// a client as it is right after apply_with_delta changed it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientDelta {
+    pub client: u16,
+    pub total: Decimal,
+    pub held: Decimal,
+    pub locked: bool,
+}
+
+/// with WithdrawalDisputePolicy::HoldNegative, disputing a withdrawal adds its negative amount to held, so held can end up below zero, and a chargeback of
+/// a disputed withdrawal returns the withdrawn funds to total, the opposite of a deposit chargeback
+/// this controls how resolves and chargebacks treat that
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum NegativeHeldPolicy {
+    /// allow held below zero, and chargebacks of withdrawals
+    #[default]
+    Allow,
+    /// reject chargebacks of withdrawals, a disputed withdrawal can only be resolved
+    RejectWithdrawalChargebacks,
+    /// reject any resolve or chargeback that would leave held below zero, ie resolving a disputed deposit while a
+    /// withdrawal is still disputed
+    RejectNegativeHeld,
+}
+
+/// how disputes, resolves and chargebacks of withdrawals move funds, deposits are the same either way
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum WithdrawalDisputePolicy {
+    /// treat a withdrawal like a negative deposit: a dispute adds its negative amount to held, so held goes down
+    /// and available goes *up* by the withdrawn amount while disputed, see NegativeHeldPolicy
+    #[default]
+    HoldNegative,
+    /// a dispute provisionally credits the withdrawn amount back to total but holds all of it, so available is
+    /// unchanged while disputed and held never goes negative
+    /// a resolve means the withdrawal stands, so the provisional credit is removed from both held and total
+    /// a chargeback means the withdrawal is reversed, so the credit is released from held and stays in total,
+    /// making it available again, and the client is locked like any chargeback
+    HoldReversal,
+    /// only deposits can be disputed, a dispute/resolve/chargeback of a withdrawal is rejected with
+    /// ApplyError::ModTargetIncompatible
+    Reject,
+}
+
+/// whether a dispute can take available below zero, ie disputing a deposit that has since been withdrawn
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DisputePolicy {
+    /// hold the full amount regardless, available goes negative until it's resolved or charged back
+    #[default]
+    AllowNegativeAvailable,
+    /// reject the dispute with ApplyError::InsufficientFunds if available would end up below zero
+    RejectIfNegativeAvailable,
+}
+
+/// what happens to a withdrawal for more than the client has available
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OverdraftPolicy {
+    /// reject it with ApplyError::InsufficientFunds
+    #[default]
+    Reject,
+    /// withdraw as much as is available instead, along with any fee, the stored transaction has the clamped amount
+    /// and the amount asked for is recorded in clamped_withdrawals, rejected if nothing at all is available
+    ClampToAvailable,
+}
+
+/// which clients' transactions apply processes, the rest are skipped, see TransactionEngine::with_client_filter
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum ClientFilter {
+    #[default]
+    All,
+    /// only these clients
+    Allow(HashSet<u16>),
+    /// every client but these
+    Deny(HashSet<u16>),
+}
+
+impl ClientFilter {
+    fn accepts(&self, client: u16) -> bool {
+        match self {
+            ClientFilter::All => true,
+            ClientFilter::Allow(clients) => clients.contains(&client),
+            ClientFilter::Deny(clients) => !clients.contains(&client),
+        }
+    }
+}
+
+/// a fee charged on every withdrawal, on top of the withdrawn amount, fees must not be negative
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum FeeConfig {
+    #[default]
+    None,
+    /// the same fee for every withdrawal
+    Flat(Decimal),
+    /// this percent of the withdrawn amount, ie Percent(1) is 1%
+    Percent(Decimal),
+}
+
+/// which transactions a locked client can no longer make
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LockPolicy {
+    /// only withdrawals are rejected, deposits and disputes still apply
+    #[default]
+    WithdrawalsOnly,
+    /// withdrawals, deposits (including DuplicateTxPolicy::Update updates) and disputes are all rejected, resolves and
+    /// chargebacks of already disputed transactions still apply so held funds aren't stuck
+    AllActivity,
+}
+
+/// what to do with a dispute/resolve/chargeback whose client doesn't match the original transaction's client
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ClientMismatchPolicy {
+    /// drop it, it could be an attempt to modify someone else's transaction
+    #[default]
+    Reject,
+    /// drop it, but record (tx, client) in client_mismatches
+    RecordAndReject,
+    /// apply it against the original transaction's client, for feeds that leave the client as 0 on disputes
+    UseOriginalClient,
+}
+
+// remembers the op ids of the most recent `capacity` applied mods, evicting the oldest first
+#[derive(Debug, Default, Clone)]
+struct ModDedupCache {
+    capacity: usize,
+    order: VecDeque<u64>,
+    seen: HashSet<u64>,
+}
+
+impl ModDedupCache {
+    fn contains(&self, key: &u64) -> bool {
+        self.seen.contains(key)
+    }
+
+    fn insert(&mut self, key: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+        self.seen.insert(key);
+    }
+}
+
+/// what to do with a deposit/withdrawal whose tx id was already used
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DuplicateTxPolicy {
+    /// drop it silently
+    #[default]
+    Ignore,
+    /// drop it, but record the tx id in rejected_duplicates
+    Reject,
+    /// replace the amount of the original deposit, this is only allowed for the same client, and only if the
+    /// original deposit has never been disputed and the client has not made a withdrawal since, the new amount
+    /// must also be a deposit, otherwise it's dropped
+    Update,
+    /// drop it and halt the engine, every apply after this returns Err(ApplyError::Halted)
+    Error,
+    /// accept it without changing anything if it's the same client, type and amount as the original, ie an
+    /// at-least-once redelivery, otherwise drop it, an accepted redelivery is counted in RunStats::redeliveries,
+    /// not as applied, and isn't audited
+    AcceptIfIdentical,
+}
+
+/// what to do when a client's invariants no longer hold after an apply
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum InvariantCheck {
+    #[default]
+    Off,
+    /// panic in debug builds, do nothing in release builds
+    DebugAssert,
+    /// reject the transaction before it changes anything, apply returns ApplyError::InvariantViolation
+    Reject,
+}
+
+/// why apply rejected a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApplyError {
+    DuplicateTx,
+    UnknownClient, // a withdrawal, transfer or unlock for a client that doesn't exist yet
+    AccountLocked,
+    InsufficientFunds, // also a dispute rejected by DisputePolicy::RejectIfNegativeAvailable
+    Overflow,
+    ClientMismatch,
+    ModTargetNotFound,      // the dispute/resolve/chargeback's tx doesn't exist
+    ModTargetIncompatible, // the tx exists but can't be disputed, ie a withdrawal under WithdrawalDisputePolicy::Reject
+    IllegalStateTransition, // ie resolving a transaction that isn't disputed
+    TxFinalized,           // any dispute/resolve/chargeback of a transaction that was charged back
+    ClientTxCapExceeded,
+    NegativeHeld,           // rejected by NegativeHeldPolicy::RejectNegativeHeld
+    WithdrawalChargeback,   // rejected by NegativeHeldPolicy::RejectWithdrawalChargebacks
+    NotUpdatable, // a duplicate deposit that can't be updated under DuplicateTxPolicy::Update
+    Halted,       // a duplicate was seen earlier under DuplicateTxPolicy::Error
+    TooManyDecimalPlaces, // the amount has more places than with_decimal_places allows
+    DisputeTooLarge, // a partial dispute for more than the deposit
+    OpenDisputeCapExceeded, // the client already has with_max_open_disputes disputes open
+    SelfTransfer, // a transfer whose to_client is its own client
+    DuplicateOp,  // a dispute/resolve/chargeback whose op id with_mod_dedup already applied
+    InvariantViolation(InvariantViolation), // rejected by InvariantCheck::Reject
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyError::DuplicateTx => write!(f, "duplicate tx"),
+            ApplyError::UnknownClient => write!(f, "unknown client"),
+            ApplyError::AccountLocked => write!(f, "account locked"),
+            ApplyError::InsufficientFunds => write!(f, "insufficient funds"),
+            ApplyError::Overflow => write!(f, "overflow"),
+            ApplyError::ClientMismatch => write!(f, "client does not match original transaction"),
+            ApplyError::ModTargetNotFound => write!(f, "unknown tx"),
+            ApplyError::ModTargetIncompatible => write!(f, "tx can not be disputed"),
+            ApplyError::IllegalStateTransition => write!(f, "illegal state transition"),
+            ApplyError::TxFinalized => write!(f, "tx already charged back"),
+            ApplyError::ClientTxCapExceeded => write!(f, "client transaction cap exceeded"),
+            ApplyError::NegativeHeld => write!(f, "held would go negative"),
+            ApplyError::WithdrawalChargeback => write!(f, "withdrawal chargeback not allowed"),
+            ApplyError::NotUpdatable => write!(f, "deposit not updatable"),
+            ApplyError::Halted => write!(f, "processing halted"),
+            ApplyError::TooManyDecimalPlaces => write!(f, "too many decimal places"),
+            ApplyError::DisputeTooLarge => write!(f, "dispute amount exceeds tx"),
+            ApplyError::OpenDisputeCapExceeded => write!(f, "open dispute cap exceeded"),
+            ApplyError::SelfTransfer => write!(f, "transfer to the same client"),
+            ApplyError::DuplicateOp => write!(f, "duplicate op id"),
+            ApplyError::InvariantViolation(violation) => {
+                write!(f, "invariant violation: {:?}", violation)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApplyError {}
+
+// what applying a transaction changes, decided by TransactionEngine::evaluate without touching the engine, then
+// made by commit
+#[derive(Debug)]
+struct Effect {
+    client: ClientChange, // inserted if the client doesn't exist yet
+    tx: TxChange,
+}
+
+// a client's fields as they are after a change, all but lock_reasons, which only ever gain or lose every reason at
+// once here, so evaluating a row never allocates
+#[derive(Debug, Clone, Copy)]
+struct ClientChange {
+    client: u16,
+    total: Decimal,
+    held: Decimal,
+    tx_count: u64,
+    last_tx: Option<u32>,
+    ever_disputed: bool,
+    open_disputes: u32,
+    lock: LockChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LockChange {
+    Unchanged,
+    Lock(LockReason),
+    UnlockAll, // manual intervention, clears every reason
+}
+
+impl ClientChange {
+    // no change yet to client
+    fn of(client: &Client) -> ClientChange {
+        ClientChange {
+            client: client.client,
+            total: client.total,
+            held: client.held,
+            tx_count: client.tx_count,
+            last_tx: client.last_tx,
+            ever_disputed: client.ever_disputed,
+            open_disputes: client.open_disputes,
+            lock: LockChange::Unchanged,
+        }
+    }
+
+    // a client that doesn't exist yet, as Client::new would create it
+    fn new(client: u16, total: Decimal) -> ClientChange {
+        ClientChange::of(&Client::new(client, total))
+    }
+
+    fn available(&self) -> Decimal {
+        self.total - self.held
+    }
+
+    fn apply_to(self, client: &mut Client) {
+        client.total = self.total;
+        client.held = self.held;
+        client.tx_count = self.tx_count;
+        client.last_tx = self.last_tx;
+        client.ever_disputed = self.ever_disputed;
+        client.open_disputes = self.open_disputes;
+        match self.lock {
+            LockChange::Unchanged => {}
+            LockChange::Lock(reason) => client.lock(reason),
+            LockChange::UnlockAll => {
+                client.lock_reasons.clear();
+                client.locked = false;
+            }
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct TransactionEngine {
-    // in production, we'd be using a real database instead of HashMaps
-    transactions: HashMap<u32, Transaction>,
-    clients: HashMap<u16, Client>,
+enum TxChange {
+    // along with the fee charged for it, see FeeConfig, and the amount asked for if OverdraftPolicy clamped it
+    Insert(Transaction, Decimal, Option<Decimal>),
+    SetState(u32, TransactionState, Decimal), // along with the transaction's held_amount after the change
+    SetAmount(u32, Decimal),                  // a deposit replaced under DuplicateTxPolicy::Update
+    Transfer(u32, ClientChange, Decimal), // the destination client after the change, and the amount moved
+    None,                                 // account level, ie unlock
+    Redelivered, // an identical duplicate accepted by DuplicateTxPolicy::AcceptIfIdentical, nothing changes
+}
+
+// what apply did with a row it didn't reject
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Applied,
+    Skipped,     // by with_client_filter
+    Redelivered, // an identical duplicate, see DuplicateTxPolicy::AcceptIfIdentical
+}
+
+impl Outcome {
+    // whether apply_all and the others count it as applied, a skipped row always has
+    fn counts(self) -> bool {
+        self != Outcome::Redelivered
+    }
+}
+
+/// whether each row passed to TransactionEngine::validate would have applied, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationReport {
+    pub outcomes: Vec<Result<(), ApplyError>>,
+}
+
+impl ValidationReport {
+    /// how many rows would have applied
+    pub fn accepted(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| outcome.is_ok())
+            .count()
+    }
+
+    /// (row index, reason) of every row that would have been rejected
+    pub fn rejected(&self) -> impl Iterator<Item = (usize, &ApplyError)> {
+        self.outcomes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, outcome)| outcome.as_ref().err().map(|reason| (i, reason)))
+    }
+}
+
+/// counts of everything apply has seen, see TransactionEngine::stats
+/// a row skipped by with_client_filter is neither applied nor rejected, and an applied unlock or transfer only counts
+/// in applied
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunStats {
+    pub applied: u64,
+    pub rejected: u64,
+    pub redeliveries: u64, // identical duplicates accepted without change by DuplicateTxPolicy::AcceptIfIdentical
+    pub deposits: u64,     // applied ones, including DuplicateTxPolicy::Update updates
+    pub withdrawals: u64,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+    pub locked_accounts: usize, // at the time stats was called
+}
+
+/// how many stored transactions are in each state, see TransactionEngine::state_counts
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StateCounts {
+    pub resolved: usize, // including ones never disputed
+    pub disputed: usize,
+    pub chargeback: usize,
+}
+
+/// what apply_limited did
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LimitedRun {
+    pub rows: usize,     // looked at, applied or not, never more than max_rows
+    pub applied: usize,  // of those
+    pub limit_hit: bool, // there were rows left after max_rows, which were never applied
+}
+
+/// the highest ids and how many distinct ones the engine has stored, see TransactionEngine::id_bounds
+/// only applied deposits/withdrawals are stored, so rejected ones don't count towards any of these
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct IdBounds {
+    pub max_tx: Option<u32>,     // None if there are no transactions
+    pub max_client: Option<u16>, // None if there are no clients
+    pub distinct_clients: usize,
+    pub distinct_txs: usize,
+}
+
+impl IdBounds {
+    /// for input whose tx ids are meant to run 1, 2, 3.., whether some weren't stored, either missing from the
+    /// input or rejected
+    pub fn tx_ids_skipped(&self) -> bool {
+        self.max_tx.map_or(0, |max| max as usize) != self.distinct_txs
+    }
+}
+
+impl RunStats {
+    // adds other's counters, for apply_parallel
+    fn merge(&mut self, other: &RunStats) {
+        self.applied += other.applied;
+        self.rejected += other.rejected;
+        self.redeliveries += other.redeliveries;
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.disputes += other.disputes;
+        self.resolves += other.resolves;
+        self.chargebacks += other.chargebacks;
+    }
+
+    /// the counters in Prometheus text exposition format, ie `transactions_applied_total 1234`, for a scrape
+    /// endpoint, locked_accounts is a gauge named clients_locked
+    pub fn to_prometheus(&self) -> String {
+        let counters = [
+            ("transactions_applied_total", self.applied),
+            ("transactions_rejected_total", self.rejected),
+            ("transactions_redelivered_total", self.redeliveries),
+            ("deposits_total", self.deposits),
+            ("withdrawals_total", self.withdrawals),
+            ("disputes_total", self.disputes),
+            ("resolves_total", self.resolves),
+            ("chargebacks_total", self.chargebacks),
+        ];
+        let mut out = String::new();
+        for (name, value) in counters {
+            out.push_str(&format!("# TYPE {} counter\n{} {}\n", name, name, value));
+        }
+        out.push_str(&format!(
+            "# TYPE clients_locked gauge\nclients_locked {}\n",
+            self.locked_accounts
+        ));
+        out
+    }
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "applied: {}, rejected: {}, redeliveries: {}, deposits: {}, withdrawals: {}, disputes: {}, resolves: {}, chargebacks: {}, locked accounts: {}",
+            self.applied,
+            self.rejected,
+            self.redeliveries,
+            self.deposits,
+            self.withdrawals,
+            self.disputes,
+            self.resolves,
+            self.chargebacks,
+            self.locked_accounts
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvariantViolation {
+    AvailableOverflow(u16), // total - held can't be represented exactly
+    NegativeHeld(u16),
+    NegativeTotal(u16),
+    TotalMismatch(u16),     // total isn't what the client's transactions add up to
+    HeldMismatch(u16),      // held isn't what the client's disputed transactions add up to
+    OrphanTransaction(u32), // a stored transaction's client doesn't exist
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SeedError {
+    NegativeBalance(u16),
+    Overflow(u16), // the balance can't be represented at the engine's decimal places
+    DuplicateClient(u16),
 }
 
 impl Default for TransactionEngine {
     fn default() -> Self {
-        TransactionEngine {
-            transactions: HashMap::new(),
-            clients: HashMap::new(),
-        }
+        TransactionEngine::with_stores(TransactionMap::default(), ClientMap::default())
     }
 }
 
 impl TransactionEngine {
-    /// returns true if the transaction successfully applied, and false otherwise
-    /// if false is returned, then no modification happened at all
-    /// if this was production code, this would return a Result with a proper Error that the client could act on
-    pub fn apply(&mut self, tx: TransactionRow) -> bool {
-        match tx {
-            TransactionRow::New(tx) => {
-                if let Entry::Vacant(tx_entry) = self.transactions.entry(tx.tx) {
-                    // new transaction, but it can still be invalid if it's withdrawal for a client that does not exist or does not have enough available funds
-                    // now insert or update the client
-                    match self.clients.get_mut(&tx.client) {
-                        None => {
-                            // client does not exist
-                            if tx.amount.is_sign_negative() {
-                                // withdrawals for a new client are not allowed
-                                return false;
-                            }
-                            self.clients
-                                .insert(tx.client, Client::new(tx.client, tx.amount));
+    /// an engine that stores clients in a BTreeMap instead of a HashMap, so clients() yields them sorted by id
+    /// lookups are O(log n) instead of O(1), and each is a handful of comparisons and pointer chases instead of a
+    /// hash and usually one probe, so applying is somewhat slower, noticeably so with many clients, but with only
+    /// 65536 possible client ids the tree stays shallow, and sorted output doesn't need a separate sort pass
+    /// the BTreeMap has no hasher, so there's also no hash flooding to worry about with untrusted client ids
+    pub fn with_btreemap() -> Self {
+        TransactionEngine {
+            clients: ClientMap::BTree(BTreeMap::new()),
+            ..TransactionEngine::default()
+        }
+    }
+
+    /// an engine with room for tx_cap transactions and client_cap clients before either map has to grow, the
+    /// default starts both empty, client_cap is capped at 65536 since that's every possible client id
+    /// benches/with_capacity.rs shows no gain on 1M generated rows, pre-sizing was slightly slower (see the readme),
+    /// growing is amortized and small next to the per-row work, so main doesn't estimate a capacity from file size,
+    /// this only avoids the latency of the occasional big rehash part way through
+    pub fn with_capacity(tx_cap: usize, client_cap: usize) -> Self {
+        let client_cap = client_cap.min(u16::MAX as usize + 1);
+        TransactionEngine {
+            transactions: TransactionMap::with_capacity(tx_cap),
+            clients: ClientMap::Hash(HashMap::with_capacity(client_cap)),
+            client_order: Vec::with_capacity(client_cap),
+            ..TransactionEngine::default()
+        }
+    }
+
+    /// only keep the `capacity` most recently deposited, withdrawn or modified transactions, evicting the least
+    /// recently used past that, so memory is bounded by capacity instead of growing with every transaction
+    /// a dispute/resolve/chargeback of an evicted transaction is rejected with ModTargetNotFound, as if it never
+    /// existed, disputed transactions are never evicted and don't count towards capacity, so their held funds can
+    /// always be resolved or charged back
+    /// duplicate detection only covers kept transactions, an evicted tx id can be used again
+    /// check_invariants can no longer recompute totals, since evicted amounts are missing, so it only checks held
+    pub fn with_recent_transactions(mut self, capacity: usize) -> Self {
+        let mut transactions = TransactionMap::recent(capacity);
+        for tx in self.transactions.take_all() {
+            transactions.insert(tx.tx, tx);
+        }
+        self.transactions = transactions;
+        self
+    }
+
+    /// a new engine with the default configuration that has had rows applied in order, ie rows exported with
+    /// normalize_csv and read back, to rebuild the same clients in another engine version, rows that were rejected
+    /// originally are rejected again, so they don't need to be filtered out first
+    pub fn from_ledger<I: IntoIterator<Item = TransactionRow>>(rows: I) -> TransactionEngine {
+        let mut engine = TransactionEngine::default();
+        engine.apply_all(rows);
+        engine
+    }
+
+    /// reads what save wrote into a new engine with the default configuration, checkpoints saved before kinds,
+    /// transfers, opening balances and fees were saved still load, without those
+    pub fn load<R: Read>(r: R) -> Result<TransactionEngine, Box<dyn std::error::Error>> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .from_reader(r);
+        let mut engine = TransactionEngine::default();
+        let mut records = rdr.records();
+        let version = match records.next().transpose()? {
+            Some(record) if record.len() == 2 && &record[0] == "checkpoint" => match &record[1] {
+                "1" => 1,
+                CHECKPOINT_VERSION => 2,
+                _ => return Err(invalid_checkpoint("unsupported checkpoint version")),
+            },
+            _ => return Err(invalid_checkpoint("not a checkpoint")),
+        };
+        for record in records {
+            let record = record?;
+            match record.iter().collect::<Vec<_>>()[..] {
+                // last_tx, ever_disputed, the opening balance and fees were added later, so they're optional
+                ["client", client, total, held, tx_count, lock_reasons, ref added @ ..]
+                    if added.len() <= 4 =>
+                {
+                    let mut client = Client::new(client.parse()?, Decimal::from_str(total)?);
+                    client.held = Decimal::from_str(held)?;
+                    client.tx_count = tx_count.parse()?;
+                    if let [last_tx, ..] = added {
+                        if !last_tx.is_empty() {
+                            client.last_tx = Some(last_tx.parse()?);
                         }
-                        Some(client) => {
-                            if client.locked && tx.amount.is_sign_negative() {
-                                // withdrawals are not allowed for locked accounts
-                                return false;
-                            }
-                            let available = client.available().checked_add(tx.amount);
-                            if available.is_none() || available.unwrap().is_sign_negative() {
-                                // withdrawals that overflow or will put the available balance into negative are not allowed
-                                return false;
-                            }
-                            match client.total.checked_add(tx.amount) {
-                                None => return false, // fail transactions that overflow
-                                Some(new_total) => {
-                                    if new_total.is_sign_negative() {
-                                        // withdrawals that will put the total balance into negative are not allowed
-                                        // this could happen because a withdrawal is disputed
-                                        return false;
-                                    }
-                                    client.total = new_total;
-                                }
-                            }
+                    }
+                    if let [_, ever_disputed, ..] = added {
+                        client.ever_disputed = ever_disputed.parse()?;
+                    }
+                    if let [_, _, opening_balance, fees] = added {
+                        if !opening_balance.is_empty() {
+                            engine
+                                .opening_balances
+                                .insert(client.client, Decimal::from_str(opening_balance)?);
+                        }
+                        if !fees.is_empty() {
+                            engine.fees.insert(client.client, Decimal::from_str(fees)?);
                         }
                     }
-                    tx_entry.insert(tx);
-                    return true;
+                    for reason in lock_reasons.split('|').filter(|r| !r.is_empty()) {
+                        client.lock(
+                            lock_reason_from_name(reason)
+                                .ok_or_else(|| invalid_checkpoint("unknown lock reason"))?,
+                        );
+                    }
+                    if engine.clients.contains_key(&client.client) {
+                        return Err(invalid_checkpoint("duplicate client"));
+                    }
+                    engine.client_order.push(client.client);
+                    engine.clients.insert(client.client, client);
                 }
-                // if the transaction already exists, we ignore this one, again in production this would be an error to log or something
-                false
+                // the partial dispute amount was added later, so it's optional
+                ["tx", tx, client, amount, state, ref part @ ..]
+                    if version == 1 && part.len() <= 1 =>
+                {
+                    // before the kind was saved it was told apart by the sign, zero amounts load as deposits
+                    let kind = if amount.starts_with('-') {
+                        TransactionKind::Withdrawal
+                    } else {
+                        TransactionKind::Deposit
+                    };
+                    engine.load_tx(tx, client, kind, amount, state, part)?;
+                }
+                ["tx", tx, client, kind, amount, state, ref part @ ..] if part.len() <= 1 => {
+                    let kind = kind_from_name(kind)
+                        .ok_or_else(|| invalid_checkpoint("unknown transaction kind"))?;
+                    engine.load_tx(tx, client, kind, amount, state, part)?;
+                }
+                ["transfer", tx, client, to_client, amount] => {
+                    let (tx, client, to_client): (u32, u16, u16) =
+                        (tx.parse()?, client.parse()?, to_client.parse()?);
+                    let amount = Decimal::from_str(amount)?;
+                    if !engine.clients.contains_key(&client)
+                        || !engine.clients.contains_key(&to_client)
+                    {
+                        return Err(invalid_checkpoint("transfer for unknown client"));
+                    }
+                    if engine.transactions.contains_key(&tx)
+                        || engine
+                            .transfer_ids
+                            .insert(tx, (client, to_client, amount))
+                            .is_some()
+                    {
+                        return Err(invalid_checkpoint("duplicate transaction"));
+                    }
+                    // the net transferred per client follows from the transfers themselves
+                    let out = engine.transfers.entry(client).or_default();
+                    *out = sub_exact(*out, amount).ok_or_else(|| invalid_checkpoint("overflow"))?;
+                    let to = engine.transfers.entry(to_client).or_default();
+                    *to = add_exact(*to, amount).ok_or_else(|| invalid_checkpoint("overflow"))?;
+                }
+                _ => return Err(invalid_checkpoint("unknown record")),
             }
-            TransactionRow::Mod(tx) => {
-                match self.transactions.get_mut(&tx.tx) {
-                    None => false, // can't mod a non-existing transactions
-                    Some(orig_tx) => {
-                        if orig_tx.client != tx.client {
-                            // an update for an existing transaction but with a different client? hacker! do not apply transaction
-                            return false;
-                        }
-                        let mut client = self.clients.get_mut(&orig_tx.client).unwrap(); // this unwrap is safe because we never insert a transaction without making sure the client exists first
-                        match tx.state {
-                            Disputed => {
-                                if orig_tx.state != Resolved {
-                                    // can only switch to Disputed from Resolved, otherwise this is invalid
-                                    return false;
-                                }
-                                match client.held.checked_add(orig_tx.amount) {
-                                    None => return false, // fail on overflow
-                                    Some(held) => client.held = held,
-                                }
-                                orig_tx.state = tx.state;
-                                true
-                            }
-                            Resolved => {
-                                if orig_tx.state != Disputed {
-                                    // can only switch to Resolved from Disputed, otherwise this is invalid
-                                    return false;
-                                }
-                                match client.held.checked_sub(orig_tx.amount) {
-                                    None => return false, // fail on overflow
-                                    Some(held) => client.held = held,
-                                }
-                                orig_tx.state = tx.state;
-                                true
-                            }
-                            Chargeback => {
-                                if orig_tx.state != Disputed {
-                                    // can only switch to Chargeback from Disputed, otherwise this is invalid
-                                    return false;
-                                }
-                                match (
-                                    client.held.checked_sub(orig_tx.amount),
-                                    client.total.checked_sub(orig_tx.amount),
-                                ) {
-                                    (Some(held), Some(total)) => {
-                                        client.held = held;
-                                        client.total = total;
-                                    }
-                                    (_, _) => return false, // fail on overflow of either
-                                }
-                                orig_tx.state = tx.state;
-                                client.locked = true;
-                                true
-                            }
-                        }
+        }
+        Ok(engine)
+    }
+
+    // adds a transaction read by load
+    fn load_tx(
+        &mut self,
+        tx: &str,
+        client: &str,
+        kind: TransactionKind,
+        amount: &str,
+        state: &str,
+        part: &[&str],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tx = Transaction {
+            tx: tx.parse()?,
+            client: client.parse()?,
+            kind,
+            amount: Decimal::from_str(amount)?,
+            state: state_from_name(state)
+                .ok_or_else(|| invalid_checkpoint("unknown transaction state"))?,
+            raw_amount: None,
+            held_amount: Decimal::ZERO,
+        };
+        if !self.clients.contains_key(&tx.client) {
+            return Err(invalid_checkpoint("transaction for unknown client"));
+        }
+        tx.held_amount = match (tx.state, part) {
+            (Resolved, _) => Decimal::ZERO,
+            (_, [part]) => Decimal::from_str(part)?,
+            (_, _) => tx.amount,
+        };
+        if tx.state == Disputed {
+            self.clients.get_mut(&tx.client).unwrap().open_disputes += 1;
+        }
+        if self.transfer_ids.contains_key(&tx.tx) || self.transactions.insert(tx.tx, tx).is_some() {
+            return Err(invalid_checkpoint("duplicate transaction"));
+        }
+        Ok(())
+    }
+}
+
+impl<T: TransactionStore, C: ClientStore> TransactionEngine<T, C> {
+    /// an engine keeping transactions and clients in the given stores instead of in memory, which should be empty,
+    /// with the default configuration
+    pub fn with_stores(transactions: T, clients: C) -> Self {
+        TransactionEngine {
+            transactions,
+            clients,
+            client_order: Default::default(),
+            opening_balances: Default::default(),
+            invariant_check: Default::default(),
+            last_invariant_violation: Default::default(),
+            duplicate_tx_policy: Default::default(),
+            updatable_deposits: Default::default(),
+            rejected_duplicates: Default::default(),
+            halted: Default::default(),
+            mod_dedup: Default::default(),
+            max_tx_per_client: Default::default(),
+            max_open_disputes: Default::default(),
+            client_filter: Default::default(),
+            client_mismatch_policy: Default::default(),
+            client_mismatches: Default::default(),
+            negative_held_policy: Default::default(),
+            dispute_policy: Default::default(),
+            overdraft_policy: Default::default(),
+            clamped_withdrawals: Default::default(),
+            fee_config: Default::default(),
+            fees: Default::default(),
+            transfers: Default::default(),
+            transfer_ids: Default::default(),
+            orphan_mods: Default::default(),
+            held_ratio_alert: Default::default(),
+            held_ratio_alerts: Default::default(),
+            decimal_places: Default::default(),
+            available_rounding: Default::default(),
+            audit: Default::default(),
+            withdrawal_dispute_policy: Default::default(),
+            lock_policy: Default::default(),
+            stats: Default::default(),
+        }
+    }
+
+    pub fn with_duplicate_tx_policy(mut self, duplicate_tx_policy: DuplicateTxPolicy) -> Self {
+        self.duplicate_tx_policy = duplicate_tx_policy;
+        self
+    }
+
+    /// for exactly-once processing of streams that may be redelivered, remember the op ids of the last `capacity`
+    /// applied dispute/resolve/chargebacks, and reject a repeat of one with ApplyError::DuplicateOp without applying
+    /// it, op ids come from an optional op_id column the reader reads whenever the input has one
+    /// a dispute/resolve/chargeback without an op id can't be told apart from a legitimate repeat (dispute, resolve,
+    /// dispute) so it's never deduplicated
+    /// memory use is bounded by capacity, but once an op id is evicted a redelivery of it will be applied again
+    pub fn with_mod_dedup(mut self, capacity: usize) -> Self {
+        self.mod_dedup = Some(ModDedupCache {
+            capacity,
+            ..ModDedupCache::default()
+        });
+        self
+    }
+
+    /// caps the number of deposits/withdrawals applied per client over the engine's lifetime, once a client hits
+    /// the cap further deposits/withdrawals for them are rejected, disputes/resolves/chargebacks don't count
+    pub fn with_max_tx_per_client(mut self, max_tx_per_client: Option<usize>) -> Self {
+        self.max_tx_per_client = max_tx_per_client;
+        self
+    }
+
+    /// caps how many of a client's transactions can be disputed at once, a dispute for a client already at the cap
+    /// is rejected until one of theirs is resolved or charged back
+    pub fn with_max_open_disputes(mut self, max_open_disputes: Option<u32>) -> Self {
+        self.max_open_disputes = max_open_disputes;
+        self
+    }
+
+    pub fn with_client_mismatch_policy(
+        mut self,
+        client_mismatch_policy: ClientMismatchPolicy,
+    ) -> Self {
+        self.client_mismatch_policy = client_mismatch_policy;
+        self
+    }
+
+    pub fn with_negative_held_policy(mut self, negative_held_policy: NegativeHeldPolicy) -> Self {
+        self.negative_held_policy = negative_held_policy;
+        self
+    }
+
+    pub fn with_dispute_policy(mut self, dispute_policy: DisputePolicy) -> Self {
+        self.dispute_policy = dispute_policy;
+        self
+    }
+
+    /// only process the transactions of some clients, the rest are skipped by apply, which returns Ok without
+    /// changing anything, so they're neither applied nor rejected
+    /// a dispute/resolve/chargeback is filtered by its original transaction's client, and a transfer is skipped
+    /// unless both clients are accepted
+    pub fn with_client_filter(mut self, client_filter: ClientFilter) -> Self {
+        self.client_filter = client_filter;
+        self
+    }
+
+    pub fn with_overdraft_policy(mut self, overdraft_policy: OverdraftPolicy) -> Self {
+        self.overdraft_policy = overdraft_policy;
+        self
+    }
+
+    /// charges a fee on every withdrawal, taken from total along with the amount, so available has to cover both
+    /// or the withdrawal is rejected with InsufficientFunds, the fee isn't part of the transaction, so disputing the
+    /// withdrawal later only moves the withdrawn amount, fees are rounded up to decimal places
+    pub fn with_fee_config(mut self, fee_config: FeeConfig) -> Self {
+        self.fee_config = fee_config;
+        self
+    }
+
+    // the fee for a withdrawal of amount (negative), at decimal places, None if it can't be represented
+    fn withdrawal_fee(&self, amount: Decimal) -> Option<Decimal> {
+        let places = self.decimal_places();
+        let mut fee = match self.fee_config {
+            FeeConfig::None => Decimal::ZERO,
+            FeeConfig::Flat(fee) => fee,
+            FeeConfig::Percent(percent) => {
+                amount.abs().checked_mul(percent)? / Decimal::ONE_HUNDRED
+            }
+        }
+        .round_dp_with_strategy(places, RoundingStrategy::AwayFromZero);
+        fee.rescale(places);
+        Some(fee).filter(|fee| fee.scale() == places)
+    }
+
+    // the largest withdrawal (negative) that available covers along with its fee, and that fee, for
+    // OverdraftPolicy::ClampToAvailable, None if there's nothing to withdraw
+    fn clamped_withdrawal(&self, available: Decimal) -> Option<(Decimal, Decimal)> {
+        let places = self.decimal_places();
+        // a first guess, then stepped down a unit at a time while the fee, rounded up, doesn't fit
+        let mut amount = match self.fee_config {
+            FeeConfig::None => available,
+            FeeConfig::Flat(fee) => sub_exact(available, fee)?,
+            FeeConfig::Percent(percent) => available
+                .checked_mul(Decimal::ONE_HUNDRED)?
+                .checked_div(add_exact(Decimal::ONE_HUNDRED, percent)?)?,
+        }
+        .round_dp_with_strategy(places, RoundingStrategy::ToZero);
+        loop {
+            if amount <= Decimal::ZERO {
+                return None;
+            }
+            let fee = self.withdrawal_fee(-amount)?;
+            if add_exact(amount, fee)? <= available {
+                let mut amount = -amount;
+                amount.rescale(places);
+                return Some((amount, fee));
+            }
+            amount = sub_exact(amount, Decimal::new(1, places))?;
+        }
+    }
+
+    /// flags a client when a dispute takes their held above `ratio` of their total, without rejecting anything
+    /// compared as held > total * ratio, so a client with a zero or negative total is flagged by any positive held
+    pub fn with_held_ratio_alert(mut self, held_ratio_alert: Option<Decimal>) -> Self {
+        self.held_ratio_alert = held_ratio_alert;
+        self
+    }
+
+    /// places past the decimal balances are kept at, defaults to DECIMAL_PLACES, and must be at most 28
+    /// this should match TransactionReaderBuilder::decimal_places, deposits and withdrawals with more significant
+    /// places than this are rejected, and the rest are rescaled to exactly this many
+    pub fn with_decimal_places(mut self, decimal_places: u32) -> Self {
+        self.decimal_places = Some(decimal_places);
+        self
+    }
+
+    fn decimal_places(&self) -> u32 {
+        self.decimal_places.unwrap_or(DECIMAL_PLACES)
+    }
+
+    /// makes available() round to `places` with strategy instead of returning total - held exactly, for reporting
+    /// at fewer places than balances are kept at, balances themselves are never rounded
+    /// ie MidpointNearestEven for banker's rounding, MidpointAwayFromZero for half-up, or ToZero to truncate
+    pub fn with_available_rounding(mut self, places: u32, strategy: RoundingStrategy) -> Self {
+        self.available_rounding = Some((places, strategy));
+        self
+    }
+
+    /// records every successful change in audit_log, off by default
+    pub fn with_audit(mut self) -> Self {
+        self.audit = Some(Vec::new());
+        self
+    }
+
+    pub fn with_withdrawal_dispute_policy(
+        mut self,
+        withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    ) -> Self {
+        self.withdrawal_dispute_policy = withdrawal_dispute_policy;
+        self
+    }
+
+    pub fn with_lock_policy(mut self, lock_policy: LockPolicy) -> Self {
+        self.lock_policy = lock_policy;
+        self
+    }
+
+    pub fn with_invariant_check(mut self, invariant_check: InvariantCheck) -> Self {
+        self.invariant_check = invariant_check;
+        self
+    }
+
+    /// creates clients with opening balances (held 0, unlocked) that subsequent transactions build on
+    /// either all clients are seeded, or on error, none are
+    pub fn seed_clients(
+        &mut self,
+        balances: impl Iterator<Item = (u16, Decimal)>,
+    ) -> Result<(), SeedError> {
+        let mut seeded = Vec::new();
+        let mut seeded_ids = HashSet::new();
+        for (client, mut total) in balances {
+            if total.is_sign_negative() {
+                return Err(SeedError::NegativeBalance(client));
+            }
+            let places = self.decimal_places();
+            total.rescale(places);
+            if total.scale() != places {
+                // rescale couldn't fit the extra places without losing integer digits
+                return Err(SeedError::Overflow(client));
+            }
+            if self.clients.contains_key(&client) || !seeded_ids.insert(client) {
+                return Err(SeedError::DuplicateClient(client));
+            }
+            seeded.push(Client::new(client, total));
+        }
+        for client in seeded {
+            self.opening_balances.insert(client.client, client.total);
+            self.client_order.push(client.client);
+            self.clients.insert(client.client, client);
+        }
+        Ok(())
+    }
+
+    /// writes every client, transaction and transfer to w as CSV, so load can pick up where this engine left off,
+    /// along with each client's opening balance and fees so check_invariants still adds up after loading
+    /// only state is saved, not configuration, the with_ settings need to be applied again to the loaded engine,
+    /// nor diagnostics like rejected_duplicates, orphan_mods or audit_log, nor which deposits
+    /// DuplicateTxPolicy::Update can still update, so after loading none can be
+    pub fn save<W: Write>(&self, w: W) -> Result<(), Box<dyn std::error::Error>> {
+        let mut wtr = csv::WriterBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .from_writer(w);
+        wtr.write_record(["checkpoint", CHECKPOINT_VERSION])?;
+        // clients in order first, so the order is restored and each exists before its transactions
+        for client in self.clients_in_order() {
+            let lock_reasons: Vec<&str> = client
+                .lock_reasons
+                .iter()
+                .map(|r| lock_reason_name(*r))
+                .collect();
+            wtr.write_record(&[
+                "client".to_string(),
+                client.client.to_string(),
+                client.total.to_string(),
+                client.held.to_string(),
+                client.tx_count.to_string(),
+                lock_reasons.join("|"),
+                client.last_tx.map(|tx| tx.to_string()).unwrap_or_default(),
+                client.ever_disputed.to_string(),
+                optional(self.opening_balances.get(&client.client)),
+                optional(self.fees.get(&client.client)),
+            ])?;
+        }
+        // sorted so the same state always saves the same
+        let mut txs: Vec<&Transaction> = self.transactions.values().collect();
+        txs.sort_by_key(|tx| tx.tx);
+        for tx in txs {
+            let mut record = vec![
+                "tx".to_string(),
+                tx.tx.to_string(),
+                tx.client.to_string(),
+                kind_name(tx.kind).to_string(),
+                tx.amount.to_string(),
+                state_name(tx.state).to_string(),
+            ];
+            // only partially disputed transactions have the extra field
+            if tx.state != Resolved && tx.held_amount != tx.amount {
+                record.push(tx.held_amount.to_string());
+            }
+            wtr.write_record(&record)?;
+        }
+        let mut transfers: Vec<_> = self.transfer_ids.iter().collect();
+        transfers.sort_by_key(|(tx, _)| **tx);
+        for (tx, (client, to_client, amount)) in transfers {
+            wtr.write_record(&[
+                "transfer".to_string(),
+                tx.to_string(),
+                client.to_string(),
+                to_client.to_string(),
+                amount.to_string(),
+            ])?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// applies every row in order, returning how many applied, why the rest were rejected is only in stats()
+    /// identical redeliveries don't count as applied
+    pub fn apply_all<I: IntoIterator<Item = TransactionRow>>(&mut self, rows: I) -> usize {
+        rows.into_iter()
+            .filter_map(|row| self.apply_row(row).ok())
+            .filter(|outcome| outcome.counts())
+            .count()
+    }
+
+    /// applies rows like apply_all, but in two passes, for files whose order isn't guaranteed: deposits, withdrawals
+    /// and transfers first, in order, then disputes/resolves/chargebacks and unlocks, in order, so a dispute that
+    /// comes before its deposit still finds it, returns how many applied across both passes
+    ///
+    /// how this differs from streaming:
+    /// - every dispute/resolve/chargeback and unlock is buffered until the end, O(those rows) memory
+    /// - funds held by a dispute never block a withdrawal or transfer, they've all been applied by the time anything is
+    ///   held, so disputes are more likely to leave available negative, see DisputePolicy
+    /// - a chargeback's lock never rejects a deposit or withdrawal, and an unlock is applied after every chargeback
+    /// - stats, the audit log and everything else recorded per row are in the order applied, not the file's order
+    pub fn apply_all_two_pass<I: IntoIterator<Item = TransactionRow>>(&mut self, rows: I) -> usize {
+        let mut deferred = Vec::new();
+        let mut applied = 0;
+        for row in rows {
+            match row {
+                TransactionRow::Mod(_) | TransactionRow::Unlock(_) => deferred.push(row),
+                row => {
+                    if self.apply_row(row).is_ok_and(Outcome::counts) {
+                        applied += 1;
                     }
                 }
             }
         }
+        applied + self.apply_all(deferred)
     }
 
-    pub fn clients(&self) -> Values<'_, u16, Client> {
-        self.clients.values()
+    /// applies rows in order like apply_all, but stops after max_rows of them, so an enormous upload can't keep
+    /// the engine busy, the row after the limit is read to tell whether there were more, and then dropped
+    pub fn apply_limited<I: IntoIterator<Item = TransactionRow>>(
+        &mut self,
+        rows: I,
+        max_rows: usize,
+    ) -> LimitedRun {
+        let mut rows = rows.into_iter();
+        let mut run = LimitedRun::default();
+        for row in rows.by_ref().take(max_rows) {
+            run.rows += 1;
+            if self.apply_row(row).is_ok_and(Outcome::counts) {
+                run.applied += 1;
+            }
+        }
+        run.limit_hit = rows.next().is_some();
+        run
+    }
+
+    /// parses line as one headerless CSV row, ie `deposit, 1, 1, 5.0`, the way TransactionReader would, and applies
+    /// it, for a REPL or a quick test, false if the line is blank or a `#` comment so there was nothing to apply
+    /// the error is the RecordError if the line isn't a valid transaction, or the ApplyError if it was rejected
+    pub fn apply_csv_line(&mut self, line: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Ok(false);
+        }
+        let row = TransactionReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(trimmed.as_bytes())
+            .records_with_errors()
+            .next()
+            .ok_or("no record on line")??;
+        self.apply(row)?;
+        Ok(true)
+    }
+
+    /// applies the transaction, or returns why it was rejected, in which case no modification happened at all
+    /// an identical redelivery accepted by DuplicateTxPolicy::AcceptIfIdentical is Ok too, but only counts in
+    /// stats().redeliveries, and isn't in the audit log
+    pub fn apply(&mut self, tx: TransactionRow) -> Result<(), ApplyError> {
+        self.apply_row(tx).map(|_| ())
+    }
+
+    // apply, telling rows that changed something apart from ones accepted without change
+    fn apply_row(&mut self, tx: TransactionRow) -> Result<Outcome, ApplyError> {
+        if self.halted {
+            self.stats.rejected += 1;
+            let (tx_id, client) = self.affected_ids(&tx);
+            log_rejection(tx_id, client, &ApplyError::Halted);
+            return Err(ApplyError::Halted);
+        }
+        if self.client_filter != ClientFilter::All && !self.accepts(&tx) {
+            return Ok(Outcome::Skipped);
+        }
+        let mod_key = match (&tx, &self.mod_dedup) {
+            (TransactionRow::Mod(tx), Some(_)) => tx.op_id,
+            _ => None,
+        };
+        if let (Some(key), Some(cache)) = (&mod_key, &self.mod_dedup) {
+            if cache.contains(key) {
+                // already applied a mod with this op id, so this is a redelivery
+                self.stats.rejected += 1;
+                let (tx_id, client) = self.affected_ids(&tx);
+                log_rejection(tx_id, client, &ApplyError::DuplicateOp);
+                return Err(ApplyError::DuplicateOp);
+            }
+        }
+        // (client, tx, was over the ratio before) for disputes we need to check for a ratio alert
+        let ratio_check = match (&tx, self.held_ratio_alert) {
+            (TransactionRow::Mod(tx), Some(_)) if tx.state == Disputed => {
+                self.transactions.get(&tx.tx).map(|orig_tx| {
+                    let over = self.over_held_ratio(orig_tx.client);
+                    (orig_tx.client, tx.tx, over)
+                })
+            }
+            _ => None,
+        };
+        // what kind of change this is, for both the audit log and the stats counters
+        let kind = match &tx {
+            TransactionRow::New(new_tx)
+                if self.duplicate_tx_policy == DuplicateTxPolicy::Update
+                    && self.transactions.contains_key(&new_tx.tx) =>
+            {
+                AuditKind::DepositUpdated
+            }
+            TransactionRow::New(new_tx) if new_tx.kind == TransactionKind::Withdrawal => {
+                AuditKind::Withdrawal
+            }
+            TransactionRow::New(_) => AuditKind::Deposit,
+            TransactionRow::Mod(mod_tx) => match mod_tx.state {
+                Disputed => AuditKind::Dispute,
+                Resolved => AuditKind::Resolve,
+                Chargeback => AuditKind::Chargeback,
+            },
+            TransactionRow::Unlock(_) => AuditKind::Unlock,
+            TransactionRow::Transfer(_) => AuditKind::Transfer,
+        };
+        let (tx_id, client) = self.affected_ids(&tx);
+        match self.apply_checked(tx) {
+            Err(reason) => {
+                self.stats.rejected += 1;
+                log_rejection(tx_id, client, &reason);
+                return Err(reason);
+            }
+            Ok(false) => {
+                self.stats.redeliveries += 1;
+                return Ok(Outcome::Redelivered);
+            }
+            Ok(true) => {}
+        }
+        self.stats.applied += 1;
+        match kind {
+            AuditKind::Deposit | AuditKind::DepositUpdated => self.stats.deposits += 1,
+            AuditKind::Withdrawal => self.stats.withdrawals += 1,
+            AuditKind::Dispute => self.stats.disputes += 1,
+            AuditKind::Resolve => self.stats.resolves += 1,
+            AuditKind::Chargeback => self.stats.chargebacks += 1,
+            AuditKind::Unlock | AuditKind::Transfer => {}
+        }
+        if let (Some(key), Some(cache)) = (mod_key, &mut self.mod_dedup) {
+            cache.insert(key);
+        }
+        if let Some(log) = &mut self.audit {
+            let c = self.clients.get(&client).unwrap();
+            log.push(AuditEntry {
+                tx: tx_id,
+                client,
+                kind,
+                total: c.total,
+                held: c.held,
+            });
+        }
+        if let Some((client, tx, false)) = ratio_check {
+            if self.over_held_ratio(client) {
+                self.held_ratio_alerts.push((client, tx));
+            }
+        }
+        Ok(Outcome::Applied)
+    }
+
+    /// applies tx like apply, calling emit with every resulting change to the client, or with why it was rejected
+    /// successful applies emit, in order, ClientCreated if the client is new, BalanceChanged and HeldChanged for
+    /// whichever of total and held changed, and ClientLocked or ClientUnlocked if the client became locked or unlocked
+    /// a transfer emits those for the source client, then for the destination
+    /// a row skipped by with_client_filter succeeds without changing anything, so emits nothing
+    pub fn apply_emitting(
+        &mut self,
+        tx: TransactionRow,
+        mut emit: impl FnMut(&EngineEvent),
+    ) -> Result<(), ApplyError> {
+        let (tx_id, client_id) = self.affected_ids(&tx);
+        let to_client_id = match &tx {
+            TransactionRow::Transfer(tx) => Some(tx.to_client),
+            _ => None,
+        };
+        let before = self.clients.get(&client_id).cloned();
+        let to_before = to_client_id.map(|to_client| self.clients.get(&to_client).cloned());
+        let skipped = !self.accepts(&tx);
+        if let Err(reason) = self.apply(tx) {
+            emit(&EngineEvent::TransactionRejected {
+                tx: tx_id,
+                client: client_id,
+                reason: reason.clone(),
+            });
+            return Err(reason);
+        }
+        if skipped {
+            return Ok(());
+        }
+        self.emit_changes(tx_id, client_id, before, &mut emit);
+        if let (Some(to_client_id), Some(to_before)) = (to_client_id, to_before) {
+            self.emit_changes(tx_id, to_client_id, to_before, &mut emit);
+        }
+        Ok(())
+    }
+
+    // emits what changed about client_id since before, for apply_emitting
+    fn emit_changes(
+        &self,
+        tx_id: u32,
+        client_id: u16,
+        before: Option<Client>,
+        emit: &mut impl FnMut(&EngineEvent),
+    ) {
+        let after = self.clients.get(&client_id).unwrap(); // safe, the client always exists after a successful apply
+        let (total_before, held_before, locked_before) = match before {
+            None => {
+                emit(&EngineEvent::ClientCreated {
+                    tx: tx_id,
+                    client: client_id,
+                });
+                let zero = Decimal::new(0, self.decimal_places());
+                (zero, zero, false)
+            }
+            Some(client) => (client.total, client.held, client.locked),
+        };
+        if after.total != total_before {
+            emit(&EngineEvent::BalanceChanged {
+                tx: tx_id,
+                client: client_id,
+                delta: after.total - total_before,
+            });
+        }
+        if after.held != held_before {
+            emit(&EngineEvent::HeldChanged {
+                tx: tx_id,
+                client: client_id,
+                delta: after.held - held_before,
+            });
+        }
+        if after.locked && !locked_before {
+            emit(&EngineEvent::ClientLocked {
+                tx: tx_id,
+                client: client_id,
+            });
+        } else if !after.locked && locked_before {
+            emit(&EngineEvent::ClientUnlocked {
+                tx: tx_id,
+                client: client_id,
+            });
+        }
+    }
+
+    /// applies tx like apply, returning the client it applied to as it is afterwards, so just that client can be
+    /// pushed downstream, None if nothing about the client changed, ie an identical duplicate accepted by
+    /// DuplicateTxPolicy::AcceptIfIdentical or a row skipped by with_client_filter
+    /// for a transfer that's the source client, the destination changed too
+    pub fn apply_with_delta(
+        &mut self,
+        tx: TransactionRow,
+    ) -> Result<Option<ClientDelta>, ApplyError> {
+        let (_, client_id) = self.affected_ids(&tx);
+        if !self.accepts(&tx) {
+            self.apply(tx)?;
+            return Ok(None);
+        }
+        let before = self
+            .clients
+            .get(&client_id)
+            .map(|c| (c.total, c.held, c.locked));
+        self.apply(tx)?;
+        let after = self.clients.get(&client_id).unwrap(); // safe, the client always exists after a successful apply
+        if before == Some((after.total, after.held, after.locked)) {
+            return Ok(None);
+        }
+        Ok(Some(ClientDelta {
+            client: client_id,
+            total: after.total,
+            held: after.held,
+            locked: after.locked,
+        }))
+    }
+
+    // whether client_filter accepts every client tx would apply to
+    pub(crate) fn accepts(&self, tx: &TransactionRow) -> bool {
+        let (_, client) = self.affected_ids(tx);
+        match tx {
+            TransactionRow::Transfer(tx) => {
+                self.client_filter.accepts(client) && self.client_filter.accepts(tx.to_client)
+            }
+            _ => self.client_filter.accepts(client),
+        }
+    }
+
+    // the tx id and the client it will actually be applied to, which for a mod is the original transaction's client
+    pub(crate) fn affected_ids(&self, tx: &TransactionRow) -> (u32, u16) {
+        match tx {
+            TransactionRow::New(tx) => (tx.tx, tx.client),
+            TransactionRow::Mod(tx) => match self.transactions.get(&tx.tx) {
+                Some(orig_tx) => (tx.tx, orig_tx.client),
+                // an orphan without a client is rejected either way
+                None => (tx.tx, tx.client.unwrap_or_default()),
+            },
+            TransactionRow::Unlock(tx) => (tx.tx, tx.client),
+            TransactionRow::Transfer(tx) => (tx.tx, tx.client),
+        }
+    }
+
+    // decides what tx changes, runs the invariant checks on the result if configured, then makes the change,
+    // false if there was nothing to change, ie an identical redelivery
+    fn apply_checked(&mut self, tx: TransactionRow) -> Result<bool, ApplyError> {
+        let effect = match self.evaluate(&tx) {
+            Ok(effect) => effect,
+            Err(reason) => {
+                self.record_rejection(&tx, &reason);
+                return Err(reason);
+            }
+        };
+        if let TxChange::Redelivered = effect.tx {
+            return Ok(false);
+        }
+        if self.invariant_check != InvariantCheck::Off {
+            let checked = match &effect.tx {
+                TxChange::Transfer(_, to, _) => {
+                    let from = &effect.client;
+                    client_invariants(from.client, from.total, from.held)
+                        .and_then(|_| client_invariants(to.client, to.total, to.held))
+                }
+                _ => client_invariants(
+                    effect.client.client,
+                    effect.client.total,
+                    effect.client.held,
+                ),
+            };
+            if let Err(violation) = checked {
+                if self.invariant_check == InvariantCheck::Reject {
+                    // nothing has been changed yet, so there's nothing to roll back
+                    self.last_invariant_violation = Some(violation.clone());
+                    return Err(ApplyError::InvariantViolation(violation));
+                }
+                if cfg!(debug_assertions) {
+                    panic!("client invariant violated: {:?}", violation);
+                }
+            }
+        }
+        self.commit(effect);
+        Ok(true)
+    }
+
+    // whether client's held is above held_ratio_alert of their total, false if either doesn't exist
+    fn over_held_ratio(&self, client: u16) -> bool {
+        match (self.held_ratio_alert, self.clients.get(&client)) {
+            // if total * ratio overflows, held can't possibly be above it
+            (Some(ratio), Some(c)) => c.total.checked_mul(ratio).is_some_and(|max| c.held > max),
+            _ => false,
+        }
+    }
+
+    /// the violation that caused the most recent rejection in InvariantCheck::Reject mode
+    pub fn last_invariant_violation(&self) -> Option<&InvariantViolation> {
+        self.last_invariant_violation.as_ref()
+    }
+
+    /// verifies available == total - held is representable, and held and total are not negative
+    /// a client that doesn't exist trivially passes
+    pub fn check_client_invariants(&self, client: u16) -> Result<(), InvariantViolation> {
+        match self.clients.get(&client) {
+            None => Ok(()),
+            Some(c) => client_invariants(c.client, c.total, c.held),
+        }
+    }
+
+    /// recomputes every client's total and held from the stored transactions, plus opening balances from
+    /// seed_clients, and verifies they match, then verifies each client as check_client_invariants does
+    /// this walks every transaction, so it's meant for tests and occasional sanity checks, not every apply
+    ///
+    /// what adds up depends on WithdrawalDisputePolicy, which must not have changed since the transactions applied:
+    /// - total is the opening balance plus every transaction's amount less withdrawal fees, except charged back
+    ///   ones, with HoldReversal
+    ///   a disputed or charged back withdrawal nets to 0 instead, since disputing it credits it back
+    /// - held is the sum of disputed amounts, so with HoldNegative a disputed withdrawal lowers it and it can go
+    ///   negative, which is reported as NegativeHeld, with HoldReversal it's raised by the withdrawn amount instead
+    /// - held is never compared to total, even when only deposits are disputed, a deposit that has already been
+    ///   withdrawn can still be disputed, holding more than the client has
+    ///
+    /// with with_recent_transactions totals aren't compared at all
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        // amounts are summed as i128 mantissas at the engine's places, transactions come out of the map in no
+        // particular order, so a running Decimal sum can overflow part way even though the final total fits
+        let places = self.decimal_places();
+        let mantissa = |mut amount: Decimal| {
+            amount.rescale(places);
+            // None if it doesn't fit at places, which the engine never stores
+            Some(amount.mantissa()).filter(|_| amount.scale() == places)
+        };
+        // (total, held) per client
+        let mut expected: HashMap<u16, (i128, i128)> = HashMap::new();
+        for (client, total) in &self.opening_balances {
+            let total = mantissa(*total).ok_or(InvariantViolation::TotalMismatch(*client))?;
+            expected.insert(*client, (total, 0));
+        }
+        for (client, fees) in &self.fees {
+            let fees = mantissa(*fees).ok_or(InvariantViolation::TotalMismatch(*client))?;
+            let (total, _) = expected.entry(*client).or_default();
+            *total -= fees;
+        }
+        for (client, transferred) in &self.transfers {
+            let transferred =
+                mantissa(*transferred).ok_or(InvariantViolation::TotalMismatch(*client))?;
+            let (total, _) = expected.entry(*client).or_default();
+            *total += transferred;
+        }
+        let hold_reversal = self.withdrawal_dispute_policy == WithdrawalDisputePolicy::HoldReversal;
+        for tx in self.transactions.values() {
+            if self.clients.get(&tx.client).is_none() {
+                return Err(InvariantViolation::OrphanTransaction(tx.tx));
+            }
+            let amount = mantissa(tx.amount).ok_or(InvariantViolation::TotalMismatch(tx.client))?;
+            // the part disputed, all of it unless it was disputed with an amount
+            let part =
+                mantissa(tx.held_amount).ok_or(InvariantViolation::HeldMismatch(tx.client))?;
+            let (total, held) = match (
+                tx.state,
+                hold_reversal && tx.kind == TransactionKind::Withdrawal,
+            ) {
+                (Resolved, _) => (amount, 0),
+                (Disputed, false) => (amount, part),
+                (Disputed, true) => (0, -amount),
+                (Chargeback, _) => (amount - part, 0),
+            };
+            let (expected_total, expected_held) = expected.entry(tx.client).or_default();
+            // 96 bit mantissas, so this would take billions of transactions
+            *expected_total = expected_total
+                .checked_add(total)
+                .ok_or(InvariantViolation::TotalMismatch(tx.client))?;
+            *expected_held = expected_held
+                .checked_add(held)
+                .ok_or(InvariantViolation::HeldMismatch(tx.client))?;
+        }
+        for c in self.clients_in_order() {
+            let (total, held) = expected.get(&c.client).copied().unwrap_or_default();
+            // evicted transactions are missing from the sum, disputed ones are never evicted so held still adds up
+            if !self.transactions.is_bounded() && mantissa(c.total) != Some(total) {
+                return Err(InvariantViolation::TotalMismatch(c.client));
+            }
+            if mantissa(c.held) != Some(held) {
+                return Err(InvariantViolation::HeldMismatch(c.client));
+            }
+            client_invariants(c.client, c.total, c.held)?;
+        }
+        Ok(())
+    }
+
+    // decides whether tx applies and exactly what it would change, without changing anything
+    fn evaluate(&self, tx: &TransactionRow) -> Result<Effect, ApplyError> {
+        match tx {
+            TransactionRow::New(tx) => {
+                let places = self.decimal_places();
+                // trailing zeros don't count, so amounts read at more places than this still work if they fit
+                if tx.amount.normalize().scale() > places {
+                    return Err(ApplyError::TooManyDecimalPlaces);
+                }
+                let mut tx = tx.clone();
+                tx.amount.rescale(places);
+                if tx.amount.scale() != places {
+                    // too big to have that many places, only possible if the reader used fewer
+                    return Err(ApplyError::Overflow);
+                }
+                if self.transfer_ids.contains_key(&tx.tx) {
+                    // a transfer can't be updated or compared with a deposit/withdrawal
+                    return Err(ApplyError::DuplicateTx);
+                }
+                if self.transactions.contains_key(&tx.tx) {
+                    // the transaction already exists, again in production this would be an error to log or something
+                    // record_rejection handles what DuplicateTxPolicy::Reject and Error do beyond rejecting it
+                    return match self.duplicate_tx_policy {
+                        DuplicateTxPolicy::Update => self.evaluate_update(tx),
+                        DuplicateTxPolicy::AcceptIfIdentical => {
+                            let orig_tx = self.transactions.get(&tx.tx).unwrap();
+                            // state isn't compared, a redelivered deposit that's been disputed since is still the same deposit
+                            if orig_tx.client == tx.client
+                                && orig_tx.kind == tx.kind
+                                && orig_tx.amount == tx.amount
+                            {
+                                Ok(Effect {
+                                    client: ClientChange::of(self.clients.get(&tx.client).unwrap()),
+                                    tx: TxChange::Redelivered,
+                                })
+                            } else {
+                                Err(ApplyError::DuplicateTx)
+                            }
+                        }
+                        _ => Err(ApplyError::DuplicateTx),
+                    };
+                }
+                // new transaction, but it can still be invalid if it's withdrawal for a client that does not exist or does not have enough available funds
+                // now insert or update the client, along with the fee charged, if any
+                let mut requested = None; // set if the withdrawal is clamped
+                let (client, fee) = match self.clients.get(&tx.client) {
+                    None => {
+                        // client does not exist
+                        if tx.kind == TransactionKind::Withdrawal {
+                            // withdrawals for a new client are not allowed
+                            return Err(ApplyError::UnknownClient);
+                        }
+                        if self.max_tx_per_client == Some(0) {
+                            return Err(ApplyError::ClientTxCapExceeded);
+                        }
+                        let mut client = ClientChange::new(tx.client, tx.amount);
+                        client.tx_count = 1;
+                        client.last_tx = Some(tx.tx);
+                        (client, Decimal::ZERO)
+                    }
+                    Some(client) => {
+                        if let Some(max_tx_per_client) = self.max_tx_per_client {
+                            if client.tx_count >= max_tx_per_client as u64 {
+                                // client is over their cap
+                                return Err(ApplyError::ClientTxCapExceeded);
+                            }
+                        }
+                        if client.locked
+                            && (tx.kind == TransactionKind::Withdrawal
+                                || self.lock_policy == LockPolicy::AllActivity)
+                        {
+                            // withdrawals are not allowed for locked accounts, deposits depend on lock_policy
+                            return Err(ApplyError::AccountLocked);
+                        }
+                        let mut fee = match tx.kind {
+                            TransactionKind::Withdrawal => {
+                                self.withdrawal_fee(tx.amount).ok_or(ApplyError::Overflow)?
+                            }
+                            TransactionKind::Deposit => Decimal::ZERO,
+                        };
+                        // the withdrawn amount plus the fee, or just the deposited amount
+                        let mut change = sub_exact(tx.amount, fee).ok_or(ApplyError::Overflow)?;
+                        match client.available().checked_add(change) {
+                            None => return Err(ApplyError::Overflow),
+                            Some(available)
+                                if available.is_sign_negative()
+                                    && tx.kind == TransactionKind::Withdrawal
+                                    && self.overdraft_policy
+                                        == OverdraftPolicy::ClampToAvailable =>
+                            {
+                                // as much as there is instead
+                                let (amount, clamped_fee) = self
+                                    .clamped_withdrawal(client.available())
+                                    .ok_or(ApplyError::InsufficientFunds)?;
+                                requested = Some(tx.amount);
+                                tx.amount = amount;
+                                fee = clamped_fee;
+                                change = sub_exact(tx.amount, fee).ok_or(ApplyError::Overflow)?;
+                            }
+                            // withdrawals that will put the available balance into negative are not allowed
+                            Some(available) if available.is_sign_negative() => {
+                                return Err(ApplyError::InsufficientFunds)
+                            }
+                            Some(_) => {}
+                        }
+                        let mut client = ClientChange::of(client);
+                        match add_exact(client.total, change) {
+                            None => return Err(ApplyError::Overflow), // fail transactions that overflow
+                            Some(new_total) => {
+                                if new_total.is_sign_negative() {
+                                    // withdrawals that will put the total balance into negative are not allowed
+                                    // this could happen because a withdrawal is disputed
+                                    return Err(ApplyError::InsufficientFunds);
+                                }
+                                client.total = new_total;
+                            }
+                        }
+                        client.tx_count += 1;
+                        client.last_tx = Some(tx.tx);
+                        (client, fee)
+                    }
+                };
+                Ok(Effect {
+                    client,
+                    tx: TxChange::Insert(tx, fee, requested),
+                })
+            }
+            TransactionRow::Mod(tx) => {
+                let orig_tx = match self.transactions.get(&tx.tx) {
+                    // can't mod a non-existing transactions, record_rejection records it since it points at an upstream data problem
+                    None => return Err(ApplyError::ModTargetNotFound),
+                    Some(orig_tx) => orig_tx,
+                };
+                // a mod without a client is always for the original transaction's client
+                if tx.client.is_some_and(|client| client != orig_tx.client) {
+                    match self.client_mismatch_policy {
+                        // an update for an existing transaction but with a different client? hacker! do not apply transaction
+                        ClientMismatchPolicy::Reject | ClientMismatchPolicy::RecordAndReject => {
+                            return Err(ApplyError::ClientMismatch)
+                        }
+                        // the original client is used from here on
+                        ClientMismatchPolicy::UseOriginalClient => {}
+                    }
+                }
+                if orig_tx.state == Chargeback {
+                    // chargeback is final, which is worth telling apart from an ordinary illegal transition
+                    return Err(ApplyError::TxFinalized);
+                }
+                let client = self.clients.get(&orig_tx.client).unwrap(); // this is safe because we never insert a transaction without making sure the client exists first
+                if client.locked
+                    && tx.state == Disputed
+                    && self.lock_policy == LockPolicy::AllActivity
+                {
+                    return Err(ApplyError::AccountLocked);
+                }
+                if self.withdrawal_dispute_policy == WithdrawalDisputePolicy::Reject
+                    && orig_tx.kind == TransactionKind::Withdrawal
+                {
+                    return Err(ApplyError::ModTargetIncompatible);
+                }
+                // how much this holds, releases or charges back, all of it unless it was disputed with an amount
+                let amount = match tx.amount {
+                    Some(_) if orig_tx.kind == TransactionKind::Withdrawal => {
+                        return Err(ApplyError::ModTargetIncompatible)
+                    }
+                    Some(part) if part > orig_tx.amount => return Err(ApplyError::DisputeTooLarge),
+                    Some(part) => part,
+                    None if tx.state == Disputed => orig_tx.amount,
+                    None => orig_tx.held_amount,
+                };
+                let held_amount = match tx.state {
+                    Resolved => Decimal::ZERO,
+                    Disputed | Chargeback => amount,
+                };
+                let mut client = ClientChange::of(client);
+                if tx.state == Disputed {
+                    client.ever_disputed = true;
+                    if orig_tx.state == Resolved
+                        && self
+                            .max_open_disputes
+                            .is_some_and(|max| client.open_disputes >= max)
+                    {
+                        return Err(ApplyError::OpenDisputeCapExceeded);
+                    }
+                    // an illegal transition is rejected below, taking this with it
+                    client.open_disputes += 1;
+                } else {
+                    client.open_disputes = client.open_disputes.saturating_sub(1);
+                }
+                if self.withdrawal_dispute_policy == WithdrawalDisputePolicy::HoldReversal
+                    && orig_tx.kind == TransactionKind::Withdrawal
+                {
+                    let reject_chargeback = self.negative_held_policy
+                        == NegativeHeldPolicy::RejectWithdrawalChargebacks;
+                    mod_withdrawal_reversal(&mut client, orig_tx, tx.state, reject_chargeback)?;
+                    return Ok(Effect {
+                        client,
+                        tx: TxChange::SetState(tx.tx, tx.state, held_amount),
+                    });
+                }
+                match tx.state {
+                    Disputed => {
+                        if orig_tx.state != Resolved {
+                            // can only switch to Disputed from Resolved, otherwise this is invalid
+                            return Err(ApplyError::IllegalStateTransition);
+                        }
+                        match add_exact(client.held, amount) {
+                            None => return Err(ApplyError::Overflow), // fail on overflow
+                            Some(held) => client.held = held,
+                        }
+                        if self.dispute_policy == DisputePolicy::RejectIfNegativeAvailable
+                            && client.available().is_sign_negative()
+                            && !client.available().is_zero()
+                        {
+                            return Err(ApplyError::InsufficientFunds);
+                        }
+                    }
+                    Resolved => {
+                        if orig_tx.state != Disputed {
+                            // can only switch to Resolved from Disputed, otherwise this is invalid
+                            return Err(ApplyError::IllegalStateTransition);
+                        }
+                        match sub_exact(client.held, amount) {
+                            None => return Err(ApplyError::Overflow), // fail on overflow
+                            Some(held) => {
+                                if self.negative_held_policy
+                                    == NegativeHeldPolicy::RejectNegativeHeld
+                                    && held.is_sign_negative()
+                                    && !held.is_zero()
+                                {
+                                    return Err(ApplyError::NegativeHeld);
+                                }
+                                client.held = held
+                            }
+                        }
+                    }
+                    Chargeback => {
+                        if orig_tx.state != Disputed {
+                            // can only switch to Chargeback from Disputed, otherwise this is invalid
+                            return Err(ApplyError::IllegalStateTransition);
+                        }
+                        if self.negative_held_policy
+                            == NegativeHeldPolicy::RejectWithdrawalChargebacks
+                            && orig_tx.kind == TransactionKind::Withdrawal
+                        {
+                            return Err(ApplyError::WithdrawalChargeback);
+                        }
+                        match (
+                            sub_exact(client.held, amount),
+                            sub_exact(client.total, amount),
+                        ) {
+                            (Some(held), Some(_))
+                                if self.negative_held_policy
+                                    == NegativeHeldPolicy::RejectNegativeHeld
+                                    && held.is_sign_negative()
+                                    && !held.is_zero() =>
+                            {
+                                return Err(ApplyError::NegativeHeld)
+                            }
+                            (Some(held), Some(total)) => {
+                                client.held = held;
+                                client.total = total;
+                            }
+                            (_, _) => return Err(ApplyError::Overflow), // fail on overflow of either
+                        }
+                        client.lock = LockChange::Lock(LockReason::Chargeback);
+                    }
+                }
+                Ok(Effect {
+                    client,
+                    tx: TxChange::SetState(tx.tx, tx.state, held_amount),
+                })
+            }
+            TransactionRow::Unlock(tx) => match self.clients.get(&tx.client) {
+                None => Err(ApplyError::UnknownClient),
+                Some(client) => {
+                    let mut client = ClientChange::of(client);
+                    // manual intervention overrides every reason, not just the chargeback
+                    client.lock = LockChange::UnlockAll;
+                    Ok(Effect {
+                        client,
+                        tx: TxChange::None,
+                    })
+                }
+            },
+            TransactionRow::Transfer(tx) => {
+                // from and to would be two copies of the same client, and the second one saved would win
+                if tx.to_client == tx.client {
+                    return Err(ApplyError::SelfTransfer);
+                }
+                let places = self.decimal_places();
+                if tx.amount.normalize().scale() > places {
+                    return Err(ApplyError::TooManyDecimalPlaces);
+                }
+                let mut amount = tx.amount;
+                amount.rescale(places);
+                if amount.scale() != places {
+                    return Err(ApplyError::Overflow);
+                }
+                if self.transactions.contains_key(&tx.tx) {
+                    return Err(ApplyError::DuplicateTx);
+                }
+                if let Some(orig_tx) = self.transfer_ids.get(&tx.tx) {
+                    // record_rejection handles DuplicateTxPolicy::Reject and Error, and Update can't update a transfer
+                    return match self.clients.get(&tx.client) {
+                        Some(client)
+                            if self.duplicate_tx_policy == DuplicateTxPolicy::AcceptIfIdentical
+                                && *orig_tx == (tx.client, tx.to_client, amount) =>
+                        {
+                            Ok(Effect {
+                                client: ClientChange::of(client),
+                                tx: TxChange::Redelivered,
+                            })
+                        }
+                        _ => Err(ApplyError::DuplicateTx),
+                    };
+                }
+                let mut from = match self.clients.get(&tx.client) {
+                    None => return Err(ApplyError::UnknownClient),
+                    Some(client) if client.locked => return Err(ApplyError::AccountLocked),
+                    Some(client) => ClientChange::of(client),
+                };
+                // a new destination is created, like a deposit would
+                let mut to = match self.clients.get(&tx.to_client) {
+                    None => ClientChange::new(tx.to_client, Decimal::new(0, places)),
+                    Some(client) if client.locked => return Err(ApplyError::AccountLocked),
+                    Some(client) => ClientChange::of(client),
+                };
+                match sub_exact(from.available(), amount) {
+                    None => return Err(ApplyError::Overflow),
+                    // only what's available can be moved, held funds stay put
+                    Some(available) if available.is_sign_negative() => {
+                        return Err(ApplyError::InsufficientFunds)
+                    }
+                    Some(_) => {}
+                }
+                from.total = sub_exact(from.total, amount).ok_or(ApplyError::Overflow)?;
+                to.total = add_exact(to.total, amount).ok_or(ApplyError::Overflow)?;
+                Ok(Effect {
+                    client: from,
+                    tx: TxChange::Transfer(tx.tx, to, amount),
+                })
+            }
+        }
+    }
+
+    // makes the change evaluate decided on
+    fn commit(&mut self, effect: Effect) {
+        let client = effect.client;
+        match effect.tx {
+            TxChange::Insert(tx, fee, requested) => {
+                if let Some(requested) = requested {
+                    self.clamped_withdrawals.push((tx.tx, requested));
+                }
+                if !fee.is_zero() {
+                    let fees = self.fees.entry(tx.client).or_default();
+                    // can only fail after fees beyond Decimal::MAX, but then check_invariants reports it
+                    *fees = add_exact(*fees, fee).unwrap_or(Decimal::MAX);
+                }
+                if self.duplicate_tx_policy == DuplicateTxPolicy::Update {
+                    let updatable = self.updatable_deposits.entry(tx.client).or_default();
+                    if tx.kind == TransactionKind::Withdrawal {
+                        // a withdrawal settles every deposit before it
+                        updatable.clear();
+                    } else {
+                        updatable.insert(tx.tx);
+                    }
+                }
+                self.transactions.insert(tx.tx, tx);
+            }
+            TxChange::SetState(tx, state, held_amount) => {
+                if state == Disputed {
+                    if let Some(updatable) = self.updatable_deposits.get_mut(&client.client) {
+                        // a disputed deposit can never be updated again
+                        updatable.remove(&tx);
+                    }
+                }
+                self.transactions.set_state(tx, state, held_amount);
+            }
+            TxChange::SetAmount(tx, amount) => {
+                self.transactions.set_amount(tx, amount);
+            }
+            TxChange::Transfer(tx, to_client, amount) => {
+                self.transfer_ids
+                    .insert(tx, (client.client, to_client.client, amount));
+                // can only fail after transfers beyond Decimal::MAX, but then check_invariants reports it
+                let out = self.transfers.entry(client.client).or_default();
+                *out = sub_exact(*out, amount).unwrap_or(Decimal::MIN);
+                let to = self.transfers.entry(to_client.client).or_default();
+                *to = add_exact(*to, amount).unwrap_or(Decimal::MAX);
+                self.commit_client(to_client);
+            }
+            TxChange::None | TxChange::Redelivered => {}
+        }
+        self.commit_client(client);
+    }
+
+    // makes the change to the client, creating it first if it doesn't exist yet
+    fn commit_client(&mut self, change: ClientChange) {
+        match self.clients.get_mut(&change.client) {
+            Some(client) => change.apply_to(client),
+            None => {
+                let mut client = Client::new(change.client, change.total);
+                change.apply_to(&mut client);
+                self.client_order.push(change.client);
+                self.clients.insert(change.client, client);
+            }
+        }
+    }
+
+    // records whatever the configured policies track about a rejected tx
+    fn record_rejection(&mut self, tx: &TransactionRow, reason: &ApplyError) {
+        match (tx, reason) {
+            (TransactionRow::New(_) | TransactionRow::Transfer(_), ApplyError::DuplicateTx) => {
+                match self.duplicate_tx_policy {
+                    DuplicateTxPolicy::Reject => self.rejected_duplicates.push(tx.tx()),
+                    DuplicateTxPolicy::Error => self.halted = true,
+                    DuplicateTxPolicy::Ignore
+                    | DuplicateTxPolicy::Update
+                    | DuplicateTxPolicy::AcceptIfIdentical => {}
+                }
+            }
+            (TransactionRow::Mod(tx), ApplyError::ModTargetNotFound) => {
+                self.orphan_mods.push((tx.client, tx.tx))
+            }
+            (TransactionRow::Mod(tx), ApplyError::ClientMismatch)
+                if self.client_mismatch_policy == ClientMismatchPolicy::RecordAndReject =>
+            {
+                self.client_mismatches
+                    .extend(tx.client.map(|client| (tx.tx, client)))
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<T: TransactionStore + Clone, C: ClientStore + Clone> TransactionEngine<T, C> {
+    /// runs rows through exactly the checks apply would, in order, returning whether each would have applied
+    /// rows see the effects of earlier accepted rows, but only on a copy of the engine's state, nothing here changes
+    /// transactions aren't copied, rows are applied over the engine's, except with with_recent_transactions, where
+    /// which ones are evicted depends on the store, and the copy is bounded by its capacity anyway
+    pub fn validate(&self, rows: impl Iterator<Item = TransactionRow>) -> ValidationReport {
+        if self.transactions.is_bounded() {
+            let mut scratch = self.scratch(self.transactions.clone());
+            return ValidationReport {
+                outcomes: rows.map(|row| scratch.apply(row)).collect(),
+            };
+        }
+        let mut scratch = self.scratch(TransactionOverlay::new(&self.transactions));
+        ValidationReport {
+            outcomes: rows.map(|row| scratch.apply(row)).collect(),
+        }
+    }
+
+    // an engine with transactions and a copy of everything else apply reads or changes
+    fn scratch<S: TransactionStore>(&self, transactions: S) -> TransactionEngine<S, C> {
+        TransactionEngine {
+            clients: self.clients.clone(),
+            client_order: self.client_order.clone(),
+            updatable_deposits: self.updatable_deposits.clone(),
+            fees: self.fees.clone(),
+            transfers: self.transfers.clone(),
+            transfer_ids: self.transfer_ids.clone(),
+            halted: self.halted,
+            mod_dedup: self.mod_dedup.clone(),
+            ..self.configured_like(transactions, self.clients.empty_like())
+        }
+    }
+}
+
+impl<T: TransactionStore + Send, C: ClientStore + Send> TransactionEngine<T, C> {
+    /// applies rows with the same result as calling apply on each in order, but spread across `threads` worker
+    /// engines, returning how many applied
+    ///
+    /// a client's transactions only ever touch that client, so rows are sharded by client id, each worker owns its
+    /// clients and their transactions outright and nothing is shared between workers, the only global state is tx
+    /// id uniqueness and which client a dispute/resolve/chargeback belongs to, so this thread routes every row and
+    /// keeps a tx id -> client index for both, mods go to the shard of the client their tx was first seen for
+    ///
+    /// the result doesn't depend on `threads`, but two kinds of rows can't be routed to a single worker, a transfer
+    /// between clients in different workers, and a tx id reused for a client in a different worker than its first
+    /// use, which is a duplicate only if that first use was accepted, so at the first of either the workers are
+    /// merged and the rest of rows is applied serially
+    ///
+    /// with audit, mod dedup, with_recent_transactions, DuplicateTxPolicy::Update or Error, or threads <= 1, this
+    /// just applies serially, since each of those needs a global order
+    pub fn apply_parallel(
+        &mut self,
+        rows: impl Iterator<Item = TransactionRow>,
+        threads: usize,
+    ) -> usize {
+        if threads <= 1
+            || self.audit.is_some()
+            || self.mod_dedup.is_some()
+            || self.transactions.is_bounded()
+            || matches!(
+                self.duplicate_tx_policy,
+                DuplicateTxPolicy::Update | DuplicateTxPolicy::Error
+            )
+        {
+            return self.apply_all(rows);
+        }
+        // rows are sent to workers in batches, a channel send per row costs more than applying it
+        const BATCH: usize = 1024;
+        let shard_of = |client: u16| client as usize % threads;
+
+        // hand the existing state to the workers
+        let mut tx_index: HashMap<u32, u16> = self
+            .transactions
+            .values()
+            .map(|tx| (tx.tx, tx.client))
+            .chain(
+                self.transfer_ids
+                    .iter()
+                    .map(|(tx, (client, _, _))| (*tx, *client)),
+            )
+            .collect();
+        let mut shards: Vec<Self> = (0..threads).map(|_| self.empty_like()).collect();
+        for client in self.clients.take_all() {
+            let shard = &mut shards[shard_of(client.client)];
+            shard.client_order.push(client.client);
+            shard.clients.insert(client.client, client);
+        }
+        for tx in self.transactions.take_all() {
+            shards[shard_of(tx.client)].transactions.insert(tx.tx, tx);
+        }
+        for (client, fees) in self.fees.drain() {
+            shards[shard_of(client)].fees.insert(client, fees);
+        }
+        for (client, transferred) in self.transfers.drain() {
+            shards[shard_of(client)]
+                .transfers
+                .insert(client, transferred);
+        }
+        for (tx, transfer) in self.transfer_ids.drain() {
+            shards[shard_of(transfer.0)]
+                .transfer_ids
+                .insert(tx, transfer);
+        }
+
+        let mut rows = rows.enumerate();
+        let (results, serial_from) = std::thread::scope(|scope| {
+            let mut senders = Vec::with_capacity(threads);
+            let mut workers = Vec::with_capacity(threads);
+            for mut shard in shards {
+                let (sender, receiver) = sync_channel::<Vec<(usize, TransactionRow)>>(4);
+                senders.push(sender);
+                workers.push(scope.spawn(move || {
+                    // (row number, client) of every client created here, to restore client_order
+                    let mut created = Vec::new();
+                    let mut diagnostics = ShardDiagnostics::default();
+                    let mut applied = 0;
+                    for batch in receiver {
+                        for (seq, row) in batch {
+                            let before = shard.client_order.len();
+                            if shard.apply_row(row).is_ok_and(Outcome::counts) {
+                                applied += 1;
+                            }
+                            if shard.client_order.len() > before {
+                                created.push((seq, shard.client_order[before]));
+                            }
+                            diagnostics.take(seq, &mut shard);
+                        }
+                    }
+                    (shard, created, diagnostics, applied)
+                }));
+            }
+
+            let mut batches: Vec<Vec<(usize, TransactionRow)>> =
+                (0..threads).map(|_| Vec::with_capacity(BATCH)).collect();
+            let mut serial_from = None;
+            for (seq, row) in rows.by_ref() {
+                let client = match &row {
+                    // an orphan without a client can go to any worker, it's rejected either way
+                    TransactionRow::Mod(tx) => tx_index
+                        .get(&tx.tx)
+                        .copied()
+                        .or(tx.client)
+                        .unwrap_or_default(),
+                    TransactionRow::Unlock(tx) => tx.client,
+                    // transfers share tx ids with deposits/withdrawals, and live with their source client
+                    TransactionRow::New(_) | TransactionRow::Transfer(_) => {
+                        let (tx_id, client) = self.affected_ids(&row);
+                        let first = *tx_index.entry(tx_id).or_insert(client);
+                        let crosses = matches!(&row, TransactionRow::Transfer(tx)
+                            if shard_of(tx.to_client) != shard_of(client));
+                        if crosses || shard_of(first) != shard_of(client) {
+                            // neither worker could apply this on its own
+                            serial_from = Some(row);
+                            break;
+                        }
+                        client
+                    }
+                };
+                let shard = shard_of(client);
+                batches[shard].push((seq, row));
+                if batches[shard].len() == BATCH {
+                    let batch = std::mem::replace(&mut batches[shard], Vec::with_capacity(BATCH));
+                    senders[shard].send(batch).expect("worker panicked");
+                }
+            }
+            for (sender, batch) in senders.into_iter().zip(batches) {
+                sender.send(batch).expect("worker panicked");
+                // dropping the sender ends the worker's loop
+            }
+            let results = workers
+                .into_iter()
+                .map(|worker| worker.join().expect("worker panicked"))
+                .collect::<Vec<_>>();
+            (results, serial_from)
+        });
+
+        let mut applied = 0;
+        let mut created = Vec::new();
+        let mut diagnostics = ShardDiagnostics::default();
+        for (mut shard, shard_created, shard_diagnostics, shard_applied) in results {
+            applied += shard_applied;
+            created.extend(shard_created);
+            diagnostics.extend(shard_diagnostics);
+            for client in shard.clients.take_all() {
+                self.clients.insert(client.client, client);
+            }
+            for tx in shard.transactions.take_all() {
+                self.transactions.insert(tx.tx, tx);
+            }
+            self.fees.extend(shard.fees.drain());
+            self.transfers.extend(shard.transfers.drain());
+            self.transfer_ids.extend(shard.transfer_ids.drain());
+            self.stats.merge(&shard.stats);
+        }
+        // existing clients are already in client_order
+        created.sort_unstable();
+        self.client_order
+            .extend(created.into_iter().map(|(_, client)| client));
+        diagnostics.restore(self);
+        if let Some(row) = serial_from {
+            applied += self.apply_all(std::iter::once(row).chain(rows.map(|(_, row)| row)));
+        }
+        applied
+    }
+}
+
+// what apply_parallel's workers recorded, by row number, so it can be put back in input order
+#[derive(Default)]
+struct ShardDiagnostics {
+    rejected_duplicates: Vec<(usize, u32)>,
+    orphan_mods: Vec<(usize, (Option<u16>, u32))>,
+    clamped_withdrawals: Vec<(usize, (u32, Decimal))>,
+    client_mismatches: Vec<(usize, (u32, u16))>,
+    held_ratio_alerts: Vec<(usize, (u16, u32))>,
+    last_invariant_violation: Option<(usize, InvariantViolation)>,
+}
+
+impl ShardDiagnostics {
+    // moves whatever applying row seq recorded in shard into self
+    fn take<T: TransactionStore, C: ClientStore>(
+        &mut self,
+        seq: usize,
+        shard: &mut TransactionEngine<T, C>,
+    ) {
+        fn tag<D>(seq: usize, from: &mut Vec<D>, to: &mut Vec<(usize, D)>) {
+            to.extend(from.drain(..).map(|d| (seq, d)));
+        }
+        tag(
+            seq,
+            &mut shard.rejected_duplicates,
+            &mut self.rejected_duplicates,
+        );
+        tag(seq, &mut shard.orphan_mods, &mut self.orphan_mods);
+        tag(
+            seq,
+            &mut shard.clamped_withdrawals,
+            &mut self.clamped_withdrawals,
+        );
+        tag(
+            seq,
+            &mut shard.client_mismatches,
+            &mut self.client_mismatches,
+        );
+        tag(
+            seq,
+            &mut shard.held_ratio_alerts,
+            &mut self.held_ratio_alerts,
+        );
+        if let Some(violation) = shard.last_invariant_violation.take() {
+            self.last_invariant_violation = Some((seq, violation));
+        }
+    }
+
+    fn extend(&mut self, other: ShardDiagnostics) {
+        self.rejected_duplicates.extend(other.rejected_duplicates);
+        self.orphan_mods.extend(other.orphan_mods);
+        self.clamped_withdrawals.extend(other.clamped_withdrawals);
+        self.client_mismatches.extend(other.client_mismatches);
+        self.held_ratio_alerts.extend(other.held_ratio_alerts);
+        self.last_invariant_violation = self
+            .last_invariant_violation
+            .take()
+            .into_iter()
+            .chain(other.last_invariant_violation)
+            .max_by_key(|(seq, _)| *seq);
+    }
+
+    // appends everything to engine in input order
+    fn restore<T: TransactionStore, C: ClientStore>(self, engine: &mut TransactionEngine<T, C>) {
+        fn untag<D>(mut from: Vec<(usize, D)>, to: &mut Vec<D>) {
+            from.sort_by_key(|(seq, _)| *seq);
+            to.extend(from.into_iter().map(|(_, d)| d));
+        }
+        untag(self.rejected_duplicates, &mut engine.rejected_duplicates);
+        untag(self.orphan_mods, &mut engine.orphan_mods);
+        untag(self.clamped_withdrawals, &mut engine.clamped_withdrawals);
+        untag(self.client_mismatches, &mut engine.client_mismatches);
+        untag(self.held_ratio_alerts, &mut engine.held_ratio_alerts);
+        if let Some((_, violation)) = self.last_invariant_violation {
+            engine.last_invariant_violation = Some(violation);
+        }
+    }
+}
+
+impl<T: TransactionStore, C: ClientStore> TransactionEngine<T, C> {
+    // a new engine with the same configuration, for apply_parallel's workers and MultiAssetEngine's assets
+    pub(crate) fn empty_like(&self) -> Self {
+        self.configured_like(self.transactions.empty_like(), self.clients.empty_like())
+    }
+
+    // a new engine over the given stores with the same configuration
+    fn configured_like<S: TransactionStore, D: ClientStore>(
+        &self,
+        transactions: S,
+        clients: D,
+    ) -> TransactionEngine<S, D> {
+        TransactionEngine {
+            invariant_check: self.invariant_check,
+            duplicate_tx_policy: self.duplicate_tx_policy,
+            max_tx_per_client: self.max_tx_per_client,
+            max_open_disputes: self.max_open_disputes,
+            client_filter: self.client_filter.clone(),
+            client_mismatch_policy: self.client_mismatch_policy,
+            negative_held_policy: self.negative_held_policy,
+            dispute_policy: self.dispute_policy,
+            overdraft_policy: self.overdraft_policy,
+            fee_config: self.fee_config,
+            held_ratio_alert: self.held_ratio_alert,
+            decimal_places: self.decimal_places,
+            available_rounding: self.available_rounding,
+            withdrawal_dispute_policy: self.withdrawal_dispute_policy,
+            lock_policy: self.lock_policy,
+            ..TransactionEngine::with_stores(transactions, clients)
+        }
+    }
+
+    /// locks or unlocks client for reason, returning false if the client doesn't exist
+    /// each reason is tracked separately, the client stays locked until every reason is unlocked, so clearing a
+    /// chargeback lock leaves a manual or regulatory lock in place
+    pub fn set_locked(&mut self, client: u16, locked: bool, reason: LockReason) -> bool {
+        match self.clients.get_mut(&client) {
+            None => false,
+            Some(client) => {
+                if locked {
+                    client.lock(reason);
+                } else {
+                    client.unlock(reason);
+                }
+                true
+            }
+        }
+    }
+
+    // the change replacing the amount of an existing deposit with tx's amount, adjusting the client's total by the difference
+    fn evaluate_update(&self, tx: Transaction) -> Result<Effect, ApplyError> {
+        if tx.kind == TransactionKind::Withdrawal {
+            // can't turn a deposit into a withdrawal
+            return Err(ApplyError::NotUpdatable);
+        }
+        let updatable = self
+            .updatable_deposits
+            .get(&tx.client)
+            .is_some_and(|updatable| updatable.contains(&tx.tx));
+        if !updatable {
+            // this also rejects changing the client, since the tx id won't be in the new client's set
+            return Err(ApplyError::NotUpdatable);
+        }
+        let orig_tx = match self.transactions.get(&tx.tx) {
+            Some(orig_tx) => orig_tx,
+            // evicted by with_recent_transactions
+            None => return Err(ApplyError::NotUpdatable),
+        };
+        if orig_tx.client != tx.client || orig_tx.state != Resolved {
+            return Err(ApplyError::NotUpdatable);
+        }
+        let client = self.clients.get(&tx.client).unwrap(); // safe, the client exists for every stored transaction
+        if client.locked && self.lock_policy == LockPolicy::AllActivity {
+            return Err(ApplyError::AccountLocked);
+        }
+        let total = match sub_exact(tx.amount, orig_tx.amount)
+            .and_then(|delta| add_exact(client.total, delta))
+        {
+            None => return Err(ApplyError::Overflow), // fail on overflow
+            Some(total) => total,
+        };
+        match sub_exact(total, client.held) {
+            Some(available) if !available.is_sign_negative() => {}
+            _ => return Err(ApplyError::InsufficientFunds), // other disputes may be holding funds this would remove
+        }
+        let mut client = ClientChange::of(client);
+        client.total = total;
+        Ok(Effect {
+            client,
+            tx: TxChange::SetAmount(tx.tx, tx.amount),
+        })
+    }
+
+    /// counts of applied and rejected transactions so far, and how many clients are locked right now
+    pub fn stats(&self) -> RunStats {
+        RunStats {
+            locked_accounts: self.clients.values().filter(|c| c.locked).count(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// the highest tx and client ids stored and how many there are, a cheap sanity check of an upstream export
+    /// this walks every stored transaction and client
+    pub fn id_bounds(&self) -> IdBounds {
+        let (max_tx, distinct_txs) = self
+            .transactions
+            .values()
+            .fold((None, 0), |(max, count), tx| {
+                (max.max(Some(tx.tx)), count + 1)
+            });
+        let (max_client, distinct_clients) =
+            self.clients.values().fold((None, 0), |(max, count), c| {
+                (max.max(Some(c.client)), count + 1)
+            });
+        IdBounds {
+            max_tx,
+            max_client,
+            distinct_clients,
+            distinct_txs,
+        }
+    }
+
+    /// tallies every stored transaction's state, ie disputed is how many disputes are open, this walks every
+    /// transaction, and with with_recent_transactions only counts the ones still kept
+    pub fn state_counts(&self) -> StateCounts {
+        let mut counts = StateCounts::default();
+        for tx in self.transactions.values() {
+            match tx.state {
+                Resolved => counts.resolved += 1,
+                Disputed => counts.disputed += 1,
+                Chargeback => counts.chargeback += 1,
+            }
+        }
+        counts
+    }
+
+    /// how much of client's held a disputed transaction accounts for, which a resolve returns to available and a
+    /// chargeback removes, just the disputed part if it was disputed with an amount, None unless tx is disputed
+    pub fn held_amount(&self, tx: u32) -> Option<Decimal> {
+        let tx = self
+            .transactions
+            .get(&tx)
+            .filter(|tx| tx.state == Disputed)?;
+        if self.withdrawal_dispute_policy == WithdrawalDisputePolicy::HoldReversal
+            && tx.kind == TransactionKind::Withdrawal
+        {
+            return Some(-tx.amount);
+        }
+        Some(tx.held_amount)
+    }
+
+    /// every successful change in the order applied, empty unless with_audit was set
+    pub fn audit_log(&self) -> &[AuditEntry] {
+        self.audit.as_deref().unwrap_or_default()
+    }
+
+    /// tx ids of duplicate deposits/withdrawals dropped under DuplicateTxPolicy::Reject, in the order seen
+    pub fn rejected_duplicates(&self) -> &[u32] {
+        &self.rejected_duplicates
+    }
+
+    /// (tx, amount asked for) of every withdrawal OverdraftPolicy::ClampToAvailable clamped, in the order seen, the
+    /// amount is negative like the withdrawal's, what was actually withdrawn is the stored transaction's amount
+    pub fn clamped_withdrawals(&self) -> &[(u32, Decimal)] {
+        &self.clamped_withdrawals
+    }
+
+    /// (client, tx) of every dispute/resolve/chargeback that referenced a transaction that doesn't exist
+    pub fn orphan_mods(&self) -> &[(Option<u16>, u32)] {
+        &self.orphan_mods
+    }
+
+    /// (client, tx) of every dispute that took the client's held above the held_ratio_alert ratio, in the order seen
+    pub fn held_ratio_alerts(&self) -> &[(u16, u32)] {
+        &self.held_ratio_alerts
+    }
+
+    /// (tx, client) of disputes/resolves/chargebacks dropped under ClientMismatchPolicy::RecordAndReject
+    pub fn client_mismatches(&self) -> &[(u32, u16)] {
+        &self.client_mismatches
+    }
+
+    /// true once a duplicate tx was seen under DuplicateTxPolicy::Error, processing should stop
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// the current state of one client, or None if it has never been seen
+    pub fn client(&self, client: u16) -> Option<&Client> {
+        self.clients.get(&client)
+    }
+
+    /// a client's available balance, rounded as with_available_rounding says, or exactly Client::available if not
+    /// None if the client has never been seen
+    pub fn available(&self, client: u16) -> Option<Decimal> {
+        let client = self.clients.get(&client)?;
+        Some(match self.available_rounding {
+            None => client.available(),
+            Some((places, strategy)) => client.available_rounded(places, strategy),
+        })
+    }
+
+    /// every stored deposit/withdrawal with its current state, in no particular order, see dump_transactions_csv
+    pub fn transactions(&self) -> Box<dyn Iterator<Item = &Transaction> + '_> {
+        self.transactions.values()
+    }
+
+    pub fn clients(&self) -> C::Values<'_> {
+        self.clients.values()
+    }
+
+    /// just the locked clients, ie for fraud review, in the same order as clients()
+    pub fn locked_clients(&self) -> impl Iterator<Item = &Client> {
+        self.clients.values().filter(|client| client.locked)
+    }
+
+    /// clients in the order they first appeared, either seeded or by their first deposit
+    pub fn clients_in_order(&self) -> impl Iterator<Item = &Client> {
+        self.client_order
+            .iter()
+            .map(move |client| self.clients.get(client).unwrap())
+    }
+
+    /// sum of held across every client matching pred, ie all locked clients
+    /// returns None if the sum overflows, which can only happen with many clients holding huge balances
+    pub fn sum_held_where(&self, pred: impl Fn(&Client) -> bool) -> Option<Decimal> {
+        self.clients
+            .values()
+            .filter(|client| pred(client))
+            .try_fold(Decimal::new(0, self.decimal_places()), |sum, client| {
+                add_exact(sum, client.held)
+            })
+    }
+
+    /// every withdrawal fee charged so far, see with_fee_config, None if the sum overflows
+    pub fn fees_collected(&self) -> Option<Decimal> {
+        self.fees
+            .values()
+            .try_fold(Decimal::new(0, self.decimal_places()), |sum, fee| {
+                add_exact(sum, *fee)
+            })
+    }
+
+    /// clients with a deposit/withdrawal whose tx id is within [lo, hi], disputes/resolves/chargebacks
+    /// share the tx id of the transaction they modify so they are covered too
+    /// this scans every stored transaction, so it's O(transactions) not O(range)
+    pub fn clients_touched_in_range(&self, lo: u32, hi: u32) -> HashSet<u16> {
+        self.transactions
+            .values()
+            .filter(|tx| tx.tx >= lo && tx.tx <= hi)
+            .map(|tx| tx.client)
+            .collect()
+    }
+}
+
+// Decimal's checked_add and checked_sub don't fail when the exact result needs more than 96 bits, they round off
+// decimal places until it fits, which would quietly lose money, so these treat losing any as overflow too
+fn add_exact(a: Decimal, b: Decimal) -> Option<Decimal> {
+    a.checked_add(b)
+        .filter(|sum| sum.scale() >= a.scale().max(b.scale()))
+}
+
+fn sub_exact(a: Decimal, b: Decimal) -> Option<Decimal> {
+    a.checked_sub(b)
+        .filter(|difference| difference.scale() >= a.scale().max(b.scale()))
+}
+
+// see TransactionEngine::check_client_invariants
+fn client_invariants(client: u16, total: Decimal, held: Decimal) -> Result<(), InvariantViolation> {
+    if sub_exact(total, held).is_none() {
+        return Err(InvariantViolation::AvailableOverflow(client));
+    }
+    if held.is_sign_negative() && !held.is_zero() {
+        return Err(InvariantViolation::NegativeHeld(client));
+    }
+    if total.is_sign_negative() && !total.is_zero() {
+        return Err(InvariantViolation::NegativeTotal(client));
+    }
+    Ok(())
+}
+
+// applies a dispute/resolve/chargeback of a withdrawal to client under WithdrawalDisputePolicy::HoldReversal
+fn mod_withdrawal_reversal(
+    client: &mut ClientChange,
+    orig_tx: &Transaction,
+    state: TransactionState,
+    reject_chargeback: bool,
+) -> Result<(), ApplyError> {
+    let amount = -orig_tx.amount; // the withdrawn amount, positive
+    let (held, total) = match state {
+        Disputed => {
+            if orig_tx.state != Resolved {
+                return Err(ApplyError::IllegalStateTransition);
+            }
+            // credit it back provisionally, but hold all of it
+            (
+                add_exact(client.held, amount),
+                add_exact(client.total, amount),
+            )
+        }
+        Resolved => {
+            if orig_tx.state != Disputed {
+                return Err(ApplyError::IllegalStateTransition);
+            }
+            // the withdrawal stands, take the provisional credit back out
+            (
+                sub_exact(client.held, amount),
+                sub_exact(client.total, amount),
+            )
+        }
+        Chargeback => {
+            if orig_tx.state != Disputed {
+                return Err(ApplyError::IllegalStateTransition);
+            }
+            if reject_chargeback {
+                return Err(ApplyError::WithdrawalChargeback);
+            }
+            // the withdrawal is reversed, release the credit so it's available
+            (sub_exact(client.held, amount), Some(client.total))
+        }
+    };
+    match (held, total) {
+        (Some(held), Some(total)) => {
+            client.held = held;
+            client.total = total;
+        }
+        _ => return Err(ApplyError::Overflow),
+    }
+    if state == Chargeback {
+        client.lock = LockChange::Lock(LockReason::Chargeback);
+    }
+    Ok(())
+}
+
+// 2 added transaction kinds, transfers, opening balances and fees
+const CHECKPOINT_VERSION: &str = "2";
+
+fn invalid_checkpoint(reason: &str) -> Box<dyn std::error::Error> {
+    Box::new(std::io::Error::new(std::io::ErrorKind::InvalidData, reason))
+}
+
+fn log_rejection(tx: u32, client: u16, reason: &ApplyError) {
+    log::warn!("rejected tx {} for client {}: {}", tx, client, reason);
+}
+
+pub(crate) fn state_name(state: TransactionState) -> &'static str {
+    match state {
+        Resolved => "resolved",
+        Disputed => "disputed",
+        Chargeback => "chargeback",
+    }
+}
+
+fn kind_name(kind: TransactionKind) -> &'static str {
+    match kind {
+        TransactionKind::Deposit => "deposit",
+        TransactionKind::Withdrawal => "withdrawal",
+    }
+}
+
+fn kind_from_name(name: &str) -> Option<TransactionKind> {
+    [TransactionKind::Deposit, TransactionKind::Withdrawal]
+        .iter()
+        .copied()
+        .find(|kind| kind_name(*kind) == name)
+}
+
+// a checkpoint field that's empty when there's nothing to save
+fn optional(value: Option<&Decimal>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn state_from_name(name: &str) -> Option<TransactionState> {
+    [Resolved, Disputed, Chargeback]
+        .iter()
+        .copied()
+        .find(|state| state_name(*state) == name)
+}
+
+fn lock_reason_name(reason: LockReason) -> &'static str {
+    match reason {
+        LockReason::Chargeback => "chargeback",
+        LockReason::Manual => "manual",
+        LockReason::Regulatory => "regulatory",
+    }
+}
+
+fn lock_reason_from_name(name: &str) -> Option<LockReason> {
+    let reasons = [
+        LockReason::Chargeback,
+        LockReason::Manual,
+        LockReason::Regulatory,
+    ];
+    reasons
+        .iter()
+        .copied()
+        .find(|reason| lock_reason_name(*reason) == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TransactionMod, TransactionState, TransactionTransfer, TransactionUnlock};
+    use std::str::FromStr;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn new_tx(tx: u32, client: u16, amount: &str) -> TransactionRow {
+        let mut amount = dec(amount);
+        amount.rescale(DECIMAL_PLACES);
+        let kind = if amount.is_sign_negative() {
+            TransactionKind::Withdrawal
+        } else {
+            TransactionKind::Deposit
+        };
+        TransactionRow::New(Transaction {
+            tx,
+            client,
+            kind,
+            amount,
+            state: Resolved,
+            raw_amount: None,
+            held_amount: Decimal::ZERO,
+        })
+    }
+
+    fn mod_tx(tx: u32, client: u16, state: TransactionState) -> TransactionRow {
+        TransactionRow::Mod(TransactionMod {
+            tx,
+            client: Some(client),
+            state,
+            amount: None,
+            op_id: None,
+        })
+    }
+
+    fn partial_dispute_tx(tx: u32, client: u16, amount: &str) -> TransactionRow {
+        let mut amount = dec(amount);
+        amount.rescale(DECIMAL_PLACES);
+        TransactionRow::Mod(TransactionMod {
+            tx,
+            client: Some(client),
+            state: Disputed,
+            amount: Some(amount),
+            op_id: None,
+        })
+    }
+
+    fn op_mod_tx(tx: u32, client: u16, state: TransactionState, op_id: u64) -> TransactionRow {
+        TransactionRow::Mod(TransactionMod {
+            tx,
+            client: Some(client),
+            state,
+            amount: None,
+            op_id: Some(op_id),
+        })
+    }
+
+    fn unlock_tx(tx: u32, client: u16) -> TransactionRow {
+        TransactionRow::Unlock(TransactionUnlock { tx, client })
+    }
+
+    fn transfer_tx(tx: u32, client: u16, to_client: u16, amount: &str) -> TransactionRow {
+        let mut amount = dec(amount);
+        amount.rescale(DECIMAL_PLACES);
+        TransactionRow::Transfer(TransactionTransfer {
+            tx,
+            client,
+            to_client,
+            amount,
+        })
+    }
+
+    #[test]
+    fn seed_clients() {
+        let mut engine = TransactionEngine::default();
+        engine
+            .seed_clients(vec![(1, dec("10")), (2, dec("0.5"))].into_iter())
+            .unwrap();
+
+        // withdrawals build on the opening balance
+        assert!(engine.apply(new_tx(1, 1, "-4.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 2, "-1.0")).is_err());
+
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.total, dec("6.0000"));
+        assert_eq!(client.held, dec("0.0000"));
+        assert!(!client.locked);
+        assert_eq!(engine.clients.get(&2).unwrap().total, dec("0.5000"));
+
+        // and so do disputes
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+    }
+
+    #[test]
+    fn withdrawal_fees() {
+        let mut engine = TransactionEngine::default().with_fee_config(FeeConfig::Percent(dec("1")));
+        engine.apply(new_tx(1, 1, "200.0")).unwrap();
+        engine.apply(new_tx(2, 1, "-100.0")).unwrap();
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.total, dec("99.0"));
+        assert_eq!(client.available(), dec("99.0"));
+        assert_eq!(engine.fees_collected(), Some(dec("1.0")));
+        // 99.0 would leave nothing for the fee
+        assert_eq!(
+            engine.apply(new_tx(3, 1, "-99.0")),
+            Err(ApplyError::InsufficientFunds)
+        );
+        engine.apply(new_tx(4, 1, "-98.0")).unwrap();
+        assert_eq!(engine.client(1).unwrap().total, dec("0.02"));
+        // deposits are free, and disputing a withdrawal doesn't refund its fee
+        engine.apply(new_tx(5, 1, "1.0")).unwrap();
+        engine.apply(mod_tx(2, 1, Disputed)).unwrap();
+        engine.apply(mod_tx(2, 1, Chargeback)).unwrap();
+        assert_eq!(engine.client(1).unwrap().total, dec("101.02"));
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        // rounded up to decimal places
+        let mut engine =
+            TransactionEngine::default().with_fee_config(FeeConfig::Percent(dec("0.5")));
+        engine.apply(new_tx(1, 1, "1.0")).unwrap();
+        engine.apply(new_tx(2, 1, "-0.0101")).unwrap();
+        assert_eq!(engine.fees_collected(), Some(dec("0.0001")));
+
+        let mut engine = TransactionEngine::default().with_fee_config(FeeConfig::Flat(dec("0.5")));
+        engine.apply(new_tx(1, 1, "1.0")).unwrap();
+        engine.apply(new_tx(2, 1, "-0.5")).unwrap();
+        assert_eq!(engine.client(1).unwrap().total, dec("0"));
+        assert_eq!(
+            engine
+                .apply(new_tx(3, 1, "1.0"))
+                .and(engine.apply(new_tx(4, 1, "-0.6"))),
+            Err(ApplyError::InsufficientFunds)
+        );
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn available_rounding() {
+        let engine = |rounding: Option<RoundingStrategy>| {
+            let mut engine = TransactionEngine::default().with_decimal_places(5);
+            if let Some(strategy) = rounding {
+                engine = engine.with_available_rounding(4, strategy);
+            }
+            engine
+                .seed_clients(vec![(1, dec("1.00005")), (2, dec("1.00015"))].into_iter())
+                .unwrap();
+            engine
+        };
+        // exact by default
+        let exact = engine(None);
+        assert_eq!(exact.available(1), Some(dec("1.00005")));
+        assert_eq!(exact.available(3), None);
+
+        let banker = engine(Some(RoundingStrategy::MidpointNearestEven));
+        assert_eq!(banker.available(1).unwrap().to_string(), "1.0000");
+        assert_eq!(banker.available(2).unwrap().to_string(), "1.0002");
+        let half_up = engine(Some(RoundingStrategy::MidpointAwayFromZero));
+        assert_eq!(half_up.available(1).unwrap().to_string(), "1.0001");
+        assert_eq!(half_up.available(2).unwrap().to_string(), "1.0002");
+        let truncate = engine(Some(RoundingStrategy::ToZero));
+        assert_eq!(truncate.available(1).unwrap().to_string(), "1.0000");
+        assert_eq!(truncate.available(2).unwrap().to_string(), "1.0001");
+        // the balances themselves keep every place
+        assert_eq!(truncate.client(1).unwrap().available(), dec("1.00005"));
+
+        // rescaled up too, not just rounded
+        let mut wider =
+            TransactionEngine::default().with_available_rounding(6, RoundingStrategy::ToZero);
+        wider.apply(new_tx(1, 1, "1.5")).unwrap();
+        assert_eq!(wider.available(1).unwrap().to_string(), "1.500000");
+    }
+
+    #[test]
+    fn seed_clients_errors() {
+        let mut engine = TransactionEngine::default();
+        engine
+            .seed_clients(vec![(1, dec("10"))].into_iter())
+            .unwrap();
+
+        assert_eq!(
+            engine.seed_clients(vec![(2, dec("-1"))].into_iter()),
+            Err(SeedError::NegativeBalance(2))
+        );
+        assert_eq!(
+            engine.seed_clients(vec![(2, Decimal::MAX)].into_iter()),
+            Err(SeedError::Overflow(2))
+        );
+        assert_eq!(
+            engine.seed_clients(vec![(1, dec("1"))].into_iter()),
+            Err(SeedError::DuplicateClient(1))
+        );
+        assert_eq!(
+            engine.seed_clients(vec![(2, dec("1")), (2, dec("1"))].into_iter()),
+            Err(SeedError::DuplicateClient(2))
+        );
+
+        // nothing from the failed seeds was applied
+        assert_eq!(engine.clients().count(), 1);
+        assert_eq!(engine.clients.get(&1).unwrap().total, dec("10.0000"));
+    }
+
+    #[test]
+    fn mod_dedup() {
+        let mut engine = TransactionEngine::default().with_mod_dedup(2);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "3.0")).is_ok());
+        assert!(engine.apply(op_mod_tx(1, 1, Disputed, 1)).is_ok());
+        // a redelivered dispute is rejected without being applied
+        assert_eq!(
+            engine.apply(op_mod_tx(1, 1, Disputed, 1)),
+            Err(ApplyError::DuplicateOp)
+        );
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("5.0000"));
+        assert_eq!(engine.stats().rejected, 1);
+
+        // failed mods aren't remembered
+        assert_eq!(
+            engine.apply(op_mod_tx(2, 1, Resolved, 2)),
+            Err(ApplyError::IllegalStateTransition)
+        );
+        assert_eq!(
+            engine.apply(op_mod_tx(2, 1, Resolved, 2)),
+            Err(ApplyError::IllegalStateTransition)
+        );
+
+        // a second dispute of the same tx is a different op, so it's applied
+        assert!(engine.apply(op_mod_tx(1, 1, Resolved, 3)).is_ok());
+        assert!(engine.apply(op_mod_tx(1, 1, Disputed, 4)).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("5.0000"));
+        assert_eq!(
+            engine.apply(op_mod_tx(1, 1, Resolved, 3)),
+            Err(ApplyError::DuplicateOp)
+        );
+
+        // once evicted, it's applied again
+        assert!(engine.apply(op_mod_tx(1, 1, Resolved, 5)).is_ok());
+        assert!(engine.apply(op_mod_tx(1, 1, Disputed, 1)).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("5.0000"));
+
+        // without an op id a mod is never deduplicated
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert_eq!(
+            engine.apply(mod_tx(2, 1, Disputed)),
+            Err(ApplyError::IllegalStateTransition)
+        );
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("8.0000"));
+
+        // without dedup, a redelivered dispute is rejected as an illegal transition
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_err());
+    }
+
+    #[test]
+    fn max_tx_per_client() {
+        let mut engine = TransactionEngine::default().with_max_tx_per_client(Some(2));
+        engine
+            .seed_clients(vec![(3, dec("1"))].into_iter())
+            .unwrap();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        // rejected transactions don't count
+        assert!(engine.apply(new_tx(2, 1, "-6.0")).is_err());
+        assert!(engine.apply(new_tx(3, 1, "-1.0")).is_ok());
+        // at the cap
+        assert!(engine.apply(new_tx(4, 1, "1.0")).is_err());
+        assert!(engine.apply(new_tx(5, 1, "-1.0")).is_err());
+        // disputes don't count and aren't capped
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().tx_count, 2);
+        assert_eq!(engine.clients.get(&1).unwrap().total, dec("4.0000"));
+
+        // other clients have their own count, seeding doesn't count
+        assert!(engine.apply(new_tx(6, 2, "1.0")).is_ok());
+        assert!(engine.apply(new_tx(7, 3, "1.0")).is_ok());
+        assert!(engine.apply(new_tx(8, 3, "1.0")).is_ok());
+        assert!(engine.apply(new_tx(9, 3, "1.0")).is_err());
+
+        let mut engine = TransactionEngine::default().with_max_tx_per_client(Some(0));
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_err());
+        assert_eq!(engine.clients().count(), 0);
+    }
+
+    #[test]
+    fn apply_all_two_pass() {
+        let rows = || {
+            vec![
+                mod_tx(1, 1, Disputed),
+                new_tx(1, 1, "5.0"),
+                mod_tx(1, 1, Chargeback),
+                new_tx(2, 1, "1.0"),
+                unlock_tx(0, 1),
+            ]
+        };
+        // streaming, the dispute doesn't find its deposit, so neither does the chargeback
+        let mut engine = TransactionEngine::default();
+        assert_eq!(engine.apply_all(rows()), 3);
+        assert_eq!(engine.orphan_mods(), &[(Some(1), 1)]);
+        assert_eq!(engine.client(1).unwrap().total, dec("6.0000"));
+
+        let mut engine = TransactionEngine::default();
+        assert_eq!(engine.apply_all_two_pass(rows()), 5);
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.total, dec("1.0000"));
+        assert_eq!(client.held, dec("0.0000"));
+        // the unlock comes after the chargeback
+        assert!(!client.locked);
+        assert!(engine.orphan_mods().is_empty());
+    }
+
+    #[test]
+    fn apply_csv_line() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply_csv_line("deposit, 1, 1, 5.0").unwrap());
+        assert!(engine.apply_csv_line("withdrawal, 1, 2, 2.0\n").unwrap());
+        assert_eq!(engine.client(1).unwrap().available(), dec("3.0000"));
+        assert!(!engine.apply_csv_line("").unwrap());
+        assert!(!engine.apply_csv_line("  # a comment").unwrap());
+        let err = engine.apply_csv_line("deposit, 1, x, 5.0").unwrap_err();
+        assert!(err.downcast_ref::<crate::RecordError>().is_some());
+        let err = engine.apply_csv_line("withdrawal, 1, 3, 9.0").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ApplyError>(),
+            Some(&ApplyError::InsufficientFunds)
+        );
+        assert_eq!(engine.client(1).unwrap().available(), dec("3.0000"));
+    }
+
+    #[test]
+    fn client_filter() {
+        let rows = || {
+            vec![
+                new_tx(1, 1, "5.0"),
+                new_tx(2, 2, "5.0"),
+                new_tx(3, 3, "5.0"),
+                mod_tx(3, 3, Disputed),
+                transfer_tx(4, 1, 3, "1.0"),
+                transfer_tx(5, 1, 2, "1.0"),
+            ]
+        };
+        let mut engine = TransactionEngine::with_btreemap()
+            .with_client_filter(ClientFilter::Allow(HashSet::from([1, 2])));
+        // skipped rows aren't errors
+        assert_eq!(engine.apply_all(rows()), 6);
+        assert!(engine.client(3).is_none());
+        assert_eq!(engine.stats().applied, 3);
+        assert_eq!(engine.stats().rejected, 0);
+        let mut out = Vec::new();
+        crate::dump_client_csv(&mut out, engine.clients()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n1,4.0000,0.0000,4.0000,false\n2,6.0000,0.0000,6.0000,false\n"
+        );
+        assert_eq!(engine.apply_with_delta(new_tx(6, 3, "1.0")), Ok(None));
+
+        let mut engine =
+            TransactionEngine::default().with_client_filter(ClientFilter::Deny(HashSet::from([1])));
+        assert_eq!(engine.apply_all(rows()), 6);
+        assert!(engine.client(1).is_none());
+        assert_eq!(engine.client(3).unwrap().held, dec("5.0000"));
+        assert_eq!(engine.client(2).unwrap().total, dec("5.0000"));
+    }
+
+    #[test]
+    fn max_open_disputes() {
+        let mut engine = TransactionEngine::default().with_max_open_disputes(Some(1));
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 2, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert_eq!(
+            engine.apply(mod_tx(2, 1, Disputed)),
+            Err(ApplyError::OpenDisputeCapExceeded)
+        );
+        // other clients have their own count
+        assert!(engine.apply(mod_tx(3, 2, Disputed)).is_ok());
+        // an illegal transition is still reported as one
+        assert_eq!(
+            engine.apply(mod_tx(1, 1, Disputed)),
+            Err(ApplyError::IllegalStateTransition)
+        );
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_ok());
+        assert_eq!(engine.client(1).unwrap().open_disputes(), 0);
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert_eq!(engine.client(1).unwrap().open_disputes(), 1);
+        // a chargeback closes it too
+        assert!(engine.apply(mod_tx(2, 1, Chargeback)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+
+        // and open disputes survive a checkpoint
+        let mut saved = Vec::new();
+        engine.save(&mut saved).unwrap();
+        let mut loaded = TransactionEngine::load(&saved[..])
+            .unwrap()
+            .with_max_open_disputes(Some(1));
+        assert_eq!(loaded.client(2).unwrap().open_disputes(), 1);
+        assert!(loaded.apply(new_tx(4, 2, "5.0")).is_ok());
+        assert_eq!(
+            loaded.apply(mod_tx(4, 2, Disputed)),
+            Err(ApplyError::OpenDisputeCapExceeded)
+        );
+    }
+
+    #[test]
+    fn client_mismatch_reject() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 2, Disputed)).is_err());
+        assert!(engine.client_mismatches().is_empty());
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("0.0000"));
+    }
+
+    #[test]
+    fn client_mismatch_record_and_reject() {
+        let mut engine = TransactionEngine::default()
+            .with_client_mismatch_policy(ClientMismatchPolicy::RecordAndReject);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 2, Disputed)).is_err());
+        assert!(engine.apply(mod_tx(1, 0, Disputed)).is_err());
+        // the correct client still works and isn't recorded
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert_eq!(engine.client_mismatches(), &[(1, 2), (1, 0)]);
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("5.0000"));
+    }
+
+    #[test]
+    fn client_mismatch_use_original_client() {
+        let mut engine = TransactionEngine::default()
+            .with_client_mismatch_policy(ClientMismatchPolicy::UseOriginalClient)
+            .with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 2, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 0, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 2, Chargeback)).is_ok());
+        assert!(engine.client_mismatches().is_empty());
+
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.held, dec("0.0000"));
+        assert_eq!(client.total, dec("0.0000"));
+        assert!(client.locked);
+        // the client given on the mods is untouched, and 0 never gets created
+        let client = engine.clients.get(&2).unwrap();
+        assert_eq!(client.total, dec("5.0000"));
+        assert!(!client.locked);
+        assert!(!engine.clients.contains_key(&0));
+    }
+
+    #[test]
+    fn clients_in_order() {
+        let mut engine = TransactionEngine::default().with_invariant_check(InvariantCheck::Reject);
+        engine
+            .seed_clients(vec![(9, dec("1")), (3, dec("1"))].into_iter())
+            .unwrap();
+        assert!(engine.apply(new_tx(1, 5, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 5, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(4, 7, "5.0")).is_ok());
+        // rejected so never created
+        assert!(engine.apply(new_tx(5, 2, "-5.0")).is_err());
+
+        let order: Vec<u16> = engine.clients_in_order().map(|c| c.client).collect();
+        assert_eq!(order, vec![9, 3, 5, 1, 7]);
+    }
+
+    #[test]
+    fn chargeback_deposit_vs_withdrawal() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 2, "5.0")).is_ok());
+
+        // a disputed withdrawal holds a negative amount, and charging it back returns the funds to total
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("-2.0000"));
+        assert!(engine.apply(mod_tx(2, 1, Chargeback)).is_ok());
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.held, dec("0.0000"));
+        assert_eq!(client.total, dec("5.0000"));
+
+        // while a disputed deposit holds a positive amount, and charging it back removes the funds from total
+        assert!(engine.apply(mod_tx(3, 2, Disputed)).is_ok());
+        assert_eq!(engine.clients.get(&2).unwrap().held, dec("5.0000"));
+        assert!(engine.apply(mod_tx(3, 2, Chargeback)).is_ok());
+        let client = engine.clients.get(&2).unwrap();
+        assert_eq!(client.held, dec("0.0000"));
+        assert_eq!(client.total, dec("0.0000"));
+    }
+
+    #[test]
+    fn reject_withdrawal_chargebacks() {
+        let mut engine = TransactionEngine::default()
+            .with_negative_held_policy(NegativeHeldPolicy::RejectWithdrawalChargebacks);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(2, 1, Chargeback)).is_err());
+        // it can only be resolved
+        assert!(engine.apply(mod_tx(2, 1, Resolved)).is_ok());
+        // deposits can still be charged back
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.held, dec("0.0000"));
+        assert_eq!(client.total, dec("-2.0000"));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn reject_negative_held() {
+        let mut engine = TransactionEngine::default()
+            .with_negative_held_policy(NegativeHeldPolicy::RejectNegativeHeld);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("3.0000"));
+        // resolving or charging back the deposit would leave held at -2
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_err());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_err());
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("3.0000"));
+        // but once the withdrawal is resolved it's fine
+        assert!(engine.apply(mod_tx(2, 1, Resolved)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("0.0000"));
+    }
+
+    #[test]
+    fn orphan_mods() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(7, 1, Disputed)).is_err());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        // rejected for other reasons, so not an orphan
+        assert!(engine.apply(mod_tx(1, 2, Resolved)).is_err());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_err());
+        assert!(engine.apply(mod_tx(8, 3, Chargeback)).is_err());
+        // a rejected deposit never gets stored, so mods of it are orphans too
+        assert!(engine.apply(new_tx(2, 4, "-1.0")).is_err());
+        assert!(engine.apply(mod_tx(2, 4, Disputed)).is_err());
+
+        assert_eq!(
+            engine.orphan_mods(),
+            &[(Some(1), 7), (Some(3), 8), (Some(4), 2)]
+        );
+    }
+
+    #[test]
+    fn unlock() {
+        let mut engine = TransactionEngine::default();
+        // only existing clients can be unlocked, and the reason doesn't claim it was a withdrawal
+        let err = engine.apply(unlock_tx(0, 1)).unwrap_err();
+        assert_eq!(err, ApplyError::UnknownClient);
+        assert_eq!(err.to_string(), "unknown client");
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+        assert!(engine.set_locked(1, true, LockReason::Manual));
+
+        // still rejected while locked
+        assert!(engine.apply(new_tx(3, 1, "-1.0")).is_err());
+
+        // tx is ignored, it can even reuse an existing id
+        assert!(engine.apply(unlock_tx(2, 1)).is_ok());
+        let client = engine.clients.get(&1).unwrap();
+        assert!(!client.locked);
+        assert!(client.lock_reasons().is_empty());
+        assert!(engine.apply(new_tx(3, 1, "-1.0")).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().total, dec("4.0"));
+
+        // unlocking an unlocked client changes nothing
+        assert!(engine.apply(unlock_tx(4, 1)).is_ok());
+        assert!(!engine.clients.get(&1).unwrap().locked);
+        assert!(!engine.transactions.contains_key(&4));
+    }
+
+    #[test]
+    fn lock_policy() {
+        let mut engine = TransactionEngine::default().with_lock_policy(LockPolicy::AllActivity);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 1, "1.0")).is_ok());
+        assert!(engine.apply(mod_tx(3, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+
+        // deposits, withdrawals and disputes are all rejected after the lock
+        assert_eq!(
+            engine.apply(new_tx(4, 1, "7.0")),
+            Err(ApplyError::AccountLocked)
+        );
+        assert!(engine.apply(new_tx(5, 1, "-1.0")).is_err());
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_err());
+        // but funds already held can still be released
+        assert!(engine.apply(mod_tx(3, 1, Resolved)).is_ok());
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!((client.total, client.held), (dec("6.0"), dec("0.0")));
+        assert!(!engine.transactions.contains_key(&4));
+
+        // other clients aren't affected
+        assert!(engine.apply(new_tx(6, 2, "7.0")).is_ok());
+
+        // the default still allows deposits after a lock
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "7.0")).is_ok());
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+    }
+
+    #[test]
+    fn stats() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 1, "-1.0")).is_ok());
+        assert!(engine.apply(new_tx(4, 2, "-1.0")).is_err());
+        assert!(engine.apply(new_tx(5, 2, "1.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_err());
+        assert!(engine.apply(unlock_tx(0, 3)).is_err());
+
+        assert_eq!(
+            engine.stats(),
+            RunStats {
+                applied: 8,
+                rejected: 3,
+                redeliveries: 0,
+                deposits: 3,
+                withdrawals: 1,
+                disputes: 2,
+                resolves: 1,
+                chargebacks: 1,
+                locked_accounts: 1,
+            }
+        );
+
+        // locked_accounts is current, not cumulative
+        assert!(engine.apply(unlock_tx(0, 1)).is_ok());
+        let stats = engine.stats();
+        assert_eq!((stats.applied, stats.locked_accounts), (9, 0));
+    }
+
+    #[test]
+    fn stats_to_prometheus() {
+        let stats = RunStats {
+            applied: 1234,
+            rejected: 5,
+            redeliveries: 7,
+            deposits: 1000,
+            withdrawals: 200,
+            disputes: 20,
+            resolves: 10,
+            chargebacks: 4,
+            locked_accounts: 3,
+        };
+        assert_eq!(
+            stats.to_prometheus(),
+            "\
+# TYPE transactions_applied_total counter
+transactions_applied_total 1234
+# TYPE transactions_rejected_total counter
+transactions_rejected_total 5
+# TYPE transactions_redelivered_total counter
+transactions_redelivered_total 7
+# TYPE deposits_total counter
+deposits_total 1000
+# TYPE withdrawals_total counter
+withdrawals_total 200
+# TYPE disputes_total counter
+disputes_total 20
+# TYPE resolves_total counter
+resolves_total 10
+# TYPE chargebacks_total counter
+chargebacks_total 4
+# TYPE clients_locked gauge
+clients_locked 3
+"
+        );
+    }
+
+    #[test]
+    fn set_locked() {
+        let mut engine = TransactionEngine::default();
+        assert!(!engine.set_locked(1, true, LockReason::Manual));
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "5.0")).is_ok());
+
+        assert!(engine.set_locked(1, true, LockReason::Manual));
+        assert!(engine.clients.get(&1).unwrap().locked);
+        assert!(engine.apply(new_tx(3, 1, "-1.0")).is_err());
+
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+        assert_eq!(
+            engine.clients.get(&1).unwrap().lock_reasons(),
+            &[LockReason::Manual, LockReason::Chargeback]
+        );
+
+        // clearing the chargeback lock leaves the manual lock in place
+        assert!(engine.set_locked(1, false, LockReason::Chargeback));
+        let client = engine.clients.get(&1).unwrap();
+        assert!(client.locked);
+        assert_eq!(client.lock_reasons(), &[LockReason::Manual]);
+        assert!(engine.apply(new_tx(4, 1, "-1.0")).is_err());
+
+        // unlocking a reason that isn't set changes nothing
+        assert!(engine.set_locked(1, false, LockReason::Regulatory));
+        assert!(engine.clients.get(&1).unwrap().locked);
+
+        assert!(engine.set_locked(1, false, LockReason::Manual));
+        let client = engine.clients.get(&1).unwrap();
+        assert!(!client.locked);
+        assert!(client.lock_reasons().is_empty());
+        assert!(engine.apply(new_tx(5, 1, "-1.0")).is_ok());
+    }
+
+    #[test]
+    fn client() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.client(1).is_none());
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "1.5")).is_ok());
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.client(), 1);
+        assert_eq!(client.available(), dec("5.0"));
+        assert_eq!((client.held(), client.total()), (dec("1.5"), dec("6.5")));
+        assert!(!client.locked());
+        assert!(engine.client(999).is_none());
+    }
+
+    #[test]
+    fn locked_clients() {
+        let mut engine = TransactionEngine::default();
+        assert_eq!(engine.locked_clients().count(), 0);
+        for client in 1..=3 {
+            assert!(engine.apply(new_tx(client as u32, client, "5.0")).is_ok());
+        }
+        assert!(engine.apply(mod_tx(2, 2, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(2, 2, Chargeback)).is_ok());
+
+        let locked: Vec<&Client> = engine.locked_clients().collect();
+        assert_eq!(locked.len(), 1);
+        assert_eq!(locked[0].client(), 2);
+
+        let mut out: Vec<u8> = Vec::new();
+        crate::dump_client_csv(&mut out, engine.locked_clients()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n2,0.0000,0.0000,0.0000,true\n"
+        );
+    }
+
+    #[test]
+    fn sum_held_where() {
+        let mut engine = TransactionEngine::default();
+        assert_eq!(engine.sum_held_where(|_| true), Some(dec("0.0000")));
+
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 2, "2.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 3, "1.5")).is_ok());
+        assert!(engine.apply(new_tx(4, 3, "4.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(3, 3, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(4, 3, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(4, 3, Chargeback)).is_ok());
+
+        assert_eq!(engine.sum_held_where(|_| true), Some(dec("6.5000")));
+        assert_eq!(engine.sum_held_where(|c| c.locked), Some(dec("1.5000")));
+        assert_eq!(
+            engine.sum_held_where(|c| c.total > dec("2.0")),
+            Some(dec("5.0000"))
+        );
+
+        engine.clients.get_mut(&1).unwrap().held = Decimal::MAX;
+        assert_eq!(engine.sum_held_where(|_| true), None);
+    }
+
+    #[test]
+    fn clients_touched_in_range() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(5, 2, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(10, 3, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(11, 1, "-1.0")).is_ok());
+        // rejected transactions touch nothing
+        assert!(engine.apply(new_tx(6, 4, "-1.0")).is_err());
+
+        assert_eq!(
+            engine.clients_touched_in_range(1, 5),
+            vec![1, 2].into_iter().collect()
+        );
+        assert_eq!(
+            engine.clients_touched_in_range(5, 11),
+            vec![1, 2, 3].into_iter().collect()
+        );
+        assert_eq!(
+            engine.clients_touched_in_range(10, 10),
+            vec![3].into_iter().collect()
+        );
+        assert!(engine.clients_touched_in_range(6, 9).is_empty());
+    }
+
+    #[test]
+    fn duplicate_tx_ignore() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(1, 1, "4.0")).is_err());
+        assert!(engine.rejected_duplicates().is_empty());
+        assert!(!engine.is_halted());
+        assert!(engine.apply(new_tx(2, 1, "1.0")).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().total, dec("6.0000"));
+    }
+
+    #[test]
+    fn duplicate_tx_reject() {
+        let mut engine =
+            TransactionEngine::default().with_duplicate_tx_policy(DuplicateTxPolicy::Reject);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 2, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "4.0")).is_err());
+        assert!(engine.apply(new_tx(1, 1, "-4.0")).is_err());
+        assert_eq!(engine.rejected_duplicates(), &[2, 1]);
+        assert!(!engine.is_halted());
+        assert!(engine.apply(new_tx(3, 1, "1.0")).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().total, dec("6.0000"));
+    }
+
+    #[test]
+    fn duplicate_tx_error() {
+        let mut engine =
+            TransactionEngine::default().with_duplicate_tx_policy(DuplicateTxPolicy::Error);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(!engine.is_halted());
+        assert!(engine.apply(new_tx(1, 1, "4.0")).is_err());
+        assert!(engine.is_halted());
+        // nothing is applied after halting
+        assert!(engine.apply(new_tx(2, 1, "1.0")).is_err());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_err());
+        assert_eq!(engine.clients.get(&1).unwrap().total, dec("5.0000"));
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("0.0000"));
+    }
+
+    #[test]
+    fn duplicate_tx_accept_if_identical() {
+        let mut engine = TransactionEngine::default()
+            .with_duplicate_tx_policy(DuplicateTxPolicy::AcceptIfIdentical)
+            .with_audit();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-1.0")).is_ok());
+        let stats = engine.stats();
+        let audit_log = engine.audit_log().to_vec();
+
+        // redeliveries are accepted, but change nothing, not even stats or the audit log
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-1.0")).is_ok());
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!((client.total, client.tx_count), (dec("4.0"), 2));
+        assert_eq!(
+            engine.stats(),
+            RunStats {
+                redeliveries: 2,
+                ..stats
+            }
+        );
+        assert_eq!(engine.audit_log(), &audit_log[..]);
+        // nor do they count as applied
+        assert_eq!(
+            engine.apply_all(vec![new_tx(1, 1, "5.0"), new_tx(3, 1, "1.0")]),
+            1
+        );
+        assert_eq!(engine.stats().redeliveries, 3);
+
+        // anything different is a conflicting duplicate
+        assert_eq!(
+            engine.apply(new_tx(1, 1, "5.1")),
+            Err(ApplyError::DuplicateTx)
+        );
+        assert_eq!(
+            engine.apply(new_tx(1, 2, "5.0")),
+            Err(ApplyError::DuplicateTx)
+        );
+        assert_eq!(
+            engine.apply(new_tx(1, 1, "-5.0")),
+            Err(ApplyError::DuplicateTx)
+        );
+        assert!(!engine.clients.contains_key(&2));
+
+        // still the same deposit after it's been disputed
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert_eq!(engine.transactions[&1].state, Disputed);
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("5.0"));
+    }
+
+    #[test]
+    fn tx_update() {
+        let mut engine =
+            TransactionEngine::default().with_duplicate_tx_policy(DuplicateTxPolicy::Update);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "3.0")).is_ok());
+        // correct the pending deposit both down and up
+        assert!(engine.apply(new_tx(1, 1, "4.0")).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().total, dec("7.0000"));
+        assert!(engine.apply(new_tx(1, 1, "6.5")).is_ok());
+        assert_eq!(engine.clients.get(&1).unwrap().total, dec("9.5000"));
+        assert_eq!(engine.transactions.get(&1).unwrap().amount, dec("6.5000"));
+
+        // without the option, the correction is dropped
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(1, 1, "4.0")).is_err());
+        assert_eq!(engine.clients.get(&1).unwrap().total, dec("5.0000"));
+    }
+
+    #[test]
+    fn tx_update_rejected() {
+        let mut engine =
+            TransactionEngine::default().with_duplicate_tx_policy(DuplicateTxPolicy::Update);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 2, "5.0")).is_ok());
+        // can't move it to a different client
+        assert!(engine.apply(new_tx(1, 2, "4.0")).is_err());
+        // can't turn it into a withdrawal
+        assert!(engine.apply(new_tx(1, 1, "-4.0")).is_err());
+
+        // can't update a withdrawal
+        assert!(engine.apply(new_tx(3, 2, "-1.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 2, "2.0")).is_err());
+        // nor a deposit that was withdrawn against
+        assert!(engine.apply(new_tx(2, 2, "6.0")).is_err());
+
+        // nor a disputed deposit, even after it's resolved, or charged back
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(new_tx(1, 1, "4.0")).is_err());
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_ok());
+        assert!(engine.apply(new_tx(1, 1, "4.0")).is_err());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+        assert!(engine.apply(new_tx(1, 1, "4.0")).is_err());
+
+        // nor when it would remove funds another dispute is holding
+        assert!(engine.apply(new_tx(4, 3, "10.0")).is_ok());
+        assert!(engine.apply(new_tx(5, 3, "-8.0")).is_ok());
+        assert!(engine.apply(new_tx(6, 3, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(4, 3, Disputed)).is_ok());
+        assert!(engine.apply(new_tx(6, 3, "1.0")).is_err());
+        assert_eq!(engine.clients.get(&3).unwrap().total, dec("7.0000"));
+
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.total, dec("0.0000"));
+        assert!(client.locked);
+        assert_eq!(engine.clients.get(&2).unwrap().total, dec("4.0000"));
+    }
+
+    #[test]
+    fn invariant_check_negative_held() {
+        let mut engine = TransactionEngine::default().with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        // disputing a withdrawal puts held into negative
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_err());
+        assert_eq!(
+            engine.last_invariant_violation(),
+            Some(&InvariantViolation::NegativeHeld(1))
+        );
+        // and it was never applied
+        assert_eq!(engine.clients.get(&1).unwrap().held, dec("0.0000"));
+        assert_eq!(engine.transactions.get(&2).unwrap().state, Resolved);
+
+        // without checks the same sequence is allowed
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+    }
+
+    #[test]
+    fn invariant_check_negative_total() {
+        let mut engine = TransactionEngine::default().with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-4.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        // charging back the deposit takes total to -4
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_err());
+        assert_eq!(
+            engine.last_invariant_violation(),
+            Some(&InvariantViolation::NegativeTotal(1))
+        );
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.total, dec("1.0000"));
+        assert_eq!(client.held, dec("5.0000"));
+        assert!(!client.locked);
+        assert_eq!(engine.transactions.get(&1).unwrap().state, Disputed);
+    }
+
+    #[test]
+    fn invariant_check_available_overflow() {
+        let mut engine = TransactionEngine::default();
+        engine
+            .seed_clients(vec![(1, dec("1"))].into_iter())
+            .unwrap();
+        let client = engine.clients.get_mut(&1).unwrap();
+        client.total = Decimal::MAX;
+        client.held = Decimal::NEGATIVE_ONE;
+        assert_eq!(
+            engine.check_client_invariants(1),
+            Err(InvariantViolation::AvailableOverflow(1))
+        );
+        assert_eq!(engine.check_client_invariants(2), Ok(()));
+    }
+
+    #[test]
+    fn check_invariants() {
+        let mut engine = TransactionEngine::default();
+        engine
+            .seed_clients(vec![(6_000, dec("10"))].into_iter())
+            .unwrap();
+        engine.apply_all(generated_rows(1_000));
+        assert!(engine.apply(new_tx(5_000, 6_000, "-4.0")).is_ok());
+        assert!(engine.apply(new_tx(5_001, 6_000, "2.0")).is_ok());
+        assert!(engine.apply(mod_tx(5_001, 6_000, Disputed)).is_ok());
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        // a disputed withdrawal holds a negative amount, which adds up but isn't a valid client
+        assert!(engine.apply(mod_tx(5_000, 6_000, Disputed)).is_ok());
+        assert_eq!(
+            engine.check_invariants(),
+            Err(InvariantViolation::NegativeHeld(6_000))
+        );
+        assert!(engine.apply(mod_tx(5_000, 6_000, Chargeback)).is_ok());
+        assert!(engine.apply(mod_tx(5_001, 6_000, Resolved)).is_ok());
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        // held can end up above total, a spent deposit can still be disputed
+        assert!(engine.apply(new_tx(5_002, 6_001, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(5_003, 6_001, "-5.0")).is_ok());
+        assert!(engine.apply(mod_tx(5_002, 6_001, Disputed)).is_ok());
+        let client = engine.clients.get(&6_001).unwrap();
+        assert!(client.held > client.total);
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        engine.transactions.get_mut(&5_001).unwrap().amount = dec("3.0000");
+        assert_eq!(
+            engine.check_invariants(),
+            Err(InvariantViolation::TotalMismatch(6_000))
+        );
+        engine.transactions.get_mut(&5_001).unwrap().amount = dec("2.0000");
+        let tx = engine.transactions.get_mut(&5_001).unwrap();
+        tx.state = Disputed;
+        tx.held_amount = tx.amount;
+        assert_eq!(
+            engine.check_invariants(),
+            Err(InvariantViolation::HeldMismatch(6_000))
+        );
+        let tx = engine.transactions.get_mut(&5_001).unwrap();
+        tx.state = Resolved;
+        tx.held_amount = Decimal::ZERO;
+        engine.transactions.get_mut(&5_001).unwrap().client = 9_000;
+        assert_eq!(
+            engine.check_invariants(),
+            Err(InvariantViolation::OrphanTransaction(5_001))
+        );
+    }
+
+    #[test]
+    fn zero_amount() {
+        let mut engine = TransactionEngine::default();
+        // a new client is created, but nothing changes otherwise
+        assert!(engine.apply(new_tx(1, 1, "0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "5.0")).is_ok());
+        let zero_withdrawal = TransactionRow::New(Transaction {
+            tx: 3,
+            client: 1,
+            kind: TransactionKind::Withdrawal,
+            amount: dec("0.0000"),
+            state: Resolved,
+            raw_amount: None,
+            held_amount: Decimal::ZERO,
+        });
+        assert!(engine.apply(zero_withdrawal).is_ok());
+        let client = engine.client(1).unwrap();
+        assert_eq!((client.total, client.held), (dec("5"), dec("0")));
+        assert_eq!(client.tx_count(), 3);
+        // the tx ids are used up
+        assert_eq!(
+            engine.apply(new_tx(1, 1, "1.0")),
+            Err(ApplyError::DuplicateTx)
+        );
+        assert_eq!(
+            engine.apply(new_tx(3, 1, "1.0")),
+            Err(ApplyError::DuplicateTx)
+        );
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn check_invariants_hold_reversal() {
+        let mut engine = TransactionEngine::default()
+            .with_withdrawal_dispute_policy(WithdrawalDisputePolicy::HoldReversal);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 1, "-1.0")).is_ok());
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(3, 1, Disputed)).is_ok());
+        assert_eq!(engine.check_invariants(), Ok(()));
+        assert!(engine.apply(mod_tx(2, 1, Chargeback)).is_ok());
+        assert!(engine.apply(mod_tx(3, 1, Resolved)).is_ok());
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "NegativeHeld")]
+    fn invariant_check_debug_assert() {
+        let mut engine =
+            TransactionEngine::default().with_invariant_check(InvariantCheck::DebugAssert);
+        let _ = engine.apply(new_tx(1, 1, "5.0"));
+        let _ = engine.apply(new_tx(2, 1, "-2.0"));
+        let _ = engine.apply(mod_tx(2, 1, Disputed));
+    }
+
+    #[test]
+    fn held_ratio_alerts() {
+        let mut engine = TransactionEngine::default().with_held_ratio_alert(Some(dec("0.5")));
+        assert!(engine.apply(new_tx(1, 1, "3.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "4.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 1, "3.0")).is_ok());
+        // held 3 of 10
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.held_ratio_alerts().is_empty());
+        // held 7 of 10 crosses
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert_eq!(engine.held_ratio_alerts(), &[(1, 2)]);
+        // already over, so not flagged again
+        assert!(engine.apply(mod_tx(3, 1, Disputed)).is_ok());
+        // rejected disputes are never flagged
+        assert!(engine.apply(mod_tx(3, 1, Disputed)).is_err());
+        assert_eq!(engine.held_ratio_alerts(), &[(1, 2)]);
+        // exactly at the ratio isn't over it
+        assert!(engine.apply(new_tx(4, 2, "2.0")).is_ok());
+        assert!(engine.apply(new_tx(5, 2, "2.0")).is_ok());
+        assert!(engine.apply(mod_tx(4, 2, Disputed)).is_ok());
+        assert_eq!(engine.held_ratio_alerts(), &[(1, 2)]);
+
+        // a zero total is flagged by any positive held
+        assert!(engine.apply(new_tx(6, 3, "2.0")).is_ok());
+        assert!(engine.apply(new_tx(7, 3, "-2.0")).is_ok());
+        assert!(engine.apply(mod_tx(6, 3, Disputed)).is_ok());
+        assert_eq!(engine.held_ratio_alerts(), &[(1, 2), (3, 6)]);
+
+        // off by default
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "3.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.held_ratio_alerts().is_empty());
+    }
+
+    #[test]
+    fn apply_emitting() {
+        use EngineEvent::*;
+        let mut engine = TransactionEngine::default();
+        let mut events = Vec::new();
+        let mut emit = |event: &EngineEvent| events.push(event.clone());
+        assert!(engine
+            .apply_emitting(new_tx(1, 1, "5.0"), &mut emit)
+            .is_ok());
+        assert!(engine
+            .apply_emitting(new_tx(2, 1, "-1.0"), &mut emit)
+            .is_ok());
+        assert!(engine
+            .apply_emitting(new_tx(3, 1, "-10.0"), &mut emit)
+            .is_err());
+        assert!(engine
+            .apply_emitting(mod_tx(1, 1, Disputed), &mut emit)
+            .is_ok());
+        assert!(engine
+            .apply_emitting(mod_tx(9, 1, Disputed), &mut emit)
+            .is_err());
+        assert!(engine
+            .apply_emitting(mod_tx(1, 1, Chargeback), &mut emit)
+            .is_ok());
+        assert!(engine
+            .apply_emitting(new_tx(4, 1, "-1.0"), &mut emit)
+            .is_err());
+        assert_eq!(
+            events,
+            vec![
+                ClientCreated { tx: 1, client: 1 },
+                BalanceChanged {
+                    tx: 1,
+                    client: 1,
+                    delta: dec("5.0000")
+                },
+                BalanceChanged {
+                    tx: 2,
+                    client: 1,
+                    delta: dec("-1.0000")
+                },
+                TransactionRejected {
+                    tx: 3,
+                    client: 1,
+                    reason: ApplyError::InsufficientFunds
+                },
+                HeldChanged {
+                    tx: 1,
+                    client: 1,
+                    delta: dec("5.0000")
+                },
+                TransactionRejected {
+                    tx: 9,
+                    client: 1,
+                    reason: ApplyError::ModTargetNotFound
+                },
+                BalanceChanged {
+                    tx: 1,
+                    client: 1,
+                    delta: dec("-5.0000")
+                },
+                HeldChanged {
+                    tx: 1,
+                    client: 1,
+                    delta: dec("-5.0000")
+                },
+                ClientLocked { tx: 1, client: 1 },
+                TransactionRejected {
+                    tx: 4,
+                    client: 1,
+                    reason: ApplyError::AccountLocked
+                },
+            ]
+        );
+
+        // the events are the same changes apply makes
+        let mut plain = TransactionEngine::default();
+        for tx in [
+            new_tx(1, 1, "5.0"),
+            new_tx(2, 1, "-1.0"),
+            mod_tx(1, 1, Disputed),
+        ] {
+            let _ = plain.apply(tx);
+        }
+        let mut emitting = TransactionEngine::default();
+        for tx in [
+            new_tx(1, 1, "5.0"),
+            new_tx(2, 1, "-1.0"),
+            mod_tx(1, 1, Disputed),
+        ] {
+            let _ = emitting.apply_emitting(tx, |_| {});
+        }
+        assert_eq!(plain.client(1), emitting.client(1));
+    }
+
+    #[test]
+    fn apply_with_delta() {
+        let mut engine = TransactionEngine::default()
+            .with_duplicate_tx_policy(DuplicateTxPolicy::AcceptIfIdentical);
+        assert_eq!(
+            engine.apply_with_delta(new_tx(1, 1, "5.0")),
+            Ok(Some(ClientDelta {
+                client: 1,
+                total: dec("5.0000"),
+                held: dec("0.0000"),
+                locked: false
+            }))
+        );
+        assert_eq!(
+            engine.apply_with_delta(mod_tx(1, 1, Disputed)),
+            Ok(Some(ClientDelta {
+                client: 1,
+                total: dec("5.0000"),
+                held: dec("5.0000"),
+                locked: false
+            }))
+        );
+        assert_eq!(
+            engine.apply_with_delta(mod_tx(9, 1, Disputed)),
+            Err(ApplyError::ModTargetNotFound)
+        );
+        let delta = engine
+            .apply_with_delta(mod_tx(1, 1, Chargeback))
+            .unwrap()
+            .unwrap();
+        let client = engine.client(1).unwrap();
+        assert_eq!(
+            delta,
+            ClientDelta {
+                client: 1,
+                total: client.total,
+                held: client.held,
+                locked: client.locked
+            }
+        );
+        assert_eq!(delta.total, dec("0.0000"));
+        assert!(delta.locked);
+        // a redelivery changes nothing
+        assert_eq!(engine.apply_with_delta(new_tx(1, 1, "5.0")), Ok(None));
+    }
+
+    #[test]
+    fn with_btreemap() {
+        let mut engine = TransactionEngine::with_btreemap().with_max_tx_per_client(Some(2));
+        for (tx, client) in [(1, 9), (2, 3), (3, 700), (4, 1), (5, 3), (6, 3)] {
+            let _ = engine.apply(new_tx(tx, client, "1.0"));
+        }
+        let ids: Vec<u16> = engine.clients().map(|c| c.client).collect();
+        assert_eq!(ids, vec![1, 3, 9, 700]);
+        // still otherwise the same engine, the cap applied and insertion order is still tracked
+        assert_eq!(engine.client(3).unwrap().total, dec("2.0000"));
+        let ids: Vec<u16> = engine.clients_in_order().map(|c| c.client).collect();
+        assert_eq!(ids, vec![9, 3, 700, 1]);
+    }
+
+    #[test]
+    fn apply_errors() {
+        use ApplyError::*;
+        let mut engine = TransactionEngine::default();
+        assert_eq!(engine.apply(new_tx(1, 1, "5.0")), Ok(()));
+        assert_eq!(engine.apply(new_tx(1, 1, "5.0")), Err(DuplicateTx));
+        assert_eq!(engine.apply(new_tx(2, 2, "-1.0")), Err(UnknownClient));
+        assert_eq!(engine.apply(new_tx(3, 1, "-6.0")), Err(InsufficientFunds));
+        assert_eq!(
+            engine.apply(new_tx(4, 1, "79228162514264337593543950335")),
+            Err(Overflow)
+        );
+        assert_eq!(engine.apply(mod_tx(1, 2, Disputed)), Err(ClientMismatch));
+        assert_eq!(engine.apply(mod_tx(9, 1, Disputed)), Err(ModTargetNotFound));
+        assert_eq!(
+            engine.apply(mod_tx(1, 1, Resolved)),
+            Err(IllegalStateTransition)
+        );
+        assert_eq!(engine.apply(mod_tx(1, 1, Disputed)), Ok(()));
+        assert_eq!(engine.apply(mod_tx(1, 1, Chargeback)), Ok(()));
+        assert_eq!(engine.apply(new_tx(5, 1, "-1.0")), Err(AccountLocked));
+        // after a chargeback every mod is TxFinalized, not just an illegal transition
+        assert_eq!(engine.apply(mod_tx(1, 1, Disputed)), Err(TxFinalized));
+        assert_eq!(engine.apply(mod_tx(1, 1, Resolved)), Err(TxFinalized));
+        assert_eq!(engine.apply(mod_tx(1, 1, Chargeback)), Err(TxFinalized));
+        assert_eq!(engine.apply(new_tx(6, 1, "1.0")), Ok(()));
+        assert_eq!(
+            engine.apply(mod_tx(6, 1, Chargeback)),
+            Err(IllegalStateTransition)
+        );
+
+        let mut engine = TransactionEngine::default().with_max_tx_per_client(Some(1));
+        assert_eq!(engine.apply(new_tx(1, 1, "5.0")), Ok(()));
+        assert_eq!(engine.apply(new_tx(2, 1, "5.0")), Err(ClientTxCapExceeded));
+
+        let mut engine = TransactionEngine::default()
+            .with_negative_held_policy(NegativeHeldPolicy::RejectWithdrawalChargebacks);
+        assert_eq!(engine.apply(new_tx(1, 1, "5.0")), Ok(()));
+        assert_eq!(engine.apply(new_tx(2, 1, "-1.0")), Ok(()));
+        assert_eq!(engine.apply(mod_tx(2, 1, Disputed)), Ok(()));
+        assert_eq!(
+            engine.apply(mod_tx(2, 1, Chargeback)),
+            Err(WithdrawalChargeback)
+        );
+
+        let mut engine = TransactionEngine::default()
+            .with_negative_held_policy(NegativeHeldPolicy::RejectNegativeHeld);
+        assert_eq!(engine.apply(new_tx(1, 1, "5.0")), Ok(()));
+        assert_eq!(engine.apply(new_tx(2, 1, "-1.0")), Ok(()));
+        assert_eq!(engine.apply(mod_tx(1, 1, Disputed)), Ok(()));
+        assert_eq!(engine.apply(mod_tx(2, 1, Disputed)), Ok(()));
+        assert_eq!(engine.apply(mod_tx(1, 1, Resolved)), Err(NegativeHeld));
+
+        let mut engine =
+            TransactionEngine::default().with_duplicate_tx_policy(DuplicateTxPolicy::Update);
+        assert_eq!(engine.apply(new_tx(1, 1, "5.0")), Ok(()));
+        assert_eq!(engine.apply(new_tx(1, 1, "-5.0")), Err(NotUpdatable));
+
+        let mut engine =
+            TransactionEngine::default().with_duplicate_tx_policy(DuplicateTxPolicy::Error);
+        assert_eq!(engine.apply(new_tx(1, 1, "5.0")), Ok(()));
+        assert_eq!(engine.apply(new_tx(1, 1, "5.0")), Err(DuplicateTx));
+        assert_eq!(engine.apply(new_tx(2, 1, "5.0")), Err(Halted));
+
+        let mut engine = TransactionEngine::default().with_invariant_check(InvariantCheck::Reject);
+        assert_eq!(engine.apply(new_tx(1, 1, "5.0")), Ok(()));
+        assert_eq!(engine.apply(new_tx(2, 1, "-2.0")), Ok(()));
+        assert_eq!(
+            engine.apply(mod_tx(2, 1, Disputed)),
+            Err(InvariantViolation(super::InvariantViolation::NegativeHeld(
+                1
+            )))
+        );
+    }
+
+    #[test]
+    fn audit_log() {
+        let mut engine = TransactionEngine::default()
+            .with_duplicate_tx_policy(DuplicateTxPolicy::Update)
+            .with_audit();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-1.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 1, "-10.0")).is_err());
+        assert!(engine.apply(new_tx(4, 1, "2.0")).is_ok());
+        assert!(engine.apply(new_tx(4, 1, "3.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_ok());
+        assert!(engine.apply(mod_tx(4, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(4, 1, Chargeback)).is_ok());
+
+        let entry = |tx, kind, total: &str, held: &str| AuditEntry {
+            tx,
+            client: 1,
+            kind,
+            total: dec(total),
+            held: dec(held),
+        };
+        assert_eq!(
+            engine.audit_log(),
+            &[
+                entry(1, AuditKind::Deposit, "5.0000", "0.0000"),
+                entry(2, AuditKind::Withdrawal, "4.0000", "0.0000"),
+                entry(4, AuditKind::Deposit, "6.0000", "0.0000"),
+                entry(4, AuditKind::DepositUpdated, "7.0000", "0.0000"),
+                entry(1, AuditKind::Dispute, "7.0000", "5.0000"),
+                entry(1, AuditKind::Resolve, "7.0000", "0.0000"),
+                entry(4, AuditKind::Dispute, "7.0000", "3.0000"),
+                entry(4, AuditKind::Chargeback, "4.0000", "0.0000"),
+            ]
+        );
+
+        // off by default, and never allocated
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.audit.is_none());
+        assert!(engine.audit_log().is_empty());
+    }
+
+    #[test]
+    fn mod_target_errors() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        assert_eq!(
+            engine.apply(mod_tx(3, 1, Disputed)),
+            Err(ApplyError::ModTargetNotFound)
+        );
+
+        // by default disputing a withdrawal holds its negative amount, as documented on HoldNegative
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        let client = engine.client(1).unwrap();
+        assert_eq!((client.available(), client.held), (dec("5"), dec("-2")));
+
+        let mut engine = TransactionEngine::default()
+            .with_withdrawal_dispute_policy(WithdrawalDisputePolicy::Reject);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        // the tx exists, but is the wrong type, which is distinct from not existing
+        for state in [Disputed, Resolved, Chargeback].iter().copied() {
+            assert_eq!(
+                engine.apply(mod_tx(2, 1, state)),
+                Err(ApplyError::ModTargetIncompatible)
+            );
+        }
+        assert_eq!(
+            engine.apply(mod_tx(3, 1, Disputed)),
+            Err(ApplyError::ModTargetNotFound)
+        );
+        assert_eq!(engine.client(1).unwrap().held, dec("0"));
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+    }
+
+    #[test]
+    fn withdrawal_dispute_hold_reversal() {
+        let mut engine = TransactionEngine::default()
+            .with_withdrawal_dispute_policy(WithdrawalDisputePolicy::HoldReversal);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        let balances = |engine: &TransactionEngine| {
+            let client = engine.client(1).unwrap();
+            (client.available(), client.held, client.total)
+        };
+
+        // disputing the withdrawal holds it without changing available
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert_eq!(balances(&engine), (dec("3"), dec("2"), dec("5")));
+        // resolving means the withdrawal stands
+        assert!(engine.apply(mod_tx(2, 1, Resolved)).is_ok());
+        assert_eq!(balances(&engine), (dec("3"), dec("0"), dec("3")));
+        // charging it back returns the funds
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(2, 1, Chargeback)).is_ok());
+        assert_eq!(balances(&engine), (dec("5"), dec("0"), dec("5")));
+        assert!(engine.client(1).unwrap().locked);
+        assert_eq!(
+            engine.apply(mod_tx(2, 1, Resolved)),
+            Err(ApplyError::TxFinalized)
+        );
+
+        // deposits are unchanged
+        assert!(engine.apply(new_tx(3, 2, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(3, 2, Disputed)).is_ok());
+        let client = engine.client(2).unwrap();
+        assert_eq!((client.held, client.total), (dec("5"), dec("5")));
+        assert!(engine.apply(mod_tx(3, 2, Chargeback)).is_ok());
+        let client = engine.client(2).unwrap();
+        assert_eq!((client.held, client.total), (dec("0"), dec("0")));
+
+        // and NegativeHeldPolicy::RejectWithdrawalChargebacks still applies
+        let mut engine = TransactionEngine::default()
+            .with_withdrawal_dispute_policy(WithdrawalDisputePolicy::HoldReversal)
+            .with_negative_held_policy(NegativeHeldPolicy::RejectWithdrawalChargebacks);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-2.0")).is_ok());
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_ok());
+        assert_eq!(
+            engine.apply(mod_tx(2, 1, Chargeback)),
+            Err(ApplyError::WithdrawalChargeback)
+        );
+    }
+
+    #[test]
+    fn overdraft_policy() {
+        let mut engine = TransactionEngine::default()
+            .with_overdraft_policy(OverdraftPolicy::ClampToAvailable)
+            .with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "30.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-100.0")).is_ok());
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.total, dec("0.0000"));
+        assert_eq!(engine.transactions[&2].amount, dec("-30.0000"));
+        assert_eq!(engine.clamped_withdrawals(), &[(2, dec("-100.0000"))]);
+        // nothing left to clamp to
+        assert_eq!(
+            engine.apply(new_tx(3, 1, "-1.0")),
+            Err(ApplyError::InsufficientFunds)
+        );
+        // a withdrawal that fits isn't clamped
+        assert!(engine.apply(new_tx(4, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(5, 1, "-5.0")).is_ok());
+        assert_eq!(engine.clamped_withdrawals().len(), 1);
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        // the fee has to fit too
+        let mut engine = TransactionEngine::default()
+            .with_overdraft_policy(OverdraftPolicy::ClampToAvailable)
+            .with_fee_config(FeeConfig::Percent(dec("1")))
+            .with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "10.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-100.0")).is_ok());
+        assert_eq!(engine.transactions[&2].amount, dec("-9.9009"));
+        assert_eq!(engine.fees_collected(), Some(dec("0.0991")));
+        assert_eq!(engine.client(1).unwrap().total, dec("0.0000"));
+
+        // rejected by default
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "30.0")).is_ok());
+        assert_eq!(
+            engine.apply(new_tx(2, 1, "-100.0")),
+            Err(ApplyError::InsufficientFunds)
+        );
+        assert!(engine.clamped_withdrawals().is_empty());
+    }
+
+    #[test]
+    fn transfers() {
+        let mut engine = TransactionEngine::default().with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "10.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 2, "1.0")).is_ok());
+        assert!(engine.apply(transfer_tx(3, 1, 2, "4.0")).is_ok());
+        assert_eq!(engine.client(1).unwrap().total, dec("6.0000"));
+        assert_eq!(engine.client(2).unwrap().total, dec("5.0000"));
+        // a new destination is created
+        assert!(engine.apply(transfer_tx(4, 1, 3, "1.0")).is_ok());
+        assert_eq!(engine.client(3).unwrap().total, dec("1.0000"));
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        // only available funds can be moved
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert_eq!(
+            engine.apply(transfer_tx(5, 1, 2, "1.0")),
+            Err(ApplyError::InsufficientFunds)
+        );
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_ok());
+        assert_eq!(
+            engine.apply(transfer_tx(6, 1, 2, "5.0001")),
+            Err(ApplyError::InsufficientFunds)
+        );
+        assert_eq!(
+            engine.apply(transfer_tx(7, 4, 2, "1.0")),
+            Err(ApplyError::UnknownClient)
+        );
+        assert_eq!(
+            engine.apply(transfer_tx(11, 1, 1, "1.0")),
+            Err(ApplyError::SelfTransfer)
+        );
+        // nothing changed on either side
+        assert_eq!(engine.client(1).unwrap().total, dec("5.0000"));
+        assert_eq!(engine.client(2).unwrap().total, dec("5.0000"));
+
+        // locked on either side
+        assert!(engine.set_locked(2, true, LockReason::Manual));
+        assert_eq!(
+            engine.apply(transfer_tx(8, 1, 2, "1.0")),
+            Err(ApplyError::AccountLocked)
+        );
+        assert_eq!(
+            engine.apply(transfer_tx(9, 2, 1, "1.0")),
+            Err(ApplyError::AccountLocked)
+        );
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        // both sides are emitted
+        let mut events = Vec::new();
+        assert!(engine
+            .apply_emitting(transfer_tx(10, 1, 4, "2.0"), |e| events.push(e.clone()))
+            .is_ok());
+        assert_eq!(
+            events,
+            vec![
+                EngineEvent::BalanceChanged {
+                    tx: 10,
+                    client: 1,
+                    delta: dec("-2.0000")
+                },
+                EngineEvent::ClientCreated { tx: 10, client: 4 },
+                EngineEvent::BalanceChanged {
+                    tx: 10,
+                    client: 4,
+                    delta: dec("2.0000")
+                },
+            ]
+        );
+
+        // a redelivered transfer doesn't move anything twice, and transfers share tx ids with deposits/withdrawals
+        let mut engine = TransactionEngine::default()
+            .with_duplicate_tx_policy(DuplicateTxPolicy::Reject)
+            .with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "10.0")).is_ok());
+        assert!(engine.apply(transfer_tx(2, 1, 2, "4.0")).is_ok());
+        assert_eq!(
+            engine.apply(transfer_tx(2, 1, 2, "4.0")),
+            Err(ApplyError::DuplicateTx)
+        );
+        assert_eq!(
+            engine.apply(transfer_tx(1, 1, 2, "1.0")),
+            Err(ApplyError::DuplicateTx)
+        );
+        assert_eq!(
+            engine.apply(new_tx(2, 2, "1.0")),
+            Err(ApplyError::DuplicateTx)
+        );
+        assert_eq!(engine.rejected_duplicates(), &[2, 1, 2]);
+        assert_eq!(engine.client(1).unwrap().total, dec("6.0000"));
+        assert_eq!(engine.client(2).unwrap().total, dec("4.0000"));
+        assert_eq!(engine.check_invariants(), Ok(()));
+        let mut engine = engine.with_duplicate_tx_policy(DuplicateTxPolicy::AcceptIfIdentical);
+        assert_eq!(engine.apply(transfer_tx(2, 1, 2, "4.0")), Ok(()));
+        assert_eq!(
+            engine.apply(transfer_tx(2, 1, 2, "3.0")),
+            Err(ApplyError::DuplicateTx)
+        );
+        assert_eq!(engine.client(1).unwrap().total, dec("6.0000"));
+
+        // apply_parallel applies a transfer between workers too, serially
+        let rows = vec![
+            new_tx(1, 1, "10.0"),
+            transfer_tx(2, 1, 3, "4.0"),
+            transfer_tx(3, 1, 2, "1.0"),
+        ];
+        let mut engine = TransactionEngine::default();
+        assert_eq!(engine.apply_parallel(rows.into_iter(), 2), 3);
+        assert_eq!(engine.client(1).unwrap().total, dec("5.0000"));
+        assert_eq!(engine.client(3).unwrap().total, dec("4.0000"));
+        assert_eq!(engine.client(2).unwrap().total, dec("1.0000"));
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn ever_disputed() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "1.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 2, "1.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_ok());
+        // a rejected dispute doesn't count
+        assert!(engine.apply(mod_tx(2, 2, Resolved)).is_err());
+        assert!(engine.apply(mod_tx(2, 1, Disputed)).is_err());
+        assert!(engine.client(1).unwrap().ever_disputed());
+        assert!(!engine.client(2).unwrap().ever_disputed());
+
+        let mut saved: Vec<u8> = Vec::new();
+        engine.save(&mut saved).unwrap();
+        let loaded = TransactionEngine::load(&saved[..]).unwrap();
+        assert!(loaded.client(1).unwrap().ever_disputed());
+        assert!(!loaded.client(2).unwrap().ever_disputed());
+    }
+
+    #[test]
+    fn save_load() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 2, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "3.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 2, "-1.5")).is_ok());
+        assert!(engine.apply(mod_tx(1, 2, Disputed)).is_ok());
+        assert!(engine.apply(new_tx(4, 3, "1.0")).is_ok());
+        assert!(engine.apply(mod_tx(4, 3, Disputed)).is_ok());
+        assert!(engine.apply(mod_tx(4, 3, Chargeback)).is_ok());
+        assert!(engine.set_locked(1, true, LockReason::Manual));
+
+        let mut saved: Vec<u8> = Vec::new();
+        engine.save(&mut saved).unwrap();
+        let mut loaded = TransactionEngine::load(&saved[..]).unwrap();
+
+        // saving the loaded engine gives the same checkpoint
+        let mut resaved: Vec<u8> = Vec::new();
+        loaded.save(&mut resaved).unwrap();
+        assert_eq!(saved, resaved);
+
+        // and both carry on the same way
+        for engine in [&mut engine, &mut loaded] {
+            assert!(engine.apply(mod_tx(1, 2, Resolved)).is_ok());
+            assert!(engine.apply(new_tx(1, 2, "9.0")).is_err());
+            assert!(engine.apply(new_tx(5, 2, "1.0")).is_ok());
+            assert!(engine.apply(new_tx(6, 1, "-1.0")).is_err());
+            assert!(engine.apply(new_tx(7, 3, "-1.0")).is_err());
+        }
+        let ordered =
+            |engine: &TransactionEngine| engine.clients_in_order().cloned().collect::<Vec<_>>();
+        assert_eq!(ordered(&engine), ordered(&loaded));
+        assert_eq!(
+            loaded.client(1).unwrap().lock_reasons(),
+            &[LockReason::Manual]
+        );
+
+        // checkpoints from before last_tx was saved still load
+        let old = TransactionEngine::load(&b"checkpoint,1\nclient,1,1.0,0.0,1,\n"[..]).unwrap();
+        assert_eq!(old.client(1).unwrap().last_tx(), None);
+        assert!(TransactionEngine::load(&b"client,1,1.0,0.0,1,\n"[..]).is_err());
+        assert!(TransactionEngine::load(&b"checkpoint,1\ntx,1,1,1.0,resolved\n"[..]).is_err());
+        assert!(TransactionEngine::load(&b"checkpoint,1\nclient,1,1.0,0.0,1,bla\n"[..]).is_err());
+    }
+
+    #[test]
+    fn save_load_transfers_fees() {
+        let zero_withdrawal = || {
+            TransactionRow::New(Transaction {
+                tx: 4,
+                client: 2,
+                kind: TransactionKind::Withdrawal,
+                amount: dec("0.0000"),
+                state: Resolved,
+                raw_amount: None,
+                held_amount: Decimal::ZERO,
+            })
+        };
+        let mut engine = TransactionEngine::default()
+            .with_fee_config(FeeConfig::Flat(dec("0.5")))
+            .with_duplicate_tx_policy(DuplicateTxPolicy::AcceptIfIdentical);
+        engine
+            .seed_clients(vec![(1, dec("10"))].into_iter())
+            .unwrap();
+        assert!(engine.apply(new_tx(1, 2, "5.0")).is_ok());
+        assert!(engine.apply(transfer_tx(2, 1, 2, "3.0")).is_ok());
+        assert!(engine.apply(new_tx(3, 1, "-1.0")).is_ok());
+        assert!(engine.apply(zero_withdrawal()).is_ok());
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        let mut saved: Vec<u8> = Vec::new();
+        engine.save(&mut saved).unwrap();
+        let loaded = TransactionEngine::load(&saved[..]).unwrap();
+        let mut resaved: Vec<u8> = Vec::new();
+        loaded.save(&mut resaved).unwrap();
+        assert_eq!(saved, resaved);
+        // opening balances, fees and transfers came along, so the totals still add up
+        assert_eq!(loaded.check_invariants(), Ok(()));
+
+        let mut loaded = loaded
+            .with_fee_config(FeeConfig::Flat(dec("0.5")))
+            .with_duplicate_tx_policy(DuplicateTxPolicy::AcceptIfIdentical);
+        for engine in [&mut engine, &mut loaded] {
+            // the transfer's tx id stays used up
+            assert_eq!(
+                engine.apply(new_tx(2, 1, "1.0")),
+                Err(ApplyError::DuplicateTx)
+            );
+            // and the zero withdrawal is still a withdrawal, so its redelivery is identical
+            assert!(engine.apply(zero_withdrawal()).is_ok());
+            assert!(engine.apply(new_tx(5, 2, "-1.0")).is_ok());
+            assert_eq!(engine.check_invariants(), Ok(()));
+        }
+        let ordered =
+            |engine: &TransactionEngine| engine.clients_in_order().cloned().collect::<Vec<_>>();
+        assert_eq!(ordered(&engine), ordered(&loaded));
+        assert_eq!(loaded.client(1).unwrap().total, dec("5.5"));
+        assert_eq!(loaded.client(2).unwrap().total, dec("6.0"));
+    }
+
+    #[test]
+    fn partial_disputes() {
+        let mut engine = TransactionEngine::default().with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(partial_dispute_tx(1, 1, "2.0")).is_ok());
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.total, dec("5.0000"));
+        assert_eq!(client.held, dec("2.0000"));
+        assert_eq!(client.available(), dec("3.0000"));
+
+        // the checkpoint keeps how much is held
+        let mut saved: Vec<u8> = Vec::new();
+        engine.save(&mut saved).unwrap();
+        let mut loaded = TransactionEngine::load(&saved[..])
+            .unwrap()
+            .with_invariant_check(InvariantCheck::Reject);
+
+        assert_eq!(loaded.transactions[&1].held_amount(), dec("2.0000"));
+        // and a resolve after loading returns exactly that
+        let mut resolved = TransactionEngine::load(&saved[..])
+            .unwrap()
+            .with_invariant_check(InvariantCheck::Reject);
+        assert!(resolved.apply(mod_tx(1, 1, Resolved)).is_ok());
+        assert_eq!(resolved.client(1).unwrap().available(), dec("5.0000"));
+        assert_eq!(resolved.transactions[&1].held_amount(), dec("0"));
+        assert_eq!(resolved.check_invariants(), Ok(()));
+
+        // only the 2.0 held is charged back
+        for engine in [&mut engine, &mut loaded] {
+            assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+            let client = engine.client(1).unwrap();
+            assert_eq!(client.total, dec("3.0000"));
+            assert_eq!(client.held, dec("0.0000"));
+            assert!(client.locked);
+            assert_eq!(engine.transactions[&1].held_amount(), dec("2.0000"));
+            assert_eq!(engine.check_invariants(), Ok(()));
+        }
+        // which a checkpoint of the charged back transaction still knows
+        let mut saved: Vec<u8> = Vec::new();
+        engine.save(&mut saved).unwrap();
+        let loaded = TransactionEngine::load(&saved[..]).unwrap();
+        assert_eq!(loaded.transactions[&1].held_amount(), dec("2.0000"));
+        assert_eq!(loaded.check_invariants(), Ok(()));
+
+        // a resolve releases just the part held, and the next dispute can be for all of it
+        let mut engine = TransactionEngine::default().with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(partial_dispute_tx(1, 1, "2.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_ok());
+        assert_eq!(engine.client(1).unwrap().held, dec("0.0000"));
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert_eq!(engine.client(1).unwrap().held, dec("5.0000"));
+        assert!(engine.apply(mod_tx(1, 1, Chargeback)).is_ok());
+        assert_eq!(engine.client(1).unwrap().total, dec("0.0000"));
+
+        // a resolve returns exactly the part held to available
+        let mut engine = TransactionEngine::default().with_invariant_check(InvariantCheck::Reject);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-1.5")).is_ok());
+        assert!(engine.apply(partial_dispute_tx(1, 1, "1.2345")).is_ok());
+        assert_eq!(engine.held_amount(1), Some(dec("1.2345")));
+        assert_eq!(engine.client(1).unwrap().available(), dec("2.2655"));
+        assert!(engine.apply(mod_tx(1, 1, Resolved)).is_ok());
+        assert_eq!(engine.held_amount(1), None);
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.held, dec("0.0000"));
+        assert_eq!(client.available(), dec("3.5000"));
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        // no more than the deposit, and never a withdrawal
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-1.0")).is_ok());
+        assert_eq!(
+            engine.apply(partial_dispute_tx(1, 1, "5.0001")),
+            Err(ApplyError::DisputeTooLarge)
+        );
+        assert_eq!(
+            engine.apply(partial_dispute_tx(2, 1, "0.5")),
+            Err(ApplyError::ModTargetIncompatible)
+        );
+        assert!(engine.apply(partial_dispute_tx(1, 1, "5.0")).is_ok());
+        assert_eq!(engine.client(1).unwrap().held, dec("5.0000"));
+        assert_eq!(engine.held_amount(1), Some(dec("5.0000")));
+        assert_eq!(engine.held_amount(2), None);
+    }
+
+    // a deterministic mix of deposits, withdrawals, disputes, resolves, chargebacks and bad rows over many clients
+    fn generated_rows(count: u32) -> Vec<TransactionRow> {
+        (0..count)
+            .map(|i| {
+                let client = (i.wrapping_mul(2_654_435_761) % 5000) as u16;
+                match i % 10 {
+                    0..=4 => new_tx(i, client, &format!("{}.{:04}", i % 100 + 1, i % 10000)),
+                    5 | 6 => new_tx(i, client, &format!("-{}.5", i % 50)),
+                    // these reference earlier rows, some of which were rejected or belong to other clients
+                    7 => mod_tx(i - 7, client, Disputed),
+                    8 => mod_tx(i.saturating_sub(8 + 10 * (i % 3)), client, Resolved),
+                    _ => mod_tx(i.saturating_sub(9 + 10 * (i % 2)), client, Chargeback),
+                }
+            })
+            .collect()
+    }
+
+    // xorshift64, so the random tests are reproducible without pulling in a crate
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn balance_conservation() {
+        // with no disputes, every client's total is exactly the sum of the signed amounts that applied for it
+        for seed in 1..=200u64 {
+            let mut rng = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            let mut engine = TransactionEngine::default();
+            let mut net: HashMap<u16, Decimal> = HashMap::new();
+            for _ in 0..200 {
+                // few enough tx ids and clients that duplicates and unknown clients come up often
+                let tx = (next_random(&mut rng) % 150) as u32;
+                let client = (next_random(&mut rng) % 5) as u16;
+                let amount = match next_random(&mut rng) % 20 {
+                    // big enough that two of them overflow
+                    0 => dec("7922816251426433759354395"),
+                    _ => Decimal::new(
+                        (next_random(&mut rng) % 1_000_000) as i64 + 1,
+                        (next_random(&mut rng) % 5) as u32,
+                    ),
+                };
+                // a third are withdrawals
+                let amount = match next_random(&mut rng) % 3 {
+                    0 => -amount,
+                    _ => amount,
+                };
+                if engine
+                    .apply(new_tx(tx, client, &amount.to_string()))
+                    .is_ok()
+                {
+                    let net = net.entry(client).or_default();
+                    *net += amount;
+                }
+            }
+            for client in engine.clients() {
+                assert_eq!(
+                    client.total,
+                    net.remove(&client.client).unwrap(),
+                    "seed {}",
+                    seed
+                );
+                assert!(client.held.is_zero());
+            }
+            // and no client had anything apply without existing
+            assert!(net.is_empty(), "seed {}", seed);
+            assert_eq!(engine.check_invariants(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn dispute_policy() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-4.0")).is_ok());
+        assert!(engine.apply(mod_tx(1, 1, Disputed)).is_ok());
+        assert_eq!(engine.client(1).unwrap().available(), dec("-4"));
+
+        let mut engine = TransactionEngine::default()
+            .with_dispute_policy(DisputePolicy::RejectIfNegativeAvailable);
+        assert!(engine.apply(new_tx(1, 1, "5.0")).is_ok());
+        assert!(engine.apply(new_tx(2, 1, "-4.0")).is_ok());
+        assert_eq!(
+            engine.apply(mod_tx(1, 1, Disputed)),
+            Err(ApplyError::InsufficientFunds)
+        );
+        assert_eq!(engine.client(1).unwrap().held, dec("0"));
+        // exactly 0 available is fine
+        assert!(engine.apply(new_tx(3, 2, "5.0")).is_ok());
+        assert!(engine.apply(mod_tx(3, 2, Disputed)).is_ok());
+        assert_eq!(engine.client(2).unwrap().available(), dec("0"));
+    }
+
+    #[test]
+    fn rounding_is_overflow() {
+        let mut engine = TransactionEngine::default();
+        assert!(engine
+            .apply(new_tx(1, 1, "7922816251426433759354395"))
+            .is_ok());
+        // fits, but only if rounded to 3 places
+        assert_eq!(
+            engine.apply(new_tx(2, 1, "0.1234")),
+            Err(ApplyError::Overflow)
+        );
+        assert_eq!(
+            engine.client(1).unwrap().total,
+            dec("7922816251426433759354395")
+        );
+    }
+
+    #[test]
+    fn apply_parallel() {
+        let mut serial = TransactionEngine::default()
+            .with_client_mismatch_policy(ClientMismatchPolicy::UseOriginalClient);
+        let applied = serial.apply_all(generated_rows(100_000));
+        for threads in [1, 2, 3, 8] {
+            let mut parallel = TransactionEngine::default()
+                .with_client_mismatch_policy(ClientMismatchPolicy::UseOriginalClient);
+            // existing state is carried over too
+            let mut rows = generated_rows(100_000).into_iter();
+            assert!(parallel.apply(rows.next().unwrap()).is_ok());
+            let parallel_applied = 1 + parallel.apply_parallel(rows, threads);
+            assert_eq!(parallel_applied, applied);
+            let clients =
+                |engine: &TransactionEngine| engine.clients_in_order().cloned().collect::<Vec<_>>();
+            assert_eq!(clients(&parallel), clients(&serial));
+            assert_eq!(parallel.stats(), serial.stats());
+            assert_eq!(parallel.transactions, serial.transactions);
+        }
+    }
+
+    #[test]
+    fn apply_limited() {
+        let mut engine = TransactionEngine::default();
+        let run = engine.apply_limited(generated_rows(1000), 10);
+        assert_eq!(run.rows, 10);
+        assert!(run.limit_hit);
+        let stats = engine.stats();
+        assert_eq!(stats.applied + stats.rejected, 10);
+        assert_eq!(run.applied as u64, stats.applied);
+
+        // the same as the first 10 rows on their own
+        let mut first = TransactionEngine::default();
+        assert_eq!(first.apply_all(generated_rows(10)), run.applied);
+        assert_eq!(first.stats(), stats);
+
+        // exactly max_rows isn't over the limit
+        let mut engine = TransactionEngine::default();
+        let run = engine.apply_limited(generated_rows(10), 10);
+        assert_eq!(run.rows, 10);
+        assert!(!run.limit_hit);
+    }
+
+    #[test]
+    fn apply_all() {
+        let mut manual = TransactionEngine::default();
+        let mut applied = 0;
+        for row in generated_rows(10_000) {
+            if manual.apply(row).is_ok() {
+                applied += 1;
+            }
+        }
+        let mut engine = TransactionEngine::default();
+        assert_eq!(engine.apply_all(generated_rows(10_000)), applied);
+        assert_eq!(engine.stats(), manual.stats());
+        let mut clients: Vec<&Client> = engine.clients().collect();
+        let mut manual_clients: Vec<&Client> = manual.clients().collect();
+        clients.sort_by_key(|c| c.client);
+        manual_clients.sort_by_key(|c| c.client);
+        assert_eq!(clients, manual_clients);
+    }
+
+    #[test]
+    fn validate() {
+        let mut engine = TransactionEngine::default()
+            .with_client_mismatch_policy(ClientMismatchPolicy::RecordAndReject);
+        assert!(engine.apply(new_tx(0, 1, "5.0")).is_ok());
+        let rows = || {
+            vec![
+                new_tx(1, 1, "-2.0"),
+                new_tx(2, 2, "-1.0"),   // unknown client
+                new_tx(0, 1, "1.0"),    // duplicate of existing state
+                mod_tx(1, 2, Disputed), // client mismatch
+                mod_tx(0, 1, Disputed), // only 3.0 available, but disputes can go negative
+                new_tx(3, 1, "-1.0"),
+                mod_tx(0, 1, Chargeback),
+                new_tx(4, 1, "-0.5"), // locked by the chargeback above
+                mod_tx(9, 1, Resolved),
+            ]
+        };
+        let report = engine.validate(rows().into_iter());
+        assert_eq!(report.accepted(), 3);
+        assert_eq!(
+            report.rejected().collect::<Vec<_>>(),
+            vec![
+                (1, &ApplyError::UnknownClient),
+                (2, &ApplyError::DuplicateTx),
+                (3, &ApplyError::ClientMismatch),
+                (5, &ApplyError::InsufficientFunds),
+                (7, &ApplyError::AccountLocked),
+                (8, &ApplyError::ModTargetNotFound),
+            ]
+        );
+        // nothing changed, not even the diagnostics
+        assert_eq!(engine.client(1).unwrap().total, dec("5.0"));
+        assert!(!engine.client(1).unwrap().locked);
+        assert_eq!(engine.transactions.values().count(), 1);
+        // the dispute and chargeback of tx 0 only changed validate's copy of it
+        assert_eq!(engine.transactions.get(&0).unwrap().state, Resolved);
+        assert!(engine.client_mismatches().is_empty());
+        assert!(engine.orphan_mods().is_empty());
+
+        // and applying for real agrees
+        let applied: Vec<_> = rows().into_iter().map(|row| engine.apply(row)).collect();
+        assert_eq!(applied, report.outcomes);
+
+        let mut engine = TransactionEngine::default();
+        let report = engine.validate(generated_rows(10_000).into_iter());
+        let applied: Vec<_> = generated_rows(10_000)
+            .into_iter()
+            .map(|row| engine.apply(row))
+            .collect();
+        assert_eq!(applied, report.outcomes);
+
+        // rows validated on top of existing transactions, disputing, resolving and charging back some of them
+        let report = engine.validate(generated_rows(10_000).into_iter().skip(5_000));
+        let mut copy = TransactionEngine {
+            transactions: engine.transactions.clone(),
+            clients: engine.clients.clone(),
+            ..engine.empty_like()
+        };
+        let applied: Vec<_> = generated_rows(10_000)
+            .into_iter()
+            .skip(5_000)
+            .map(|row| copy.apply(row))
+            .collect();
+        assert_eq!(applied, report.outcomes);
+
+        // evictions depend on the store, so with_recent_transactions validates on a copy of it
+        let mut engine = TransactionEngine::default().with_recent_transactions(100);
+        let report = engine.validate(generated_rows(2_000).into_iter());
+        let applied: Vec<_> = generated_rows(2_000)
+            .into_iter()
+            .map(|row| engine.apply(row))
+            .collect();
+        assert_eq!(applied, report.outcomes);
+    }
+
+    // everything apply_parallel has to put back the way serial apply would have left it
+    fn assert_same_as_serial(parallel: &TransactionEngine, serial: &TransactionEngine) {
+        let clients =
+            |engine: &TransactionEngine| engine.clients_in_order().cloned().collect::<Vec<_>>();
+        assert_eq!(clients(parallel), clients(serial));
+        assert_eq!(parallel.stats(), serial.stats());
+        assert_eq!(parallel.transactions, serial.transactions);
+        assert_eq!(parallel.transfer_ids, serial.transfer_ids);
+        assert_eq!(parallel.rejected_duplicates(), serial.rejected_duplicates());
+        assert_eq!(parallel.orphan_mods(), serial.orphan_mods());
+        assert_eq!(parallel.client_mismatches(), serial.client_mismatches());
+    }
+
+    #[test]
+    fn apply_parallel_any_threads() {
+        let engine = || {
+            TransactionEngine::default()
+                .with_client_mismatch_policy(ClientMismatchPolicy::RecordAndReject)
+                .with_duplicate_tx_policy(DuplicateTxPolicy::Reject)
+        };
+        let mut serial = engine();
+        let applied = serial.apply_all(generated_rows(20_000));
+        for threads in 1..=8 {
+            let mut parallel = engine();
+            assert_eq!(
+                parallel.apply_parallel(generated_rows(20_000).into_iter(), threads),
+                applied
+            );
+            assert_same_as_serial(&parallel, &serial);
+        }
+    }
+
+    #[test]
+    fn apply_parallel_serial_fallback() {
+        let rows = || {
+            vec![
+                new_tx(1, 1, "5.0"),
+                new_tx(2, 3, "-1.0"), // rejected, unknown client
+                new_tx(3, 2, "5.0"),
+                new_tx(1, 2, "5.0"), // duplicate of a tx in the other worker
+                new_tx(2, 4, "1.0"), // not a duplicate, its first use was rejected
+                transfer_tx(4, 1, 2, "1.0"), // between the workers
+                new_tx(5, 4, "1.0"),
+                mod_tx(1, 1, Disputed),
+            ]
+        };
+        let engine =
+            || TransactionEngine::default().with_duplicate_tx_policy(DuplicateTxPolicy::Reject);
+        let mut serial = engine();
+        let applied = serial.apply_all(rows());
+        assert_eq!(applied, 6);
+        assert_eq!(serial.rejected_duplicates(), &[1]);
+        for threads in 1..=8 {
+            let mut parallel = engine();
+            assert_eq!(
+                parallel.apply_parallel(rows().into_iter(), threads),
+                applied
+            );
+            assert_same_as_serial(&parallel, &serial);
+        }
+    }
+
+    #[test]
+    fn recent_transactions() {
+        let mut engine = TransactionEngine::default().with_recent_transactions(2);
+        for tx in 1..=3 {
+            engine.apply(new_tx(tx, 1, "1.0")).unwrap();
+        }
+        // tx 1 is the oldest of 3, so it was evicted
+        assert_eq!(
+            engine.apply(mod_tx(1, 1, Disputed)),
+            Err(ApplyError::ModTargetNotFound)
+        );
+        assert_eq!(engine.orphan_mods(), &[(Some(1), 1)]);
+        engine.apply(mod_tx(2, 1, Disputed)).unwrap();
+        // disputed tx 2 is pinned, so 4 and 5 evict 3 and 4 instead
+        for tx in 4..=5 {
+            engine.apply(new_tx(tx, 1, "1.0")).unwrap();
+        }
+        engine.apply(new_tx(6, 1, "1.0")).unwrap();
+        assert!(!engine.transactions.contains_key(&3));
+        assert!(!engine.transactions.contains_key(&4));
+        engine.apply(mod_tx(2, 1, Chargeback)).unwrap();
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.total, dec("5.0"));
+        assert_eq!(client.held, dec("0"));
+        assert!(client.locked);
+        assert_eq!(engine.check_invariants(), Ok(()));
+        // charged back, so it's no longer pinned and is the most recent, 5 is evicted
+        assert!(engine.transactions.contains_key(&2));
+        assert!(!engine.transactions.contains_key(&5));
+        assert!(engine.transactions.contains_key(&6));
+    }
+
+    // stores that count how often the engine writes to them, standing in for an external backend
+    #[derive(Debug, Default, Clone)]
+    struct MockTransactions {
+        transactions: BTreeMap<u32, Transaction>,
+        writes: usize,
+    }
+
+    impl TransactionStore for MockTransactions {
+        fn get(&self, tx: &u32) -> Option<&Transaction> {
+            self.transactions.get(tx)
+        }
+
+        fn get_mut(&mut self, tx: &u32) -> Option<&mut Transaction> {
+            self.writes += 1;
+            self.transactions.get_mut(tx)
+        }
+
+        fn insert(&mut self, id: u32, tx: Transaction) -> Option<Transaction> {
+            self.writes += 1;
+            self.transactions.insert(id, tx)
+        }
+
+        fn values(&self) -> Box<dyn Iterator<Item = &Transaction> + '_> {
+            Box::new(self.transactions.values())
+        }
+
+        fn take_all(&mut self) -> Vec<Transaction> {
+            std::mem::take(&mut self.transactions)
+                .into_values()
+                .collect()
+        }
+
+        fn empty_like(&self) -> Self {
+            MockTransactions::default()
+        }
+    }
+
+    #[derive(Debug, Default, Clone)]
+    struct MockClients {
+        clients: BTreeMap<u16, Client>,
+        writes: usize,
+    }
+
+    impl ClientStore for MockClients {
+        type Values<'a> = std::collections::btree_map::Values<'a, u16, Client>;
+
+        fn get(&self, client: &u16) -> Option<&Client> {
+            self.clients.get(client)
+        }
+
+        fn get_mut(&mut self, client: &u16) -> Option<&mut Client> {
+            self.writes += 1;
+            self.clients.get_mut(client)
+        }
+
+        fn insert(&mut self, id: u16, client: Client) -> Option<Client> {
+            self.writes += 1;
+            self.clients.insert(id, client)
+        }
+
+        fn values(&self) -> Self::Values<'_> {
+            self.clients.values()
+        }
+
+        fn take_all(&mut self) -> Vec<Client> {
+            std::mem::take(&mut self.clients).into_values().collect()
+        }
+
+        fn empty_like(&self) -> Self {
+            MockClients::default()
+        }
+    }
+
+    #[test]
+    fn with_stores() {
+        let mut engine =
+            TransactionEngine::with_stores(MockTransactions::default(), MockClients::default());
+        let mut default = TransactionEngine::default();
+        let applied = engine.apply_all(generated_rows(2_000));
+        assert_eq!(default.apply_all(generated_rows(2_000)), applied);
+        assert_eq!(engine.check_invariants(), Ok(()));
+        let mut expected: Vec<&Client> = default.clients().collect();
+        expected.sort_by_key(|c| c.client);
+        assert!(engine.clients().eq(expected));
+        assert!(engine.transactions.writes >= engine.transactions.transactions.len());
+        assert!(engine.clients.writes >= engine.clients.clients.len());
+
+        // validate and apply_parallel only need the stores to be Clone and Send
+        let report = engine.validate(generated_rows(100).into_iter());
+        assert_eq!(
+            report.accepted(),
+            default.validate(generated_rows(100).into_iter()).accepted()
+        );
+        let rows = (2_000..2_100).map(|tx| new_tx(tx, (tx % 7) as u16, "1.0"));
+        assert_eq!(engine.apply_parallel(rows, 2), 100);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn with_capacity() {
+        let engine = TransactionEngine::with_capacity(1_000, 1_000_000);
+        assert!(engine.transactions.capacity().unwrap() >= 1_000);
+        assert!(engine.client_order.capacity() >= 1_000);
+        assert!(engine.client_order.capacity() < 1_000_000);
+        assert_eq!(
+            TransactionEngine::default().transactions.capacity(),
+            Some(0)
+        );
     }
 }
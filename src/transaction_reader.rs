@@ -1,33 +1,720 @@
-use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::num::{IntErrorKind, ParseIntError};
 use std::ops::MulAssign;
+use std::path::Path;
+use std::str::FromStr;
 
-use csv::{Reader, ReaderBuilder, Trim};
+use csv::{DeserializeErrorKind, ErrorKind, Reader, ReaderBuilder, StringRecord, Trim};
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
 
+use crate::log;
 use crate::*;
 
 pub struct TransactionReader<R> {
-    reader: Reader<R>,
+    reader: Reader<PeekReader<R>>,
+    options: ReaderOptions,
+    metadata: Option<FileMetadata>,
+}
+
+/// the SourceRecord rejection reason for a client or tx id that is all digits but too big for its type, as
+/// opposed to one that isn't a number at all
+pub const ID_OUT_OF_RANGE: &str = "id out of range";
+
+/// the rejection reason for a deposit or withdrawal over TransactionReaderBuilder::max_amount, as opposed to one
+/// that isn't a valid amount at all
+pub const AMOUNT_OVER_MAX: &str = "amount over max_amount";
+
+/// the rejection reason for a record whose type isn't one this reader knows, ie one added upstream later, as
+/// opposed to a record that's malformed
+pub const UNKNOWN_TYPE: &str = "unknown transaction type";
+
+/// parsed from an optional leading `# key=value key=value` line, ie `# version=2`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMetadata {
+    fields: Vec<(String, String)>,
+}
+
+impl FileMetadata {
+    fn parse(line: &str) -> Option<FileMetadata> {
+        let line = line.trim().strip_prefix('#')?;
+        let fields = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter_map(|field| field.split_once('='))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Some(FileMetadata { fields })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn version(&self) -> Option<u32> {
+        self.get("version")?.parse().ok()
+    }
+}
+
+// reads back bytes we had to peek at before the rest of inner
+struct PeekReader<R> {
+    peeked: Vec<u8>,
+    pos: usize,
+    inner: R,
+    bom_checked: bool,
+}
+
+impl<R: Read> PeekReader<R> {
+    fn new(inner: R) -> PeekReader<R> {
+        PeekReader {
+            peeked: Vec::new(),
+            pos: 0,
+            inner,
+            bom_checked: false,
+        }
+    }
+
+    // reads up to and including the next newline, it'll still be read again unless consume_peeked is called
+    fn peek_line(&mut self) -> std::io::Result<&[u8]> {
+        self.skip_bom()?;
+        let mut byte = [0u8];
+        while !self.peeked.ends_with(b"\n") && self.inner.read(&mut byte)? == 1 {
+            self.peeked.push(byte[0]);
+        }
+        Ok(&self.peeked)
+    }
+
+    fn consume_peeked(&mut self) {
+        self.pos = self.peeked.len();
+    }
+
+    // drops a leading UTF-8 byte order mark, as Windows tools like Excel write, on the first read so nothing is
+    // read before then, otherwise csv keeps it as part of the first header, which then doesn't match `type`
+    fn skip_bom(&mut self) -> std::io::Result<()> {
+        const BOM: &[u8] = b"\xEF\xBB\xBF";
+        if self.bom_checked {
+            return Ok(());
+        }
+        self.bom_checked = true;
+        let mut byte = [0u8];
+        while self.peeked.len() < BOM.len()
+            && BOM.starts_with(&self.peeked)
+            && self.inner.read(&mut byte)? == 1
+        {
+            self.peeked.push(byte[0]);
+        }
+        if self.peeked == BOM {
+            self.peeked.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for PeekReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.skip_bom()?;
+        if self.pos < self.peeked.len() {
+            let len = buf.len().min(self.peeked.len() - self.pos);
+            buf[..len].copy_from_slice(&self.peeked[self.pos..self.pos + len]);
+            self.pos += len;
+            return Ok(len);
+        }
+        self.inner.read(buf)
+    }
+}
+
+// options that change how a raw row is interpreted, shared by the builder and reader
+#[derive(Debug, Clone)]
+pub(crate) struct ReaderOptions {
+    amount_is_minor_units: bool,
+    direction_column: bool,
+    decimal_places: u32,
+    allow_zero_amount: bool,
+    asset_column: bool,
+    amount_strip: String, // characters removed from amounts before they're parsed, empty for strict amounts
+    optional_mod_client: bool,
+    trust_original_client: bool,
+    max_amount: Option<Decimal>,
+    allow_partial_disputes: bool,
+    transfers: bool,
+    raw_amounts: bool,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> Self {
+        ReaderOptions {
+            amount_is_minor_units: false,
+            direction_column: false,
+            decimal_places: DECIMAL_PLACES,
+            allow_zero_amount: false,
+            asset_column: false,
+            amount_strip: String::new(),
+            optional_mod_client: false,
+            trust_original_client: false,
+            max_amount: None,
+            allow_partial_disputes: false,
+            transfers: false,
+            raw_amounts: false,
+        }
+    }
+}
+
+// the default used by from_path, same as std's BufReader
+// benches/read_buffering.rs shows no real difference between 8KiB, 64KiB, 1MiB, or no BufReader at all on a
+// 1M row file, because csv::Reader keeps its own internal buffer, so there's no reason to go bigger
+pub const DEFAULT_BUFFER_CAPACITY: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub struct TransactionReaderBuilder {
+    options: ReaderOptions,
+    buffer_capacity: usize,
+    metadata_line: bool,
+    delimiter: u8,
+    trim: Trim,
+    has_headers: bool,
+}
+
+impl Default for TransactionReaderBuilder {
+    fn default() -> Self {
+        TransactionReaderBuilder {
+            options: ReaderOptions::default(),
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            metadata_line: false,
+            delimiter: b',',
+            trim: Trim::All,
+            has_headers: true,
+        }
+    }
+}
+
+impl TransactionReaderBuilder {
+    pub fn new() -> TransactionReaderBuilder {
+        TransactionReaderBuilder::default()
+    }
+
+    /// capacity of the BufReader from_path wraps the file in
+    pub fn buffer_capacity(&mut self, capacity: usize) -> &mut TransactionReaderBuilder {
+        self.buffer_capacity = capacity;
+        self
+    }
+
+    /// the number of places past the decimal amounts may have, amounts with more are rejected, and the rest are
+    /// rescaled to exactly this many, defaults to DECIMAL_PLACES, and must be at most 28
+    /// the engine should be given the same with TransactionEngine::with_decimal_places
+    pub fn decimal_places(&mut self, places: u32) -> &mut TransactionReaderBuilder {
+        self.options.decimal_places = places;
+        self
+    }
+
+    /// when set, amounts are integers in minor units, ie 150 means 1.50 if decimal_places is 2
+    /// amounts with a decimal point are rejected in this mode
+    pub fn amount_is_minor_units(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.options.amount_is_minor_units = yes;
+        self
+    }
+
+    /// when set, deposits and withdrawals come from a `direction` column of `credit` or `debit` plus an always
+    /// positive amount, the `type` column may be left empty or omitted for those rows, but if present must agree
+    /// dispute/resolve/chargeback rows still use `type`, rows missing a direction are rejected
+    pub fn direction_column(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.options.direction_column = yes;
+        self
+    }
+
+    /// when set, deposits and withdrawals of 0 are valid rows instead of being rejected, for ledgers that record
+    /// zero value bookkeeping entries, the engine applies them like any other, so they change no balance but
+    /// still use up their tx id, and a zero deposit for a new client still creates it
+    pub fn allow_zero_amount(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.options.allow_zero_amount = yes;
+        self
+    }
+
+    /// when set, an optional `asset` column says which asset each row is in, for MultiAssetEngine, rows without one
+    /// are in the implicit asset, the empty string, read them with TransactionReader::valid_records_with_asset
+    /// when not set, the column is ignored and every row is in the implicit asset
+    pub fn asset_column(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.options.asset_column = yes;
+        self
+    }
+
+    /// characters removed from amounts before they're parsed, ie `"$,"` so `"$1,234.5678"` reads as 1234.5678,
+    /// defaults to none so amounts are strict, grouping isn't checked, `1,2,3` would read as 123, and an amount
+    /// with a grouping comma has to be quoted when `,` is also the delimiter
+    /// records_with_source and records_raw_with_errors still report the amount as it was in the file
+    pub fn lenient_amount(&mut self, strip: &str) -> &mut TransactionReaderBuilder {
+        self.options.amount_strip = strip.to_string();
+        self
+    }
+
+    /// when set, dispute/resolve/chargeback rows may leave client empty, for upstreams that don't repeat it, and the
+    /// engine applies those to the original transaction's client, a client that is there is still checked against it
+    /// deposits, withdrawals, unlocks and transfers always need a client
+    pub fn optional_mod_client(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.options.optional_mod_client = yes;
+        self
+    }
+
+    /// when set, the client of dispute/resolve/chargeback rows is ignored, empty or not, and the engine applies them
+    /// to the original transaction's client without checking they match
+    /// off by default, so a dispute naming another client's transaction is rejected as possible tampering
+    pub fn trust_original_client(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.options.trust_original_client = yes;
+        self
+    }
+
+    /// deposits and withdrawals over this amount are rejected with AMOUNT_OVER_MAX, a business limit well below
+    /// what the engine can represent, an amount exactly at the limit is accepted, defaults to no limit
+    pub fn max_amount(&mut self, max: Decimal) -> &mut TransactionReaderBuilder {
+        self.options.max_amount = Some(max);
+        self
+    }
+
+    /// when set, a dispute row may have an amount, to dispute and hold only that much of a deposit, a resolve or
+    /// chargeback of it then releases or charges back only that much, the amount follows the same rules as a
+    /// deposit's, resolve and chargeback rows still can't have one
+    pub fn allow_partial_disputes(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.options.allow_partial_disputes = yes;
+        self
+    }
+
+    /// when set, deposit and withdrawal amounts with more than decimal_places places are rounded to decimal_places,
+    /// half to even, instead of rejected, and the amount as read is kept as Transaction::raw_amount for audit,
+    /// balances are still computed with the rounded amount, an amount that rounds to 0 is rejected like 0 is
+    pub fn raw_amounts(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.options.raw_amounts = yes;
+        self
+    }
+
+    /// when set, a `transfer` row moves its amount from client's available balance to the client in an extra
+    /// `to_client` column, which can't be client itself, the amount follows the same rules as a deposit's
+    /// when not set, `transfer` is an unknown type and rejected with UNKNOWN_TYPE, and to_client is ignored
+    pub fn transfers(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.options.transfers = yes;
+        self
+    }
+
+    /// when set, a first line starting with `#` before the CSV header is parsed into FileMetadata instead of being
+    /// read as the header, if the first line doesn't start with `#` it's read as the header as usual
+    pub fn metadata_line(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.metadata_line = yes;
+        self
+    }
+
+    /// the field delimiter, defaults to `,`, ie `\t` for TSV
+    pub fn delimiter(&mut self, delimiter: u8) -> &mut TransactionReaderBuilder {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// whitespace trimming of headers and fields, defaults to Trim::All so `deposit, 1, 1, 1.0` parses
+    pub fn trim(&mut self, trim: Trim) -> &mut TransactionReaderBuilder {
+        self.trim = trim;
+        self
+    }
+
+    /// whether the first line (after any metadata line) is a header, defaults to true
+    pub fn has_headers(&mut self, yes: bool) -> &mut TransactionReaderBuilder {
+        self.has_headers = yes;
+        self
+    }
+
+    /// opens the file at path, wrapped in a BufReader of buffer_capacity, or reads stdin if path is `-`
+    pub fn from_path<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> std::io::Result<TransactionReader<Box<dyn Read>>> {
+        let path = path.as_ref();
+        let rdr: Box<dyn Read> = if path == Path::new("-") {
+            // stdin is already buffered
+            Box::new(std::io::stdin())
+        } else {
+            let file = File::open(path)?;
+            Box::new(BufReader::with_capacity(self.buffer_capacity, file))
+        };
+        Ok(self.from_reader(rdr))
+    }
+
+    /// rdr is read as-is, csv::Reader buffers internally so wrapping it in a BufReader first isn't required
+    pub fn from_reader<R: std::io::Read>(&self, rdr: R) -> TransactionReader<R> {
+        let mut rdr = PeekReader::new(rdr);
+        let mut metadata = None;
+        if self.metadata_line {
+            // if this fails, the csv reader will run into the same error and report it
+            if let Ok(line) = rdr.peek_line() {
+                metadata = FileMetadata::parse(&String::from_utf8_lossy(line));
+                if metadata.is_some() {
+                    rdr.consume_peeked();
+                }
+            }
+        }
+        TransactionReader {
+            reader: ReaderBuilder::new()
+                .delimiter(self.delimiter)
+                .trim(self.trim)
+                .has_headers(self.has_headers)
+                .from_reader(rdr),
+            options: self.options.clone(),
+            metadata,
+        }
+    }
+}
+
+impl TransactionReader<Box<dyn Read>> {
+    /// opens the file at path, or reads stdin if path is `-`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        TransactionReaderBuilder::new().from_path(path)
+    }
 }
 
 impl<R: std::io::Read> TransactionReader<R> {
     pub fn from_reader(rdr: R) -> TransactionReader<R> {
-        TransactionReader {
-            reader: ReaderBuilder::new().trim(Trim::All).from_reader(rdr),
+        TransactionReaderBuilder::new().from_reader(rdr)
+    }
+
+    /// the leading metadata line, if TransactionReaderBuilder::metadata_line was set and the file had one
+    pub fn metadata(&self) -> Option<&FileMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// every record, valid or not, along with its line number, raw fields, and why it was rejected if it was
+    pub fn records_with_source(&mut self) -> SourceRecordsIter<'_, R> {
+        SourceRecordsIter {
+            records: RawRecords::new(&mut self.reader, &self.options),
+            options: &self.options,
+        }
+    }
+
+    /// the header fields, None if has_headers is off or the header couldn't be read
+    pub fn headers(&mut self) -> Option<Vec<String>> {
+        if !self.reader.has_headers() {
+            return None;
+        }
+        self.reader
+            .headers()
+            .ok()
+            .map(|headers| headers.iter().map(String::from).collect())
+    }
+
+    /// every record that deserializes, along with the reason valid_records would drop it, if it would
+    /// records that don't deserialize at all have no RawTransactionRow so are skipped, records_with_source has them
+    pub fn records_raw_with_errors(&mut self) -> RawRecordsWithErrorsIter<'_, R> {
+        RawRecordsWithErrorsIter {
+            records: RawRecords::new(&mut self.reader, &self.options),
+            options: &self.options,
+        }
+    }
+
+    /// every record, with the reason and line of each one valid_records would drop
+    pub fn records_with_errors(&mut self) -> RecordsWithErrorsIter<'_, R> {
+        RecordsWithErrorsIter {
+            records: RawRecords::new(&mut self.reader, &self.options),
+            options: &self.options,
+        }
+    }
+
+    /// records_with_errors, but stopping after the first error, for strict validation where one bad line fails
+    /// the whole file, ie `strict_records().collect::<Result<Vec<_>, _>>()`
+    pub fn strict_records(&mut self) -> StrictRecordsIter<'_, R> {
+        StrictRecordsIter {
+            records: self.records_with_errors(),
+            failed: false,
         }
     }
 
     // in a real application, you wouldn't just silently discard invalid records, but here we will
-    pub fn valid_records(&mut self) -> ValidRecordsIter<R> {
+    pub fn valid_records(&mut self) -> ValidRecordsIter<'_, R> {
         ValidRecordsIter {
-            deserialize_records: self.reader.deserialize(),
+            records: RawRecords::new(&mut self.reader, &self.options),
+            options: &self.options,
+        }
+    }
+
+    /// valid_records, along with the asset each is in, see TransactionReaderBuilder::asset_column
+    pub fn valid_records_with_asset(&mut self) -> ValidRecordsWithAssetIter<'_, R> {
+        ValidRecordsWithAssetIter {
+            records: RawRecords::new(&mut self.reader, &self.options),
+            options: &self.options,
+        }
+    }
+
+    /// the valid records of each reader in turn, as one stream, ie for transactions split across daily files
+    /// each reader has its own header, and tx ids are only unique across them as far as the engine checks
+    pub fn chain(
+        readers: &mut [TransactionReader<R>],
+    ) -> impl Iterator<Item = TransactionRow> + '_ {
+        readers.iter_mut().flat_map(|reader| reader.valid_records())
+    }
+}
+
+/// a record along with where it came from, for reporting on rejected records
+#[derive(Debug)]
+pub struct SourceRecord {
+    pub line: u64,           // 1-based line number in the input, 0 if unknown
+    pub fields: Vec<String>, // raw fields as read, empty if the line couldn't be read as a record
+    pub client: Option<u16>, // None if it couldn't be parsed
+    pub tx: Option<u32>,     // None if it couldn't be parsed
+    pub row: Result<TransactionRow, String>, // Err has the reason the record was rejected
+}
+
+// reads records one at a time keeping track of where each came from, shared by the iterators that report errors
+struct RawRecords<'r, R: 'r> {
+    reader: &'r mut Reader<PeekReader<R>>,
+    headers: Option<StringRecord>,
+    record: StringRecord, // the fields of the most recently read record
+    // the amount column, if lenient_amount has characters to strip from it
+    amount_column: Option<usize>,
+    amount_strip: &'r str,
+    done: bool,
+}
+
+enum RawRecord {
+    // the line couldn't be read as a record at all
+    Unreadable(csv::Error),
+    Read {
+        line: u64,
+        raw: Result<RawTransactionRow, csv::Error>,
+    },
+}
+
+impl<'r, R: std::io::Read> RawRecords<'r, R> {
+    fn new(reader: &'r mut Reader<PeekReader<R>>, options: &'r ReaderOptions) -> Self {
+        let headers = if reader.has_headers() {
+            // if this fails the first read_record will run into the same error and report it
+            reader.headers().ok().cloned()
+        } else {
+            None
+        };
+        let amount_column = match &headers {
+            _ if options.amount_strip.is_empty() => None,
+            Some(headers) => headers.iter().position(|h| h.trim() == "amount"),
+            None => Some(3), // deserialized by position, see RawTransactionRow
+        };
+        RawRecords {
+            reader,
+            headers,
+            record: StringRecord::new(),
+            amount_column,
+            amount_strip: &options.amount_strip,
+            done: false,
+        }
+    }
+
+    fn next_raw(&mut self) -> Option<RawRecord> {
+        if self.done {
+            return None;
+        }
+        match self.reader.read_record(&mut self.record) {
+            Ok(false) => None,
+            Err(e) => {
+                // an io error will just repeat forever, anything else only affects this record
+                self.done = e.is_io_error();
+                Some(RawRecord::Unreadable(e))
+            }
+            Ok(true) => Some(RawRecord::Read {
+                line: self.record.position().map_or(0, |p| p.line()),
+                raw: match self.lenient_record() {
+                    Some(record) => record.deserialize(self.headers.as_ref()),
+                    None => self.record.deserialize(self.headers.as_ref()),
+                },
+            }),
+        }
+    }
+
+    // a copy of record with amount_strip removed from the amount, None if there's nothing to remove
+    fn lenient_record(&self) -> Option<StringRecord> {
+        let column = self.amount_column?;
+        let strip = |c: char| self.amount_strip.contains(c);
+        if !self.record.get(column)?.contains(strip) {
+            return None;
+        }
+        let mut record: StringRecord = self
+            .record
+            .iter()
+            .enumerate()
+            .map(|(i, field)| match i == column {
+                true => field.replace(strip, ""),
+                false => field.to_string(),
+            })
+            .collect();
+        record.set_position(self.record.position().cloned());
+        Some(record)
+    }
+}
+
+pub struct SourceRecordsIter<'r, R: 'r> {
+    records: RawRecords<'r, R>,
+    options: &'r ReaderOptions,
+}
+
+impl<'r, R: std::io::Read> Iterator for SourceRecordsIter<'r, R> {
+    type Item = SourceRecord;
+
+    fn next(&mut self) -> Option<SourceRecord> {
+        match self.records.next_raw()? {
+            RawRecord::Unreadable(e) => Some(SourceRecord {
+                line: e.position().map_or(0, |p| p.line()),
+                fields: Vec::new(),
+                client: None,
+                tx: None,
+                row: Err(e.to_string()),
+            }),
+            RawRecord::Read { line, raw } => {
+                let fields = self.records.record.iter().map(String::from).collect();
+                match raw {
+                    Err(e) => Some(SourceRecord {
+                        line,
+                        fields,
+                        client: None,
+                        tx: None,
+                        row: Err(rejection_reason(e)),
+                    }),
+                    Ok(raw) => Some(SourceRecord {
+                        line,
+                        fields,
+                        client: raw.client,
+                        tx: Some(raw.tx),
+                        row: raw
+                            .normalize(self.options)
+                            .and_then(|row| row.into_row(self.options))
+                            .map_err(String::from),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+/// why a record was dropped by valid_records, along with the 1-based line it was on
+#[derive(Debug)]
+pub enum RecordError {
+    /// the record parsed, but isn't a valid transaction
+    Invalid { line: u64, reason: &'static str },
+    /// the record couldn't be read or parsed
+    Csv { line: u64, err: csv::Error },
+}
+
+impl RecordError {
+    /// 1-based line number in the input, 0 if csv couldn't tell
+    pub fn line(&self) -> u64 {
+        match self {
+            RecordError::Invalid { line, .. } | RecordError::Csv { line, .. } => *line,
+        }
+    }
+}
+
+impl std::fmt::Display for RecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordError::Invalid { line, reason } => write!(f, "line {}: {}", line, reason),
+            RecordError::Csv { line, err } => write!(f, "line {}: {}", line, err),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+pub struct RecordsWithErrorsIter<'r, R: 'r> {
+    records: RawRecords<'r, R>,
+    options: &'r ReaderOptions,
+}
+
+impl<'r, R: std::io::Read> Iterator for RecordsWithErrorsIter<'r, R> {
+    type Item = Result<TransactionRow, RecordError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.records.next_raw()? {
+            RawRecord::Unreadable(err) | RawRecord::Read { raw: Err(err), .. } => {
+                Some(Err(csv_record_error(err)))
+            }
+            RawRecord::Read { line, raw: Ok(raw) } => Some(
+                raw.normalize(self.options)
+                    .and_then(|row| row.into_row(self.options))
+                    .map_err(|reason| RecordError::Invalid { line, reason }),
+            ),
+        }
+    }
+}
+
+pub struct StrictRecordsIter<'r, R: 'r> {
+    records: RecordsWithErrorsIter<'r, R>,
+    failed: bool,
+}
+
+impl<'r, R: std::io::Read> Iterator for StrictRecordsIter<'r, R> {
+    type Item = Result<TransactionRow, RecordError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let record = self.records.next()?;
+        self.failed = record.is_err();
+        Some(record)
+    }
+}
+
+pub struct RawRecordsWithErrorsIter<'r, R: 'r> {
+    records: RawRecords<'r, R>,
+    options: &'r ReaderOptions,
+}
+
+impl<'r, R: std::io::Read> Iterator for RawRecordsWithErrorsIter<'r, R> {
+    type Item = (RawTransactionRow, Option<&'static str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let RawRecord::Read { raw: Ok(raw), .. } = self.records.next_raw()? {
+                let reason = raw
+                    .clone()
+                    .normalize(self.options)
+                    .and_then(|row| row.into_row(self.options))
+                    .err();
+                return Some((raw, reason));
+            }
+        }
+    }
+}
+
+fn csv_record_error(err: csv::Error) -> RecordError {
+    RecordError::Csv {
+        line: err.position().map_or(0, |p| p.line()),
+        err,
+    }
+}
+
+// valid_records drops these silently unless a logger is installed
+fn log_invalid(line: u64, (tx, client): (u32, Option<u16>), reason: &str) {
+    match client {
+        Some(client) => log::debug!(
+            "line {}: skipped tx {} for client {}: {}",
+            line,
+            tx,
+            client,
+            reason
+        ),
+        None => log::debug!("line {}: skipped tx {}: {}", line, tx, reason),
+    }
+}
+
+// ID_OUT_OF_RANGE on its own so it can be matched on, otherwise csv's full description of the error
+fn rejection_reason(e: csv::Error) -> String {
+    if let ErrorKind::Deserialize { err, .. } = e.kind() {
+        if let DeserializeErrorKind::Message(msg) = err.kind() {
+            if msg == ID_OUT_OF_RANGE {
+                return ID_OUT_OF_RANGE.to_string();
+            }
         }
     }
+    e.to_string()
 }
 
 pub struct ValidRecordsIter<'r, R: 'r> {
-    deserialize_records: csv::DeserializeRecordsIter<'r, R, RawTransactionRow>,
+    records: RawRecords<'r, R>,
+    options: &'r ReaderOptions,
 }
 
 impl<'r, R: std::io::Read> Iterator for ValidRecordsIter<'r, R> {
@@ -35,63 +722,331 @@ impl<'r, R: std::io::Read> Iterator for ValidRecordsIter<'r, R> {
 
     fn next(&mut self) -> Option<TransactionRow> {
         loop {
-            match self.deserialize_records.next() {
-                None => return None,
-                Some(Ok(transaction_row)) => match transaction_row.try_into() {
-                    Ok(transaction_row) => return Some(transaction_row),
-                    Err(_) => continue,
-                },
-                _ => continue, // move to next on Err
+            match self.records.next_raw()? {
+                RawRecord::Read {
+                    line,
+                    raw: Ok(transaction_row),
+                } => {
+                    let ids = (transaction_row.tx, transaction_row.client);
+                    match transaction_row
+                        .normalize(self.options)
+                        .and_then(|row| row.into_row(self.options))
+                    {
+                        Ok(transaction_row) => return Some(transaction_row),
+                        Err(reason) => log_invalid(line, ids, reason),
+                    }
+                }
+                RawRecord::Unreadable(err) | RawRecord::Read { raw: Err(err), .. } => {
+                    log::debug!("{}", csv_record_error(err))
+                    // move to next on Err
+                }
             }
         }
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
+pub struct ValidRecordsWithAssetIter<'r, R: 'r> {
+    records: RawRecords<'r, R>,
+    options: &'r ReaderOptions,
+}
+
+impl<'r, R: std::io::Read> Iterator for ValidRecordsWithAssetIter<'r, R> {
+    type Item = (AssetId, TransactionRow);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.records.next_raw()? {
+                RawRecord::Read {
+                    line,
+                    raw: Ok(mut raw),
+                } => {
+                    let asset = match raw.asset.take() {
+                        Some(asset) if self.options.asset_column => asset,
+                        _ => AssetId::new(),
+                    };
+                    let ids = (raw.tx, raw.client);
+                    match raw
+                        .normalize(self.options)
+                        .and_then(|row| row.into_row(self.options))
+                    {
+                        Ok(transaction_row) => return Some((asset, transaction_row)),
+                        Err(reason) => log_invalid(line, ids, reason),
+                    }
+                }
+                RawRecord::Unreadable(err) | RawRecord::Read { raw: Err(err), .. } => {
+                    log::debug!("{}", csv_record_error(err))
+                    // move to next on Err
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 enum RawTransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Unlock,
+    Transfer,        // only valid in transfers mode
+    Unknown(String), // kept so the record can still be deserialized, and written back as it was
+}
+
+impl<'de> Deserialize<'de> for RawTransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(match name.as_str() {
+            "deposit" => RawTransactionType::Deposit,
+            "withdrawal" => RawTransactionType::Withdrawal,
+            "dispute" => RawTransactionType::Dispute,
+            "resolve" => RawTransactionType::Resolve,
+            "chargeback" => RawTransactionType::Chargeback,
+            "unlock" => RawTransactionType::Unlock,
+            "transfer" => RawTransactionType::Transfer,
+            _ => RawTransactionType::Unknown(name),
+        })
+    }
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
-struct RawTransactionRow {
-    r#type: RawTransactionType,
-    client: u16,
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum RawDirection {
+    Credit,
+    Debit,
+}
+
+/// a record as deserialized, before any validation, see TransactionReader::records_raw_with_errors
+// without headers, records are deserialized by position, so the field order here must stay the column order
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct RawTransactionRow {
+    #[serde(default)]
+    r#type: Option<RawTransactionType>, // only optional in direction_column mode
+    #[serde(default, deserialize_with = "deserialize_optional_id")]
+    client: Option<u16>, // only optional on dispute/resolve/chargeback rows in optional_mod_client mode
+    #[serde(deserialize_with = "deserialize_id")]
     tx: u32,
+    #[serde(default, deserialize_with = "deserialize_amount")]
     amount: Option<Decimal>,
+    #[serde(default)]
+    direction: Option<RawDirection>, // only read in direction_column mode
+    #[serde(default)]
+    asset: Option<AssetId>, // only read in asset_column mode
+    #[serde(default, deserialize_with = "deserialize_optional_id")]
+    to_client: Option<u16>, // only read on transfer rows in transfers mode
+    #[serde(default, deserialize_with = "deserialize_optional_id")]
+    op_id: Option<u64>, // only kept on dispute/resolve/chargeback rows, see TransactionEngine::with_mod_dedup
+}
+
+// parses ids the same as serde would, leading zeros and all, but reports overflow as ID_OUT_OF_RANGE
+fn deserialize_id<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = ParseIntError>,
+{
+    let id = String::deserialize(deserializer)?;
+    parse_id(&id).map_err(D::Error::custom)
+}
+
+// deserialize_id, but an empty field is None
+fn deserialize_optional_id<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = ParseIntError>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(id) => parse_id(&id).map(Some).map_err(D::Error::custom),
+    }
+}
+
+fn parse_id<T: FromStr<Err = ParseIntError>>(id: &str) -> Result<T, String> {
+    id.parse().map_err(|e: ParseIntError| match e.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => ID_OUT_OF_RANGE.to_string(),
+        _ => e.to_string(),
+    })
+}
+
+// parses amounts straight from the text, left to csv they go through f64 and big ones lose digits, so
+// 7922816251426433759354395.0 would become 7922816251426434000000000
+// surrounding whitespace is trimmed first, some exporters quote and pad numbers, ie `"  1.50 "`, which csv's trim
+// doesn't reach with Trim::None, an amount that's only whitespace is the same as an empty one
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(amount) if amount.trim().is_empty() => Ok(None),
+        Some(amount) => Decimal::from_str(amount.trim())
+            .map(Some)
+            .map_err(D::Error::custom),
+    }
+}
+
+// a deposit, withdrawal or partial dispute amount rescaled to decimal_places, it cannot be 0 (unless
+// allow_zero_amount), negative, or have more than the allowed number of decimal_places
+fn valid_amount(amount: Option<Decimal>, options: &ReaderOptions) -> Result<Decimal, &'static str> {
+    let decimal_places = options.decimal_places;
+    let mut amount = match amount {
+        Some(amount)
+            if amount.scale() <= decimal_places
+                && (!amount.is_zero() || options.allow_zero_amount)
+                && !amount.is_sign_negative() =>
+        {
+            amount
+        }
+        _ => return Err("missing or invalid amount"),
+    };
+    amount.rescale(decimal_places);
+    if amount.scale() != decimal_places {
+        // rescale stops short instead of failing when the extra places would need more than
+        // 96 bits, ie a value near Decimal::MAX
+        return Err("amount out of representable range");
+    }
+    if options.max_amount.is_some_and(|max| amount > max) {
+        return Err(AMOUNT_OVER_MAX);
+    }
+    Ok(amount)
 }
 
-impl TryInto<TransactionRow> for RawTransactionRow {
-    type Error = &'static str; // we aren't handling these anyway, real production code would and would need a better type
+impl RawTransactionRow {
+    /// type, client, tx and amount as they'd be written back to CSV, type and amount are empty if they were
+    /// missing, direction and to_client aren't included
+    pub fn fields(&self) -> [String; 4] {
+        let r#type = match &self.r#type {
+            None => "",
+            Some(RawTransactionType::Deposit) => "deposit",
+            Some(RawTransactionType::Withdrawal) => "withdrawal",
+            Some(RawTransactionType::Dispute) => "dispute",
+            Some(RawTransactionType::Resolve) => "resolve",
+            Some(RawTransactionType::Chargeback) => "chargeback",
+            Some(RawTransactionType::Unlock) => "unlock",
+            Some(RawTransactionType::Transfer) => "transfer",
+            Some(RawTransactionType::Unknown(name)) => name,
+        };
+        [
+            r#type.to_string(),
+            self.client.map(|c| c.to_string()).unwrap_or_default(),
+            self.tx.to_string(),
+            self.amount.map(|a| a.to_string()).unwrap_or_default(),
+        ]
+    }
+
+    // applies reader options that change how the raw fields are interpreted, before into_row validates them
+    pub(crate) fn normalize(
+        mut self,
+        options: &ReaderOptions,
+    ) -> Result<RawTransactionRow, &'static str> {
+        if options.amount_is_minor_units {
+            if let Some(amount) = self.amount {
+                if amount.scale() != 0 {
+                    return Err("amount must be an integer number of minor units");
+                }
+                self.amount = Some(Decimal::from_i128_with_scale(
+                    amount.mantissa(),
+                    options.decimal_places,
+                ));
+            }
+        }
+        if options.direction_column {
+            match self.r#type {
+                Some(RawTransactionType::Dispute)
+                | Some(RawTransactionType::Resolve)
+                | Some(RawTransactionType::Chargeback)
+                | Some(RawTransactionType::Unlock)
+                | Some(RawTransactionType::Transfer)
+                | Some(RawTransactionType::Unknown(_)) => {}
+                r#type => {
+                    let from_direction = match self.direction {
+                        None => return Err("missing direction"),
+                        Some(RawDirection::Credit) => RawTransactionType::Deposit,
+                        Some(RawDirection::Debit) => RawTransactionType::Withdrawal,
+                    };
+                    if r#type.is_some_and(|r#type| r#type != from_direction) {
+                        return Err("type does not match direction");
+                    }
+                    self.r#type = Some(from_direction);
+                }
+            }
+        }
+        Ok(self)
+    }
 
-    fn try_into(self) -> Result<TransactionRow, Self::Error> {
-        match self.r#type {
+    // validates the row, rescaling the amount to decimal_places
+    pub(crate) fn into_row(self, options: &ReaderOptions) -> Result<TransactionRow, &'static str> {
+        let r#type = match self.r#type {
+            None => return Err("missing type"),
+            Some(RawTransactionType::Unknown(_)) => return Err(UNKNOWN_TYPE),
+            Some(RawTransactionType::Transfer) if !options.transfers => return Err(UNKNOWN_TYPE),
+            Some(r#type) => r#type,
+        };
+        let is_mod = matches!(
+            r#type,
+            RawTransactionType::Dispute
+                | RawTransactionType::Resolve
+                | RawTransactionType::Chargeback
+        );
+        if self.client.is_none()
+            && !(is_mod && (options.optional_mod_client || options.trust_original_client))
+        {
+            return Err("missing client");
+        }
+        // the engine applies a dispute/resolve/chargeback without a client to the original transaction's client
+        let mod_client = if options.trust_original_client {
+            None
+        } else {
+            self.client
+        };
+        let client = self.client.unwrap_or_default(); // only read by row types that always have one
+        match r#type {
             RawTransactionType::Deposit | RawTransactionType::Withdrawal => {
-                if let Some(mut amount) = self.amount {
-                    // amount cannot be 0, negative, or have more than the allowed number of DECIMAL_PLACES
-                    if amount.scale() <= DECIMAL_PLACES
-                        && !amount.is_zero()
-                        && !amount.is_sign_negative()
-                    {
-                        // valid amount, so valid deposit or withdrawal
-                        amount.rescale(DECIMAL_PLACES);
-                        if self.r#type == RawTransactionType::Withdrawal {
-                            // a withdrawal is just a negative deposit
-                            amount.mul_assign(Decimal::NEGATIVE_ONE);
+                // valid amount, so valid deposit or withdrawal
+                let (mut amount, mut raw_amount) = match self.amount {
+                    Some(raw) if options.raw_amounts => (
+                        valid_amount(Some(raw.round_dp(options.decimal_places)), options)?,
+                        Some(raw),
+                    ),
+                    amount => (valid_amount(amount, options)?, None),
+                };
+                let kind = if r#type == RawTransactionType::Withdrawal {
+                    // a withdrawal is just a negative deposit, but its kind is kept so it never has to be inferred
+                    // 0 stays positive, -0 would look negative to anything checking the sign
+                    if !amount.is_zero() {
+                        amount.mul_assign(Decimal::NEGATIVE_ONE);
+                    }
+                    if let Some(raw) = &mut raw_amount {
+                        if !raw.is_zero() {
+                            raw.mul_assign(Decimal::NEGATIVE_ONE);
                         }
-                        return Ok(TransactionRow::New(Transaction {
-                            tx: self.tx,
-                            client: self.client,
-                            amount,
-                            state: Resolved,
-                        }));
                     }
-                }
-                Err("missing or invalid amount")
+                    TransactionKind::Withdrawal
+                } else {
+                    TransactionKind::Deposit
+                };
+                Ok(TransactionRow::New(Transaction {
+                    tx: self.tx,
+                    client,
+                    kind,
+                    amount,
+                    raw_amount,
+                    state: Resolved,
+                    held_amount: Decimal::ZERO,
+                }))
+            }
+            RawTransactionType::Dispute if options.allow_partial_disputes => {
+                let amount = match self.amount {
+                    None => None,
+                    amount => Some(valid_amount(amount, options)?),
+                };
+                Ok(TransactionRow::Mod(TransactionMod {
+                    tx: self.tx,
+                    client: mod_client,
+                    state: Disputed,
+                    amount,
+                    op_id: self.op_id,
+                }))
             }
             RawTransactionType::Dispute
             | RawTransactionType::Resolve
@@ -99,28 +1054,57 @@ impl TryInto<TransactionRow> for RawTransactionRow {
                 Some(_) => Err("amount provided for Dispute/Resolve/Chargeback and not allowed"),
                 None => Ok(TransactionRow::Mod(TransactionMod {
                     tx: self.tx,
-                    client: self.client,
-                    state: match self.r#type {
+                    client: mod_client,
+                    state: match r#type {
                         RawTransactionType::Dispute => Disputed,
                         RawTransactionType::Resolve => Resolved,
                         RawTransactionType::Chargeback => Chargeback,
                         _ => unreachable!("impossible to reach this due to outer match"),
                     },
+                    amount: None,
+                    op_id: self.op_id,
+                })),
+            },
+            // account level, so tx isn't checked against anything
+            RawTransactionType::Unlock => match self.amount {
+                Some(_) => Err("amount provided for Unlock and not allowed"),
+                None => Ok(TransactionRow::Unlock(TransactionUnlock {
+                    tx: self.tx,
+                    client,
                 })),
             },
+            RawTransactionType::Transfer => match self.to_client {
+                None => Err("missing to_client"),
+                Some(to_client) if to_client == client => Err("transfer to the same client"),
+                Some(to_client) => Ok(TransactionRow::Transfer(TransactionTransfer {
+                    tx: self.tx,
+                    client,
+                    to_client,
+                    amount: valid_amount(self.amount, options)?,
+                })),
+            },
+            RawTransactionType::Unknown(_) => {
+                unreachable!("impossible to reach this, rejected above")
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::transaction_reader::{RecordError, AMOUNT_OVER_MAX, ID_OUT_OF_RANGE, UNKNOWN_TYPE};
     use crate::Decimal;
     use crate::{
-        Transaction, TransactionMod, TransactionReader, TransactionRow, TransactionRow::*,
-        TransactionState::*,
+        Transaction, TransactionKind::*, TransactionMod, TransactionReader,
+        TransactionReaderBuilder, TransactionRow, TransactionRow::*, TransactionState::*,
+        TransactionTransfer, TransactionUnlock,
     };
     use std::str::FromStr;
 
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
     #[test]
     fn read_valid_rows() {
         let input_file = b"\
@@ -152,30 +1136,811 @@ dispute, 2, 2, 5
 dispute, 2, 2,
 chargeback, 2, 2,
 resolve, 2, 2,
+unlock, 2, 0, 1.0
+unlock, 2, 0,
+";
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 2, kind: Deposit, amount: dec("2.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 3, client: 1, kind: Deposit, amount: dec("2.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 4, client: 1, kind: Withdrawal, amount: dec("-1.5000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 5, client: 2, kind: Withdrawal, amount: dec("-3.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 84, client: 4, kind: Deposit, amount: dec("4.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 2, kind: Deposit, amount: dec("2.0001"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 2, kind: Deposit, amount: dec("2.0010"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 2, kind: Deposit, amount: dec("2.0010"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 2, kind: Deposit, amount: dec("2.0100"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 2, kind: Deposit, amount: dec("2.1000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 2, kind: Deposit, amount: dec("2.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            Mod(TransactionMod { tx: 2, client: Some(2), state: Disputed, amount: None , op_id: None }),
+            Mod(TransactionMod { tx: 2, client: Some(2), state: Chargeback, amount: None , op_id: None }),
+            Mod(TransactionMod { tx: 2, client: Some(2), state: Resolved, amount: None , op_id: None }),
+            Unlock(TransactionUnlock { tx: 0, client: 2 }),
+        ]);
+    }
+
+    #[test]
+    fn read_delimiter_and_trim() {
+        let input_file = "\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 0.5
+deposit, 2, 3, 2.0
+dispute, 1, 1,
+resolve, 1, 1,
+bad, 2, 4, 4.0
+";
+        let expected: Vec<TransactionRow> = TransactionReader::from_reader(input_file.as_bytes())
+            .valid_records()
+            .collect();
+        assert_eq!(expected.len(), 5);
+
+        let tsv = input_file.replace(", ", "\t").replace(',', "\t");
+        let mut rdr = TransactionReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_reader(tsv.as_bytes());
+        assert_eq!(rdr.valid_records().collect::<Vec<_>>(), expected);
+
+        // without trimming, " 1" isn't a valid client, and " amount" isn't the amount header
+        let mut rdr = TransactionReaderBuilder::new()
+            .trim(csv::Trim::None)
+            .from_reader(input_file.as_bytes());
+        assert_eq!(rdr.valid_records().count(), 0);
+        let untrimmed = input_file.replace(", ", ",");
+        let mut rdr = TransactionReaderBuilder::new()
+            .trim(csv::Trim::None)
+            .from_reader(untrimmed.as_bytes());
+        assert_eq!(rdr.valid_records().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn read_padded_amount() {
+        let input_file = b"\
+type,client,tx,amount
+deposit,1,1,\"  1.50 \"
+withdrawal,1,2,\"\t0.5\"
+deposit,1,3,\" \"
+";
+        let expected = vec![
+            New(Transaction {
+                tx: 1,
+                client: 1,
+                kind: Deposit,
+                amount: dec("1.5000"),
+                state: Resolved,
+                raw_amount: None,
+                held_amount: Decimal::ZERO,
+            }),
+            New(Transaction {
+                tx: 2,
+                client: 1,
+                kind: Withdrawal,
+                amount: dec("-0.5000"),
+                state: Resolved,
+                raw_amount: None,
+                held_amount: Decimal::ZERO,
+            }),
+        ];
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().collect::<Vec<_>>(), expected);
+        let mut rdr = TransactionReaderBuilder::new()
+            .trim(csv::Trim::None)
+            .from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn read_without_headers() {
+        let rows = "\
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 0.5
+deposit, 2, 3, 2.0
+dispute, 1, 1,
+chargeback, 1, 1,
+";
+        let expected: Vec<TransactionRow> = TransactionReader::from_reader(
+            format!("type, client, tx, amount\n{}", rows).as_bytes(),
+        )
+        .valid_records()
+        .collect();
+        assert_eq!(expected.len(), 5);
+
+        let mut rdr = TransactionReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(rows.as_bytes());
+        assert_eq!(rdr.valid_records().collect::<Vec<_>>(), expected);
+
+        // the first row isn't lost as a header with records_with_source either
+        let mut rdr = TransactionReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(rows.as_bytes());
+        let records: Vec<_> = rdr.records_with_source().collect();
+        assert_eq!(records[0].line, 1);
+        assert_eq!(
+            records
+                .into_iter()
+                .map(|r| r.row.unwrap())
+                .collect::<Vec<_>>(),
+            expected
+        );
+
+        // with headers, the first row is taken as the header, so the columns don't match
+        let mut rdr = TransactionReader::from_reader(rows.as_bytes());
+        assert_eq!(rdr.valid_records().count(), 0);
+    }
+
+    #[test]
+    fn read_minor_units() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 150
+withdrawal, 1, 2, 1
+deposit, 1, 3, 12345678
+# decimal points aren't allowed in minor units mode
+deposit, 1, 4, 1.50
+deposit, 1, 5, 0
+dispute, 1, 1,
+";
+        let mut rdr = TransactionReaderBuilder::new()
+            .amount_is_minor_units(true)
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("0.0150"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 1, kind: Withdrawal, amount: dec("-0.0001"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 3, client: 1, kind: Deposit, amount: dec("1234.5678"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            Mod(TransactionMod { tx: 1, client: Some(1), state: Disputed, amount: None , op_id: None }),
+        ]);
+    }
+
+    #[test]
+    fn read_direction_column() {
+        let input_file = b"\
+type, client, tx, amount, direction
+, 1, 1, 1.0, credit
+, 1, 2, 0.5, debit
+deposit, 1, 3, 2.0, credit
+# type and direction must agree
+withdrawal, 1, 4, 2.0, credit
+# missing or unknown directions are rejected
+, 1, 5, 1.0,
+deposit, 1, 6, 1.0,
+, 1, 7, 1.0, sideways
+dispute, 1, 1, ,
+";
+        let mut rdr = TransactionReaderBuilder::new()
+            .direction_column(true)
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 1, kind: Withdrawal, amount: dec("-0.5000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 3, client: 1, kind: Deposit, amount: dec("2.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            Mod(TransactionMod { tx: 1, client: Some(1), state: Disputed, amount: None , op_id: None }),
+        ]);
+
+        // the type column can be left out entirely
+        let input_file = b"\
+client, tx, amount, direction
+1, 1, 1.0, credit
+1, 2, 0.5, debit
+";
+        let mut rdr = TransactionReaderBuilder::new()
+            .direction_column(true)
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 1, kind: Withdrawal, amount: dec("-0.5000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+        ]);
+
+        // but without direction_column mode there's no type
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().count(), 0);
+    }
+
+    #[test]
+    fn read_from_path() {
+        let path = std::env::temp_dir().join("csv_transaction_engine_read_from_path.csv");
+        std::fs::write(&path, "type, client, tx, amount\ndeposit, 1, 1, 1.0\n").unwrap();
+        let mut rdr = TransactionReaderBuilder::new()
+            .buffer_capacity(4)
+            .from_path(&path)
+            .unwrap();
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        std::fs::remove_file(&path).unwrap();
+
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+        ]);
+
+        assert!(
+            TransactionReader::from_path(std::env::temp_dir().join("does/not/exist.csv")).is_err()
+        );
+        // nothing is read from stdin until records are
+        assert!(TransactionReader::from_path(std::ffi::OsStr::new("-")).is_ok());
+    }
+
+    #[test]
+    fn read_bom_crlf() {
+        let csv = include_str!("../test.csv");
+        let windows = format!("\u{feff}{}", csv.replace('\n', "\r\n"));
+        let expected: Vec<TransactionRow> = TransactionReader::from_reader(csv.as_bytes())
+            .valid_records()
+            .collect();
+        assert!(!expected.is_empty());
+        let mut rdr = TransactionReader::from_reader(windows.as_bytes());
+        assert_eq!(rdr.headers().unwrap()[0], "type");
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        assert_eq!(all_valid_records, expected);
+
+        // before a metadata line too
+        let input_file =
+            b"\xEF\xBB\xBF# version=2\r\ntype, client, tx, amount\r\ndeposit, 1, 1, 1.0\r\n";
+        let mut rdr = TransactionReaderBuilder::new()
+            .metadata_line(true)
+            .from_reader(&input_file[..]);
+        assert_eq!(rdr.metadata().unwrap().version(), Some(2));
+        assert_eq!(rdr.valid_records().count(), 1);
+    }
+
+    #[test]
+    fn read_metadata_line() {
+        let input_file = b"\
+# version=2 source=partner
+type, client, tx, amount
+deposit, 1, 1, 1.0
+";
+        let mut rdr = TransactionReaderBuilder::new()
+            .metadata_line(true)
+            .from_reader(&input_file[..]);
+        let metadata = rdr.metadata().unwrap();
+        assert_eq!(metadata.version(), Some(2));
+        assert_eq!(metadata.get("source"), Some("partner"));
+        assert_eq!(metadata.get("missing"), None);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+        ]);
+
+        // without the option, the metadata line is read as the header, so nothing is valid
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.metadata(), None);
+        assert_eq!(rdr.valid_records().count(), 0);
+
+        // with the option, but no metadata line, it behaves as usual
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+";
+        let mut rdr = TransactionReaderBuilder::new()
+            .metadata_line(true)
+            .from_reader(&input_file[..]);
+        assert_eq!(rdr.metadata(), None);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+        ]);
+    }
+
+    #[test]
+    fn read_records_with_source() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+# comment
+deposit, 2, 2, bla
+deposit, trash, 3, 1.0
+withdrawal, 2, 4,
+dispute, 2, 4,
+";
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let records: Vec<_> = rdr.records_with_source().collect();
+        assert_eq!(records.len(), 6);
+
+        assert_eq!(records[0].line, 2);
+        assert_eq!(records[0].fields, vec!["deposit", "1", "1", "1.0"]);
+        #[rustfmt::skip]
+        assert_eq!(records[0].row, Ok(New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO })));
+
+        // wrong number of fields
+        assert_eq!(records[1].line, 3);
+        assert!(records[1].fields.is_empty());
+        assert!(records[1].row.is_err());
+
+        // fields that don't parse
+        assert_eq!(records[2].line, 4);
+        assert_eq!(records[2].fields, vec!["deposit", "2", "2", "bla"]);
+        assert_eq!(records[2].client, None);
+        assert!(records[2].row.is_err());
+        assert_eq!(records[3].line, 5);
+        assert!(records[3].row.is_err());
+
+        // parses, but isn't valid
+        assert_eq!(records[4].line, 6);
+        assert_eq!((records[4].client, records[4].tx), (Some(2), Some(4)));
+        assert_eq!(records[4].row, Err("missing or invalid amount".to_string()));
+
+        assert_eq!(records[5].line, 7);
+        #[rustfmt::skip]
+        assert_eq!(records[5].row, Ok(Mod(TransactionMod { tx: 4, client: Some(2), state: Disputed, amount: None , op_id: None })));
+    }
+
+    #[test]
+    fn read_id_out_of_range() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 007, 0004294967295, 1.0
+deposit, 1, 4294967296, 1.0
+deposit, 65536, 2, 1.0
+deposit, 1, 99999999999999999999999, 1.0
+deposit, -1, 3, 1.0
+deposit, 1, 3x, 1.0
+";
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let records: Vec<_> = rdr.records_with_source().collect();
+        assert_eq!(records.len(), 6);
+        #[rustfmt::skip]
+        assert_eq!(records[0].row, Ok(New(Transaction { tx: 4294967295, client: 7, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO })));
+        for record in &records[1..4] {
+            assert_eq!(record.row, Err(ID_OUT_OF_RANGE.to_string()));
+        }
+        // not numbers at all, so not out of range
+        for record in &records[4..] {
+            assert!(record.row.as_ref().unwrap_err().contains("invalid digit"));
+        }
+
+        // the lenient iterator still just drops them
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().count(), 1);
+    }
+
+    #[test]
+    fn read_records_with_errors() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+# comment
+deposit, 2, 2, bla
+withdrawal, 2, 4,
+dispute, 2, 4,
+";
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let records: Vec<_> = rdr.records_with_errors().collect();
+        assert_eq!(records.len(), 5);
+        #[rustfmt::skip]
+        assert_eq!(records[0].as_ref().unwrap(), &New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }));
+        match &records[1] {
+            Err(e @ RecordError::Csv { .. }) => assert_eq!(e.line(), 3),
+            r => panic!("unexpected {:?}", r),
+        }
+        match &records[2] {
+            Err(e @ RecordError::Csv { .. }) => assert_eq!(e.line(), 4),
+            r => panic!("unexpected {:?}", r),
+        }
+        match &records[3] {
+            Err(RecordError::Invalid { line, reason }) => {
+                assert_eq!((*line, *reason), (5, "missing or invalid amount"))
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        #[rustfmt::skip]
+        assert_eq!(records[4].as_ref().unwrap(), &Mod(TransactionMod { tx: 4, client: Some(2), state: Disputed, amount: None , op_id: None }));
+
+        // the valid ones are exactly what valid_records yields
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let valid: Vec<_> = rdr.valid_records().collect();
+        let ok: Vec<_> = records.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(valid, ok);
+    }
+
+    #[test]
+    fn read_amount_exactly() {
+        // through f64 these would come out as 7922816251426434000000000 and 792281625142643400000
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 7922816251426433759354395.0
+deposit, 1, 2, 792281625142643375172
+deposit, 1, 3, 0.1
+";
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let amounts: Vec<String> = rdr
+            .valid_records()
+            .map(|row| match row {
+                New(tx) => tx.amount.to_string(),
+                row => panic!("unexpected {:?}", row),
+            })
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                "7922816251426433759354395.0000",
+                "792281625142643375172.0000",
+                "0.1000"
+            ]
+        );
+    }
+
+    #[test]
+    fn read_amount_out_of_range() {
+        // the largest whole number that still fits at 4 places, then slightly bigger ones
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 7922816251426433759354395
+deposit, 1, 2, 7922816251426433759354396
+withdrawal, 1, 3, 7922816251426433759354395.5
+";
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let records: Vec<_> = rdr.records_with_errors().collect();
+        #[rustfmt::skip]
+        assert_eq!(records[0].as_ref().unwrap(), &New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("7922816251426433759354395.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }));
+        for (record, line) in records[1..].iter().zip([3, 4].iter().copied()) {
+            match record {
+                Err(RecordError::Invalid { line: l, reason }) => {
+                    assert_eq!((*l, *reason), (line, "amount out of representable range"))
+                }
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn read_allow_zero_amount() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 0
+withdrawal, 1, 2, 0.0000
+";
+        // rejected by default
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().count(), 0);
+
+        let mut rdr = TransactionReaderBuilder::new()
+            .allow_zero_amount(true)
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("0.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 1, kind: Withdrawal, amount: dec("0.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+        ]);
+        if let New(tx) = &all_valid_records[1] {
+            assert!(!tx.amount.is_sign_negative());
+        }
+    }
+
+    #[test]
+    fn read_lenient_amount() {
+        // no space before the quotes, or csv doesn't treat the field as quoted
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1,\"$1,234.5678\"
+withdrawal, 1, 2, $0.5
+deposit, 1, 3, $abc
+deposit, 1, 4,\"1,000.00001\"
+deposit, 1, 5, 1.5
+";
+        // strict by default, only the plain amount parses
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().count(), 1);
+
+        let mut rdr = TransactionReaderBuilder::new()
+            .lenient_amount("$,")
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1234.5678"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 1, kind: Withdrawal, amount: dec("-0.5000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 5, client: 1, kind: Deposit, amount: dec("1.5000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+        ]);
+
+        // the original text is still what's reported
+        let mut rdr = TransactionReaderBuilder::new()
+            .lenient_amount("$,")
+            .from_reader(&input_file[..]);
+        let sources: Vec<_> = rdr.records_with_source().collect();
+        assert_eq!(sources[0].fields[3], "$1,234.5678");
+        assert!(sources[0].row.is_ok());
+        assert!(sources[2].row.is_err());
+        assert_eq!(sources[3].row, Err("missing or invalid amount".to_string()));
+    }
+
+    #[test]
+    fn read_max_amount() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 100
+deposit, 1, 2, 100.0001
+withdrawal, 1, 3, 100.0000
+withdrawal, 1, 4, 1000
+";
+        // no limit by default
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().count(), 4);
+
+        let mut rdr = TransactionReaderBuilder::new()
+            .max_amount(dec("100"))
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("100.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 3, client: 1, kind: Withdrawal, amount: dec("-100.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+        ]);
+
+        let mut rdr = TransactionReaderBuilder::new()
+            .max_amount(dec("100"))
+            .from_reader(&input_file[..]);
+        let sources: Vec<_> = rdr.records_with_source().collect();
+        assert_eq!(sources[1].row, Err(AMOUNT_OVER_MAX.to_string()));
+        assert_eq!(sources[3].row, Err(AMOUNT_OVER_MAX.to_string()));
+    }
+
+    #[test]
+    fn read_partial_disputes() {
+        let input_file = b"\
+type, client, tx, amount
+dispute, 1, 1, 2.0
+dispute, 1, 2,
+resolve, 1, 1, 2.0
+chargeback, 1, 1, 2.0
+dispute, 1, 3, -2.0
+dispute, 1, 4, 2.00001
+";
+        // an amount isn't allowed by default
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().count(), 1);
+
+        let mut rdr = TransactionReaderBuilder::new()
+            .allow_partial_disputes(true)
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            Mod(TransactionMod { tx: 1, client: Some(1), state: Disputed, amount: Some(dec("2.0000")) , op_id: None }),
+            Mod(TransactionMod { tx: 2, client: Some(1), state: Disputed, amount: None , op_id: None }),
+        ]);
+    }
+
+    #[test]
+    fn read_transfers() {
+        let input_file = b"\
+type, client, tx, amount, to_client
+transfer, 1, 1, 2.0, 2
+transfer, 1, 2, 2.0,
+transfer, 1, 3, 2.0, 1
+transfer, 1, 4, , 2
+deposit, 1, 5, 1.0, 2
+";
+        // transfer is an unknown type by default
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        assert_eq!(all_valid_records.len(), 1);
+
+        let mut rdr = TransactionReaderBuilder::new()
+            .transfers(true)
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            Transfer(TransactionTransfer { tx: 1, client: 1, to_client: 2, amount: dec("2.0000") }),
+            New(Transaction { tx: 5, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+        ]);
+    }
+
+    #[test]
+    fn read_op_ids() {
+        let input_file = b"\
+type, client, tx, amount, op_id
+deposit, 1, 1, 1.0, 7
+dispute, 1, 1, , 8
+resolve, 1, 1, ,
 ";
         let mut rdr = TransactionReader::from_reader(&input_file[..]);
         let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
-        fn dec(s: &str) -> Decimal {
-            Decimal::from_str(s).unwrap()
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO }),
+            Mod(TransactionMod { tx: 1, client: Some(1), state: Disputed, amount: None, op_id: Some(8) }),
+            Mod(TransactionMod { tx: 1, client: Some(1), state: Resolved, amount: None, op_id: None }),
+        ]);
+    }
+
+    #[test]
+    fn read_raw_amounts() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.23456
+withdrawal, 1, 2, 0.50
+deposit, 1, 3, 0.00004
+";
+        // more than 4 places is rejected by default
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().count(), 1);
+
+        let mut rdr = TransactionReaderBuilder::new()
+            .raw_amounts(true)
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        #[rustfmt::skip]
+        assert_eq!(all_valid_records, vec![
+            New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.2346"), state: Resolved, raw_amount: Some(dec("1.23456")), held_amount: Decimal::ZERO }),
+            New(Transaction { tx: 2, client: 1, kind: Withdrawal, amount: dec("-0.5000"), state: Resolved, raw_amount: Some(dec("-0.50")), held_amount: Decimal::ZERO }),
+        ]);
+        let mut engine = crate::TransactionEngine::default();
+        engine.apply_all(all_valid_records);
+        assert_eq!(engine.client(1).unwrap().total(), dec("0.7346"));
+        let raw: Vec<_> = engine.transactions().map(|tx| tx.raw_amount()).collect();
+        assert!(raw.contains(&Some(dec("1.23456"))));
+    }
+
+    #[test]
+    fn read_strict_records() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, -1.0
+deposit, 1, 3, 1.0
+";
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        match rdr.strict_records().collect::<Result<Vec<_>, _>>() {
+            Err(RecordError::Invalid { line, .. }) => assert_eq!(line, 3),
+            other => panic!("expected an invalid record, got {:?}", other),
         }
+        // nothing after the first error
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let records: Vec<_> = rdr.strict_records().collect();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_ok());
+
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().count(), 2);
+
+        let mut rdr =
+            TransactionReader::from_reader(&b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n"[..]);
+        assert_eq!(
+            rdr.strict_records()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
 
+    #[test]
+    fn read_unknown_type() {
+        let input_file = b"\
+type, client, tx, amount
+transfer, 1, 1, 5
+deposit, 1, 2, 5
+deposit, 1, x, 5
+";
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let records: Vec<_> = rdr.records_with_errors().collect();
+        assert_eq!(records.len(), 3);
+        // an unknown type is told apart from a malformed row
+        match &records[0] {
+            Err(RecordError::Invalid { line, reason }) => {
+                assert_eq!(*line, 2);
+                assert_eq!(*reason, UNKNOWN_TYPE);
+            }
+            other => panic!("expected an unknown type, got {:?}", other),
+        }
+        assert!(records[1].is_ok());
+        assert!(matches!(records[2], Err(RecordError::Csv { line: 4, .. })));
+
+        // and written back as it was
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        let (raw, reason) = rdr.records_raw_with_errors().next().unwrap();
+        assert_eq!(raw.fields()[0], "transfer");
+        assert_eq!(reason, Some(UNKNOWN_TYPE));
+    }
+
+    #[test]
+    fn read_raw_with_errors() {
+        let input_file = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.50
+withdrawal, 1, 2,
+deposit, 1, 3, bla
+dispute, 1, 1, 5
+";
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(
+            rdr.headers().unwrap(),
+            vec!["type", "client", "tx", "amount"]
+        );
+        let records: Vec<_> = rdr
+            .records_raw_with_errors()
+            .map(|(raw, reason)| (raw.fields(), reason))
+            .collect();
+        let fields = |f: [&str; 4]| f.map(String::from);
+        // bla doesn't deserialize, so it's skipped
+        assert_eq!(
+            records,
+            vec![
+                (fields(["deposit", "1", "1", "1.50"]), None),
+                (
+                    fields(["withdrawal", "1", "2", ""]),
+                    Some("missing or invalid amount")
+                ),
+                (
+                    fields(["dispute", "1", "1", "5"]),
+                    Some("amount provided for Dispute/Resolve/Chargeback and not allowed")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_asset_column() {
+        let input_file = b"\
+type, client, tx, amount, asset
+deposit, 1, 1, 1.0, USD
+deposit, 1, 2, 0.5, BTC
+deposit, 1, 3, 2.0,
+dispute, 1, 2,,
+";
+        let mut rdr = TransactionReaderBuilder::new()
+            .asset_column(true)
+            .from_reader(&input_file[..]);
+        let all_valid_records: Vec<(String, TransactionRow)> =
+            rdr.valid_records_with_asset().collect();
         #[rustfmt::skip]
         assert_eq!(all_valid_records, vec![
-            New(Transaction { tx: 1, client: 1, amount: dec("1.0000"), state: Resolved }),
-            New(Transaction { tx: 2, client: 2, amount: dec("2.0000"), state: Resolved }),
-            New(Transaction { tx: 3, client: 1, amount: dec("2.0000"), state: Resolved }),
-            New(Transaction { tx: 4, client: 1, amount: dec("-1.5000"), state: Resolved }),
-            New(Transaction { tx: 5, client: 2, amount: dec("-3.0000"), state: Resolved }),
-            New(Transaction { tx: 84, client: 4, amount: dec("4.0000"), state: Resolved }),
-            New(Transaction { tx: 2, client: 2, amount: dec("2.0001"), state: Resolved }),
-            New(Transaction { tx: 2, client: 2, amount: dec("2.0010"), state: Resolved }),
-            New(Transaction { tx: 2, client: 2, amount: dec("2.0010"), state: Resolved }),
-            New(Transaction { tx: 2, client: 2, amount: dec("2.0100"), state: Resolved }),
-            New(Transaction { tx: 2, client: 2, amount: dec("2.1000"), state: Resolved }),
-            New(Transaction { tx: 2, client: 2, amount: dec("2.0000"), state: Resolved }),
-            Mod(TransactionMod { tx: 2, client: 2, state: Disputed }),
-            Mod(TransactionMod { tx: 2, client: 2, state: Chargeback }),
-            Mod(TransactionMod { tx: 2, client: 2, state: Resolved }),
+            ("USD".to_string(), New(Transaction { tx: 1, client: 1, kind: Deposit, amount: dec("1.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO })),
+            ("BTC".to_string(), New(Transaction { tx: 2, client: 1, kind: Deposit, amount: dec("0.5000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO })),
+            ("".to_string(), New(Transaction { tx: 3, client: 1, kind: Deposit, amount: dec("2.0000"), state: Resolved, raw_amount: None, held_amount: Decimal::ZERO })),
+            ("".to_string(), Mod(TransactionMod { tx: 2, client: Some(1), state: Disputed, amount: None , op_id: None })),
         ]);
+
+        // ignored by default
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert!(rdr
+            .valid_records_with_asset()
+            .all(|(asset, _)| asset.is_empty()));
+        let mut rdr = TransactionReader::from_reader(&input_file[..]);
+        assert_eq!(rdr.valid_records().count(), 4);
+    }
+
+    #[test]
+    fn read_chain() {
+        let day1 = b"\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+";
+        let day2 = b"\
+type, client, tx, amount
+withdrawal, 1, 3, 0.5
+dispute, 2, 2,
+";
+        let mut readers = vec![
+            TransactionReader::from_reader(&day1[..]),
+            TransactionReader::from_reader(&day2[..]),
+        ];
+        let chained: Vec<TransactionRow> = TransactionReader::chain(&mut readers).collect();
+
+        let both = [&day1[..], &day2[25..]].concat();
+        let mut rdr = TransactionReader::from_reader(&both[..]);
+        let all_valid_records: Vec<TransactionRow> = rdr.valid_records().collect();
+        assert_eq!(chained.len(), 4);
+        assert_eq!(chained, all_valid_records);
     }
 }